@@ -1,4 +1,5 @@
 use std::env;
+use std::ffi::OsStr;
 use std::fs;
 use std::io;
 use std::io::BufWriter;
@@ -29,7 +30,127 @@ fn parse(string: &str) -> Option<Vec<(&str, Rect)>> {
     Some(rects)
 }
 
+struct Glyph {
+    code: u32,
+    rect: Rect,
+    offset: (i32, i32),
+    advance: i32,
+}
+
+/// The bitmap font, described by `res/font/font.txt`.
+struct Font {
+    line: i32,
+    glyphs: Vec<Glyph>,
+    kerning: Vec<(u32, u32, i32)>,
+}
+
+fn parse_font(string: &str) -> Option<Font> {
+    let mut font = Font {
+        line: 0,
+        glyphs: Vec::new(),
+        kerning: Vec::new(),
+    };
+    for line in string.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        let tokens = line.split_ascii_whitespace().collect::<Vec<&str>>();
+        let numbers = tokens
+            .iter()
+            .skip(1)
+            .map(|x| x.parse::<i32>().ok())
+            .collect::<Option<Vec<i32>>>()?;
+        match (tokens.first(), &numbers[..]) {
+            (None, _) => {}
+            (Some(&"line"), &[line, _base]) => font.line = line,
+            (Some(&"glyph"), &[code, x, y, width, height, offset_x, offset_y, advance]) => {
+                char::from_u32(code as u32)?;
+                font.glyphs.push(Glyph {
+                    code: code as u32,
+                    rect: Rect::new(x, y, width, height),
+                    offset: (offset_x, offset_y),
+                    advance,
+                });
+            }
+            (Some(&"kerning"), &[a, b, amount]) => font.kerning.push((a as u32, b as u32, amount)),
+            _ => return None,
+        }
+    }
+    if font.line <= 0 {
+        return None;
+    }
+    font.glyphs.sort_by_key(|x| x.code);
+    font.kerning.sort_unstable();
+    Some(font)
+}
+
+/// Write the metrics of the font, and the place of its glyphs in the atlas, given by 'uv_rect'.
+/// All the metrics are in lines, so the text can be laid out in any size.
+fn write_font(
+    out_dir: &OsStr,
+    font: &Font,
+    uv_rect: impl Fn(u32) -> [f32; 4],
+) -> Result<(), io::Error> {
+    let mut font_rs = BufWriter::new(fs::File::create(PathBuf::from(out_dir).join("font.rs"))?);
+    let line = font.line as f32;
+    writeln!(
+        font_rs,
+        "pub static GLYPHS: &[(char, [f32; 4], [f32; 4], f32)] = &["
+    )?;
+    for glyph in font.glyphs.iter() {
+        let metrics = [
+            glyph.offset.0 as f32 / line,
+            glyph.offset.1 as f32 / line,
+            glyph.rect.width as f32 / line,
+            glyph.rect.height as f32 / line,
+        ];
+        writeln!(
+            font_rs,
+            "    ({:?}, {:?}, {:?}, {:?}),",
+            char::from_u32(glyph.code).unwrap(),
+            uv_rect(glyph.code),
+            metrics,
+            glyph.advance as f32 / line
+        )?;
+    }
+    writeln!(font_rs, "];")?;
+    writeln!(font_rs, "pub static KERNING: &[((char, char), f32)] = &[")?;
+    for &(a, b, amount) in font.kerning.iter() {
+        if let (Some(a), Some(b)) = (char::from_u32(a), char::from_u32(b)) {
+            writeln!(
+                font_rs,
+                "    (({:?}, {:?}), {:?}),",
+                a,
+                b,
+                amount as f32 / line
+            )?;
+        }
+    }
+    writeln!(font_rs, "];")?;
+    Ok(())
+}
+
+/// Write a table with the contents of every file in `res/music`, so the music can be chosen by
+/// name from `res/music.cfg`.
+fn write_music_table(out_dir: &OsStr) -> Result<(), io::Error> {
+    println!("cargo:rerun-if-changed=res/music");
+    let mut entries = fs::read_dir("res/music")?
+        .map(|res| res.map(|e| e.path()))
+        .collect::<Result<Vec<_>, io::Error>>()?;
+    entries.sort();
+    let mut music_rs = BufWriter::new(fs::File::create(PathBuf::from(out_dir).join("music.rs"))?);
+    writeln!(music_rs, "pub static FILES: &[(&str, &[u8])] = &[")?;
+    for entry in entries {
+        println!("cargo:rerun-if-changed={}", entry.to_str().unwrap());
+        let name = entry.file_name().unwrap().to_str().unwrap();
+        let path = fs::canonicalize(&entry)?;
+        writeln!(music_rs, "    ({:?}, include_bytes!({:?})),", name, path)?;
+    }
+    writeln!(music_rs, "];")?;
+    Ok(())
+}
+
 fn main() -> Result<(), io::Error> {
+    write_music_table(&env::var_os("OUT_DIR").unwrap())?;
+
     let entries = fs::read_dir("res\\textures")?
         .map(|res| res.map(|e| e.path()))
         .collect::<Result<Vec<_>, io::Error>>()?;
@@ -37,9 +158,24 @@ fn main() -> Result<(), io::Error> {
 
     let mut sprites = Vec::new();
 
+    // the glyphs of the font are packed in the atlas, but go to their own table
+    println!("cargo:rerun-if-changed=res/font");
+    let font_png = PathBuf::from("res/font/font.png");
+    let font = parse_font(&fs::read_to_string("res/font/font.txt")?)
+        .expect("res/font/font.txt is malformed");
+    let glyph_name = |code: u32| format!("glyph {}", code);
+    sprites.push((
+        font_png.clone(),
+        font.glyphs
+            .iter()
+            .filter(|x| x.rect.width > 0 && x.rect.height > 0)
+            .map(|x| (glyph_name(x.code), x.rect, Rect::new(0, 0, 0, 0)))
+            .collect::<Vec<_>>(),
+    ));
+    let mut glyph_uvs = Vec::new();
+
     entries.into_iter().for_each(|mut entry| {
         println!("cargo:rerun-if-changed={}", entry.to_str().unwrap());
-        use std::ffi::OsStr;
         match entry.extension().and_then(OsStr::to_str) {
             Some("txt") => {
                 let text = fs::read_to_string(&entry).unwrap();
@@ -103,8 +239,7 @@ fn main() -> Result<(), io::Error> {
 
     let mut rects = sprites
         .iter_mut()
-        .map(|(_, x)| x.iter_mut().map(|(_, r, x)| (r.width, r.height, x)))
-        .flatten()
+        .flat_map(|(_, x)| x.iter_mut().map(|(_, r, x)| (r.width, r.height, x)))
         .collect::<Vec<(i32, i32, &mut Rect)>>();
 
     let mut i = std::time::SystemTime::now()
@@ -129,6 +264,10 @@ fn main() -> Result<(), io::Error> {
         }
     }
 
+    // the place of every sprite, so development builds can copy the edited art over the same
+    // places, without moving the constants
+    let mut places = Vec::new();
+
     for (path, rects) in sprites.iter() {
         let image = image::open(path).unwrap().to_rgba8();
 
@@ -149,22 +288,30 @@ fn main() -> Result<(), io::Error> {
             let left = packed.left() as u32;
             let right = packed.right() as u32;
 
-            atlas_rs.write_all(b"pub const ").unwrap();
-            atlas_rs
-                .write_all(name.replace(' ', "_").to_uppercase().as_bytes())
-                .unwrap();
-            atlas_rs
-                .write_all(
-                    format!(
-                        ":[f32;4]=[{},{},{},{}];\n",
-                        packed.x as f32 / width as f32,
-                        packed.y as f32 / height as f32,
-                        packed.width as f32 / width as f32,
-                        packed.height as f32 / height as f32
+            let uv_rect = [
+                packed.x as f32 / width as f32,
+                packed.y as f32 / height as f32,
+                packed.width as f32 / width as f32,
+                packed.height as f32 / height as f32,
+            ];
+            places.push((name.clone(), *packed));
+            if *path == font_png {
+                glyph_uvs.push((name.clone(), uv_rect));
+            } else {
+                atlas_rs.write_all(b"pub const ").unwrap();
+                atlas_rs
+                    .write_all(name.replace(' ', "_").to_uppercase().as_bytes())
+                    .unwrap();
+                atlas_rs
+                    .write_all(
+                        format!(
+                            ":[f32;4]=[{},{},{},{}];\n",
+                            uv_rect[0], uv_rect[1], uv_rect[2], uv_rect[3]
+                        )
+                        .as_bytes(),
                     )
-                    .as_bytes(),
-                )
-                .unwrap();
+                    .unwrap();
+            }
 
             for x in left - 1..right + 1 {
                 unsafe {
@@ -186,6 +333,27 @@ fn main() -> Result<(), io::Error> {
         .save(PathBuf::from(&out_dir).join("atlas.png"))
         .unwrap();
 
+    places.sort_by(|a, b| a.0.cmp(&b.0));
+    writeln!(atlas_rs, "#[allow(dead_code)]")?;
+    writeln!(atlas_rs, "pub static PLACES: &[(&str, [u32; 4])] = &[")?;
+    for (name, rect) in places.iter() {
+        writeln!(
+            atlas_rs,
+            "    ({:?}, [{}, {}, {}, {}]),",
+            name, rect.x, rect.y, rect.width, rect.height
+        )?;
+    }
+    writeln!(atlas_rs, "];")?;
+    atlas_rs.flush()?;
+
+    write_font(&out_dir, &font, |code| {
+        let name = glyph_name(code);
+        glyph_uvs
+            .iter()
+            .find(|x| x.0 == name)
+            .map_or([0.0; 4], |x| x.1)
+    })?;
+
     // unimplemented!();
 
     Ok(())
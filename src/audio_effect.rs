@@ -1,74 +1,245 @@
 use audio_engine::SoundSource;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 
-// Play the intro once,
-// and then keep the music in loop
-pub struct WithIntro<T: SoundSource, S: SoundSource> {
-    in_intro: bool,
-    intro: T,
-    music: S,
+/// The loop points of a music, read from a sidecar file, in frames from the start of the file.
+/// Each line is in the form `<name> <frame>`, and '#' starts a comment.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LoopRegion {
+    pub start: u64,
+    /// The end of the loop, or the end of the file if none.
+    pub end: Option<u64>,
 }
-impl<T: SoundSource, S: SoundSource> WithIntro<T, S> {
-    pub fn new(intro: T, music: S) -> Self {
+impl LoopRegion {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut start = None;
+        let mut end = None;
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut tokens = line.split_ascii_whitespace();
+            let (key, value) = match (tokens.next(), tokens.next(), tokens.next()) {
+                (None, _, _) => continue,
+                (Some(key), Some(value), None) => (key, value),
+                _ => return Err(format!("line {}: expected '<name> <frame>'", n + 1)),
+            };
+            let value = value
+                .parse::<u64>()
+                .map_err(|_| format!("line {}: invalid frame '{}'", n + 1, value))?;
+            match key {
+                "loop_start" => start = Some(value),
+                "loop_end" => end = Some(value),
+                _ => return Err(format!("line {}: unknown name '{}'", n + 1, key)),
+            }
+        }
+        let start = start.ok_or("'loop_start' is missing")?;
+        if matches!(end, Some(end) if end <= start) {
+            return Err("'loop_end' must be after 'loop_start'".to_string());
+        }
+        Ok(Self { start, end })
+    }
+}
+
+/// Play a sound from its start, and then keep repeating the region between its loop points.
+/// A second copy of the source is moved to the loop start a little at each write, so the jump
+/// back is sample accurate and does not stall the audio thread.
+pub struct LoopPoints<T: SoundSource> {
+    current: T,
+    /// The copy that will play the next repetition of the loop.
+    spare: T,
+    region: LoopRegion,
+    /// The frame of 'current', and the frame 'spare' is at.
+    frame: u64,
+    spare_frame: u64,
+    skip_buffer: Box<[i16]>,
+}
+impl<T: SoundSource> LoopPoints<T> {
+    /// 'a' and 'b' must be two copies of the same source.
+    pub fn new(a: T, b: T, region: LoopRegion) -> Self {
         Self {
-            in_intro: true,
-            intro,
-            music,
+            skip_buffer: vec![0; 1024 * a.channels() as usize].into_boxed_slice(),
+            current: a,
+            spare: b,
+            region,
+            frame: 0,
+            spare_frame: 0,
+        }
+    }
+
+    /// Move the spare copy up to 'max_frames' closer to the loop start.
+    fn prepare_spare(&mut self, mut max_frames: u64) {
+        let channels = self.spare.channels() as u64;
+        while self.spare_frame < self.region.start && max_frames > 0 {
+            let frames = (self.region.start - self.spare_frame)
+                .min(max_frames)
+                .min(self.skip_buffer.len() as u64 / channels);
+            let written = self
+                .spare
+                .write_samples(&mut self.skip_buffer[..(frames * channels) as usize])
+                as u64
+                / channels;
+            if written == 0 {
+                // the loop starts after the end of the source
+                return;
+            }
+            self.spare_frame += written;
+            max_frames -= written;
         }
     }
 }
-impl<T: SoundSource, S: SoundSource> SoundSource for WithIntro<T, S> {
+impl<T: SoundSource> SoundSource for LoopPoints<T> {
     fn channels(&self) -> u16 {
-        self.music.channels()
+        self.current.channels()
     }
     fn sample_rate(&self) -> u32 {
-        self.music.sample_rate()
+        self.current.sample_rate()
     }
     fn reset(&mut self) {
-        self.music.reset();
+        self.current.reset();
+        self.spare.reset();
+        self.frame = 0;
+        self.spare_frame = 0;
     }
     fn write_samples(&mut self, buffer: &mut [i16]) -> usize {
+        let channels = self.channels() as usize;
         let mut len = 0;
-        loop {
-            break if self.in_intro {
-                len += self.intro.write_samples(&mut buffer[len..]);
-                if len < buffer.len() {
-                    self.intro.reset();
-                    self.in_intro = false;
-                    continue;
-                }
-                len
-            } else {
-                len += self.music.write_samples(&mut buffer[len..]);
-                len
-            };
+        while len < buffer.len() {
+            let mut want = buffer.len() - len;
+            if let Some(end) = self.region.end {
+                want = want.min((end - self.frame) as usize * channels);
+            }
+            let written = self.current.write_samples(&mut buffer[len..len + want]);
+            if written == 0 && self.frame == self.region.start {
+                // the loop is empty
+                break;
+            }
+            len += written;
+            self.frame += (written / channels) as u64;
+            if written < want || Some(self.frame) == self.region.end {
+                self.prepare_spare(u64::MAX);
+                std::mem::swap(&mut self.current, &mut self.spare);
+                self.frame = self.region.start;
+                self.spare.reset();
+                self.spare_frame = 0;
+            }
         }
+        // skip faster than the music plays, so the spare is ready before the end of the loop
+        self.prepare_spare(2 * (len / channels) as u64);
+        len
     }
 }
 
-/// When slow_down is true, the sound will slow down, and stop.
+/// The time the speed of `Speed` takes to change by 1, in seconds.
+const SPEED_RAMP: f32 = 0.75;
+
+/// Play the sound at the speed of a control, changing its pitch, like a tape. The speed ramps to
+/// the one of the control, and at 0 the sound stops, but can start again.
 /// This always keep the inner sound source in loop.
-pub struct SlowDown<T: SoundSource> {
-    inner: T,
-    pub slow_down: Arc<AtomicBool>,
-    in_buffer: Box<[i16]>,
-    iter: f32,
+pub struct Speed<T: SoundSource> {
+    reader: FrameReader<T>,
+    control: Control,
+    /// The position between the previous and the next frame.
+    t: f32,
     speed: f32,
 }
-impl<T: SoundSource> SlowDown<T> {
-    pub fn new(inner: T) -> Self {
-        let len = 100 * inner.channels() as usize;
+impl<T: SoundSource> Speed<T> {
+    pub fn new(inner: T, control: Control) -> Self {
+        Self {
+            reader: FrameReader::new(inner),
+            speed: control.get(),
+            control,
+            t: 2.0,
+        }
+    }
+}
+impl<T: SoundSource> SoundSource for Speed<T> {
+    fn channels(&self) -> u16 {
+        self.reader.inner.channels()
+    }
+    fn sample_rate(&self) -> u32 {
+        self.reader.inner.sample_rate()
+    }
+    fn reset(&mut self) {
+        self.reader.reset();
+        self.t = 2.0;
+    }
+    fn write_samples(&mut self, buffer: &mut [i16]) -> usize {
+        let target = self.control.get().max(0.0);
+        let step = 1.0 / (SPEED_RAMP * self.sample_rate() as f32);
+        let channels = self.reader.next.len();
+        for frame in buffer.chunks_mut(channels) {
+            if self.speed < target {
+                self.speed = (self.speed + step).min(target);
+            } else if self.speed > target {
+                self.speed = (self.speed - step).max(target);
+            }
+            if self.speed == 0.0 {
+                frame.iter_mut().for_each(|x| *x = 0);
+                continue;
+            }
+            while self.t >= 1.0 {
+                if !self.reader.advance() {
+                    self.reader.reset();
+                    if !self.reader.advance() {
+                        // the inner source is empty
+                        frame.iter_mut().for_each(|x| *x = 0);
+                        break;
+                    }
+                }
+                self.t -= 1.0;
+            }
+            self.reader.lerp(self.t, frame);
+            self.t += self.speed;
+        }
+        buffer.len()
+    }
+}
+
+/// A value that the game can change while a source reads it in the audio thread.
+#[derive(Clone)]
+pub struct Control(Arc<AtomicU32>);
+impl Control {
+    pub fn new(value: f32) -> Self {
+        Self(Arc::new(AtomicU32::new(value.to_bits())))
+    }
+
+    pub fn set(&self, value: f32) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// Multiply each frame of 'buffer' by the next value of 'gain'.
+fn scale_frames(buffer: &mut [i16], channels: u16, mut gain: impl FnMut() -> f32) {
+    for frame in buffer.chunks_mut(channels as usize) {
+        let gain = gain();
+        for sample in frame.iter_mut() {
+            *sample = (*sample as f32 * gain) as i16;
+        }
+    }
+}
+
+/// Start the sound in silence, and raise it to full volume in 'duration' seconds.
+#[allow(dead_code)]
+pub struct FadeIn<T: SoundSource> {
+    inner: T,
+    duration: f32,
+    frame: usize,
+}
+#[allow(dead_code)]
+impl<T: SoundSource> FadeIn<T> {
+    pub fn new(inner: T, duration: f32) -> Self {
         Self {
             inner,
-            slow_down: Arc::new(false.into()),
-            in_buffer: vec![0; len].into_boxed_slice(),
-            iter: 100.0,
-            speed: 1.0,
+            duration,
+            frame: 0,
         }
     }
 }
-impl<T: SoundSource> SoundSource for SlowDown<T> {
+impl<T: SoundSource> SoundSource for FadeIn<T> {
     fn channels(&self) -> u16 {
         self.inner.channels()
     }
@@ -77,47 +248,891 @@ impl<T: SoundSource> SoundSource for SlowDown<T> {
     }
     fn reset(&mut self) {
         self.inner.reset();
-        self.slow_down.store(false, Ordering::Relaxed);
-        self.iter = 100.0;
-        self.speed = 1.0;
+        self.frame = 0;
     }
     fn write_samples(&mut self, buffer: &mut [i16]) -> usize {
-        if !self.slow_down.load(Ordering::Relaxed) {
-            let mut len = self.inner.write_samples(buffer);
-            while len < buffer.len() {
-                self.inner.reset();
-                len += self.inner.write_samples(&mut buffer[len..]);
+        let len = self.inner.write_samples(buffer);
+        let frames = self.duration * self.sample_rate() as f32;
+        let frame = &mut self.frame;
+        scale_frames(&mut buffer[..len], self.inner.channels(), || {
+            *frame += 1;
+            ((*frame - 1) as f32 / frames).min(1.0)
+        });
+        len
+    }
+}
+
+/// Play the sound for 'start' seconds, then lower it to silence in 'duration' seconds, and end.
+#[allow(dead_code)]
+pub struct FadeOut<T: SoundSource> {
+    inner: T,
+    start: f32,
+    duration: f32,
+    frame: usize,
+}
+#[allow(dead_code)]
+impl<T: SoundSource> FadeOut<T> {
+    pub fn new(inner: T, start: f32, duration: f32) -> Self {
+        Self {
+            inner,
+            start,
+            duration,
+            frame: 0,
+        }
+    }
+}
+impl<T: SoundSource> SoundSource for FadeOut<T> {
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+    fn reset(&mut self) {
+        self.inner.reset();
+        self.frame = 0;
+    }
+    fn write_samples(&mut self, buffer: &mut [i16]) -> usize {
+        let channels = self.inner.channels() as usize;
+        let rate = self.sample_rate() as f32;
+        let start = (self.start * rate) as usize;
+        let frames = (self.duration * rate) as usize;
+        let left = (start + frames).saturating_sub(self.frame);
+        let end = (left * channels).min(buffer.len());
+        let len = self.inner.write_samples(&mut buffer[..end]);
+        let frame = &mut self.frame;
+        scale_frames(&mut buffer[..len], channels as u16, || {
+            *frame += 1;
+            let t = (*frame - 1).saturating_sub(start) as f32 / frames.max(1) as f32;
+            1.0 - t.min(1.0)
+        });
+        len
+    }
+}
+
+/// Where a `Gain` reads the amplitude it ramps to, in the audio thread.
+pub trait GainTarget {
+    fn target(&self) -> f32;
+}
+impl GainTarget for Control {
+    fn target(&self) -> f32 {
+        self.get()
+    }
+}
+
+/// Scale the sound by a gain that can change while it plays. The changes take 'ramp_time'
+/// seconds, instead of being a sudden jump.
+pub struct Gain<T: SoundSource, G: GainTarget = Control> {
+    inner: T,
+    target: G,
+    ramp_time: f32,
+    gain: f32,
+    /// Where the gain is written after each write, for the game to know when it is silent.
+    level: Option<Control>,
+}
+impl<T: SoundSource, G: GainTarget> Gain<T, G> {
+    pub fn new(inner: T, target: G, ramp_time: f32) -> Self {
+        Self {
+            inner,
+            gain: target.target(),
+            target,
+            ramp_time,
+            level: None,
+        }
+    }
+
+    /// Write the gain to 'level' as it ramps.
+    pub fn with_level(self, level: Control) -> Self {
+        level.set(self.gain);
+        Self {
+            level: Some(level),
+            ..self
+        }
+    }
+}
+impl<T: SoundSource, G: GainTarget> SoundSource for Gain<T, G> {
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+    fn reset(&mut self) {
+        self.inner.reset();
+    }
+    fn write_samples(&mut self, buffer: &mut [i16]) -> usize {
+        let len = self.inner.write_samples(buffer);
+        let target = self.target.target();
+        let step = 1.0 / (self.ramp_time * self.sample_rate() as f32);
+        let gain = &mut self.gain;
+        scale_frames(&mut buffer[..len], self.inner.channels(), || {
+            if *gain < target {
+                *gain = (*gain + step).min(target);
+            } else if *gain > target {
+                *gain = (*gain - step).max(target);
             }
-            buffer.len()
-        } else {
-            let mut i = 0;
-            let channels = self.inner.channels() as usize;
-            while i < buffer.len() {
-                if self.speed <= 0.0 {
-                    return i;
-                }
-                if (self.iter + 1.0) * channels as f32 >= self.in_buffer.len() as f32 {
-                    self.iter -= self.in_buffer.len() as f32 / channels as f32;
-                    let mut len = self.inner.write_samples(&mut self.in_buffer);
-                    while len < self.in_buffer.len() {
-                        self.inner.reset();
-                        len += self.inner.write_samples(&mut self.in_buffer[len..]);
-                    }
-                }
-                let t = self.iter.fract();
-                let j = self.iter as usize * channels;
-                for c in 0..channels {
-                    buffer[i + c] = (self.in_buffer[j + c] as f32 * t
-                        + self.in_buffer[j + c + channels] as f32 * (1.0 - t))
-                        as i16;
+            *gain
+        });
+        if let Some(level) = &self.level {
+            level.set(self.gain);
+        }
+        len
+    }
+}
+
+/// Reads a source one frame at a time, keeping the last two, to interpolate between them.
+struct FrameReader<T: SoundSource> {
+    inner: T,
+    input: Box<[i16]>,
+    /// The position of the next frame in 'input', and the number of samples in it.
+    read: usize,
+    available: usize,
+    previous: Vec<i16>,
+    next: Vec<i16>,
+}
+impl<T: SoundSource> FrameReader<T> {
+    fn new(inner: T) -> Self {
+        let channels = inner.channels() as usize;
+        Self {
+            input: vec![0; 256 * channels].into_boxed_slice(),
+            read: 0,
+            available: 0,
+            previous: vec![0; channels],
+            next: vec![0; channels],
+            inner,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+        self.read = 0;
+        self.available = 0;
+    }
+
+    /// Read the next frame. Return false if the source has ended.
+    fn advance(&mut self) -> bool {
+        let channels = self.next.len();
+        if self.read + channels > self.available {
+            self.available = self.inner.write_samples(&mut self.input);
+            self.read = 0;
+            if self.available < channels {
+                return false;
+            }
+        }
+        std::mem::swap(&mut self.previous, &mut self.next);
+        self.next
+            .copy_from_slice(&self.input[self.read..self.read + channels]);
+        self.read += channels;
+        true
+    }
+
+    /// Write the frame at 't', from 0 at the previous frame to 1 at the next one.
+    fn lerp(&self, t: f32, frame: &mut [i16]) {
+        for (c, sample) in frame.iter_mut().enumerate() {
+            let (a, b) = (self.previous[c] as f32, self.next[c] as f32);
+            *sample = (a + (b - a) * t) as i16;
+        }
+    }
+}
+
+/// Convert the sound to another sample rate, interpolating between its frames.
+pub struct Resample<T: SoundSource> {
+    reader: FrameReader<T>,
+    rate: u32,
+    /// The position between the previous and the next frame.
+    t: f64,
+    ended: bool,
+}
+impl<T: SoundSource> Resample<T> {
+    pub fn new(inner: T, rate: u32) -> Self {
+        Self {
+            reader: FrameReader::new(inner),
+            rate,
+            // read the first two frames before the first output
+            t: 2.0,
+            ended: false,
+        }
+    }
+}
+impl<T: SoundSource> SoundSource for Resample<T> {
+    fn channels(&self) -> u16 {
+        self.reader.inner.channels()
+    }
+    fn sample_rate(&self) -> u32 {
+        self.rate
+    }
+    fn reset(&mut self) {
+        self.reader.reset();
+        self.t = 2.0;
+        self.ended = false;
+    }
+    fn write_samples(&mut self, buffer: &mut [i16]) -> usize {
+        if self.reader.inner.sample_rate() == self.rate {
+            return self.reader.inner.write_samples(buffer);
+        }
+        if self.ended {
+            return 0;
+        }
+        let step = self.reader.inner.sample_rate() as f64 / self.rate as f64;
+        let channels = self.reader.next.len();
+        let mut len = 0;
+        while len + channels <= buffer.len() {
+            while self.t >= 1.0 {
+                if !self.reader.advance() {
+                    self.ended = true;
+                    return len;
                 }
-                self.iter += 1.0 * self.speed;
-                self.speed -=
-                    1.0 / (0.5 * self.sample_rate() as f32) * (1.0 - self.speed).max(0.05);
-                i += channels;
+                self.t -= 1.0;
+            }
+            self.reader
+                .lerp(self.t as f32, &mut buffer[len..len + channels]);
+            self.t += step;
+            len += channels;
+        }
+        len
+    }
+}
+
+/// Play the sound faster or slower, changing its pitch by 'ratio'.
+pub struct Pitch<T: SoundSource>(Resample<T>);
+impl<T: SoundSource> Pitch<T> {
+    pub fn new(inner: T, ratio: f32) -> Self {
+        // resampling to a lower rate, and playing at the original one, is faster
+        let rate = (inner.sample_rate() as f32 / ratio).round() as u32;
+        Self(Resample::new(inner, rate))
+    }
+}
+impl<T: SoundSource> SoundSource for Pitch<T> {
+    fn channels(&self) -> u16 {
+        self.0.channels()
+    }
+    fn sample_rate(&self) -> u32 {
+        self.0.reader.inner.sample_rate()
+    }
+    fn reset(&mut self) {
+        self.0.reset();
+    }
+    fn write_samples(&mut self, buffer: &mut [i16]) -> usize {
+        self.0.write_samples(buffer)
+    }
+}
+
+/// Place a mono or stereo sound between the left and right speakers, from -1, at the left, to
+/// 1, at the right. The output is always stereo.
+pub struct Pan<T: SoundSource> {
+    inner: T,
+    pan: f32,
+    buffer: Vec<i16>,
+}
+impl<T: SoundSource> Pan<T> {
+    pub fn new(inner: T, pan: f32) -> Self {
+        assert!(inner.channels() <= 2);
+        Self {
+            inner,
+            pan: pan.clamp(-1.0, 1.0),
+            buffer: Vec::new(),
+        }
+    }
+}
+impl<T: SoundSource> SoundSource for Pan<T> {
+    fn channels(&self) -> u16 {
+        2
+    }
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+    fn reset(&mut self) {
+        self.inner.reset();
+    }
+    fn write_samples(&mut self, buffer: &mut [i16]) -> usize {
+        // the center keeps the sound as it is, and the sides silence the other speaker
+        let left = (1.0 - self.pan).min(1.0);
+        let right = (1.0 + self.pan).min(1.0);
+        let len = if self.inner.channels() == 1 {
+            self.buffer.resize(buffer.len() / 2, 0);
+            let len = self.inner.write_samples(&mut self.buffer);
+            for (frame, &x) in buffer.chunks_mut(2).zip(self.buffer[..len].iter()) {
+                frame[0] = x;
+                frame[1] = x;
+            }
+            len * 2
+        } else {
+            self.inner.write_samples(buffer)
+        };
+        for frame in buffer[..len].chunks_mut(2) {
+            frame[0] = (frame[0] as f32 * left) as i16;
+            frame[1] = (frame[1] as f32 * right) as i16;
+        }
+        len
+    }
+}
+
+/// A mono sine tone that slides from one frequency to another, and fades out in 'duration'
+/// seconds.
+pub struct Tone {
+    from: f32,
+    to: f32,
+    amplitude: f32,
+    frames: usize,
+    sample_rate: u32,
+    frame: usize,
+    phase: f32,
+}
+impl Tone {
+    pub fn new(from: f32, to: f32, amplitude: f32, duration: f32, sample_rate: u32) -> Self {
+        Self {
+            from,
+            to,
+            amplitude,
+            frames: (duration * sample_rate as f32) as usize,
+            sample_rate,
+            frame: 0,
+            phase: 0.0,
+        }
+    }
+}
+impl SoundSource for Tone {
+    fn channels(&self) -> u16 {
+        1
+    }
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+    fn reset(&mut self) {
+        self.frame = 0;
+        self.phase = 0.0;
+    }
+    fn write_samples(&mut self, buffer: &mut [i16]) -> usize {
+        let len = buffer.len().min(self.frames - self.frame);
+        let rate = self.sample_rate as f32;
+        for x in buffer[..len].iter_mut() {
+            let t = self.frame as f32 / self.frames as f32;
+            // a short attack, to not start with a pop, and a smooth decay
+            let envelope = (self.frame as f32 / (0.005 * rate)).min(1.0) * (1.0 - t) * (1.0 - t);
+            *x = (self.phase.sin() * envelope * self.amplitude) as i16;
+            let frequency = self.from * (self.to / self.from).powf(t);
+            self.phase = (self.phase + 2.0 * PI * frequency / rate) % (2.0 * PI);
+            self.frame += 1;
+        }
+        len
+    }
+}
+
+/// The coefficient of a one-pole filter with a 'cutoff' frequency, in Hz.
+#[allow(dead_code)]
+fn one_pole(cutoff: f32, sample_rate: u32) -> f32 {
+    1.0 - (-2.0 * PI * cutoff / sample_rate as f32).exp()
+}
+
+/// Attenuate the frequencies above 'cutoff', in Hz, with a one-pole filter.
+#[allow(dead_code)]
+pub struct LowPass<T: SoundSource> {
+    inner: T,
+    cutoff: f32,
+    state: Vec<f32>,
+}
+#[allow(dead_code)]
+impl<T: SoundSource> LowPass<T> {
+    pub fn new(inner: T, cutoff: f32) -> Self {
+        Self {
+            state: vec![0.0; inner.channels() as usize],
+            inner,
+            cutoff,
+        }
+    }
+}
+impl<T: SoundSource> SoundSource for LowPass<T> {
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+    fn reset(&mut self) {
+        self.inner.reset();
+        self.state.iter_mut().for_each(|x| *x = 0.0);
+    }
+    fn write_samples(&mut self, buffer: &mut [i16]) -> usize {
+        let len = self.inner.write_samples(buffer);
+        let a = one_pole(self.cutoff, self.sample_rate());
+        for frame in buffer[..len].chunks_mut(self.state.len()) {
+            for (sample, state) in frame.iter_mut().zip(self.state.iter_mut()) {
+                *state += a * (*sample as f32 - *state);
+                *sample = *state as i16;
             }
+        }
+        len
+    }
+}
+
+/// Attenuate the frequencies under 'cutoff', in Hz, with a one-pole filter.
+#[allow(dead_code)]
+pub struct HighPass<T: SoundSource> {
+    inner: T,
+    cutoff: f32,
+    /// The low frequencies, that are removed from the sound.
+    state: Vec<f32>,
+}
+#[allow(dead_code)]
+impl<T: SoundSource> HighPass<T> {
+    pub fn new(inner: T, cutoff: f32) -> Self {
+        Self {
+            state: vec![0.0; inner.channels() as usize],
+            inner,
+            cutoff,
+        }
+    }
+}
+impl<T: SoundSource> SoundSource for HighPass<T> {
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+    fn reset(&mut self) {
+        self.inner.reset();
+        self.state.iter_mut().for_each(|x| *x = 0.0);
+    }
+    fn write_samples(&mut self, buffer: &mut [i16]) -> usize {
+        let len = self.inner.write_samples(buffer);
+        let a = one_pole(self.cutoff, self.sample_rate());
+        for frame in buffer[..len].chunks_mut(self.state.len()) {
+            for (sample, state) in frame.iter_mut().zip(self.state.iter_mut()) {
+                *state += a * (*sample as f32 - *state);
+                *sample = (*sample as f32 - *state) as i16;
+            }
+        }
+        len
+    }
+}
+
+/// Play many sources at once, summing them. All of them must have the same channels and sample
+/// rate. It ends when the longest one ends.
+#[allow(dead_code)]
+pub struct Mix {
+    sources: Vec<Box<dyn SoundSource + Send>>,
+    buffer: Vec<i16>,
+}
+#[allow(dead_code)]
+impl Mix {
+    pub fn new(sources: Vec<Box<dyn SoundSource + Send>>) -> Self {
+        assert!(!sources.is_empty());
+        for source in sources.iter() {
+            assert_eq!(source.channels(), sources[0].channels());
+            assert_eq!(source.sample_rate(), sources[0].sample_rate());
+        }
+        Self {
+            sources,
+            buffer: Vec::new(),
+        }
+    }
+}
+impl SoundSource for Mix {
+    fn channels(&self) -> u16 {
+        self.sources[0].channels()
+    }
+    fn sample_rate(&self) -> u32 {
+        self.sources[0].sample_rate()
+    }
+    fn reset(&mut self) {
+        self.sources.iter_mut().for_each(|x| x.reset());
+    }
+    fn write_samples(&mut self, buffer: &mut [i16]) -> usize {
+        buffer.iter_mut().for_each(|x| *x = 0);
+        self.buffer.resize(buffer.len(), 0);
+        let mut len = 0;
+        for source in self.sources.iter_mut() {
+            let written = source.write_samples(&mut self.buffer);
+            for (out, x) in buffer.iter_mut().zip(self.buffer[..written].iter()) {
+                *out = out.saturating_add(*x);
+            }
+            len = len.max(written);
+        }
+        len
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::needless_range_loop)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 8000;
+
+    /// A sine wave with amplitude 10000, the same in all channels.
+    struct Sine {
+        frequency: f32,
+        rate: u32,
+        channels: u16,
+        frames: usize,
+        frame: usize,
+    }
+    impl Sine {
+        fn new(frequency: f32, seconds: f32) -> Self {
+            Self::with_format(frequency, seconds, RATE, 1)
+        }
+
+        fn with_format(frequency: f32, seconds: f32, rate: u32, channels: u16) -> Self {
+            Self {
+                frequency,
+                rate,
+                channels,
+                frames: (seconds * rate as f32) as usize,
+                frame: 0,
+            }
+        }
+
+        fn sample(&self, frame: usize) -> i16 {
+            let t = frame as f32 / self.rate as f32;
+            ((2.0 * PI * self.frequency * t).sin() * 10000.0) as i16
+        }
+    }
+    impl SoundSource for Sine {
+        fn channels(&self) -> u16 {
+            self.channels
+        }
+        fn sample_rate(&self) -> u32 {
+            self.rate
+        }
+        fn reset(&mut self) {
+            self.frame = 0;
+        }
+        fn write_samples(&mut self, buffer: &mut [i16]) -> usize {
+            let channels = self.channels as usize;
+            let frames = (buffer.len() / channels).min(self.frames - self.frame);
+            for i in 0..frames {
+                let x = self.sample(self.frame + i);
+                buffer[i * channels..(i + 1) * channels]
+                    .iter_mut()
+                    .for_each(|s| *s = x);
+            }
+            self.frame += frames;
+            frames * channels
+        }
+    }
+
+    struct Silence {
+        frames: usize,
+        frame: usize,
+    }
+    impl Silence {
+        fn new(seconds: f32) -> Self {
+            Self {
+                frames: (seconds * RATE as f32) as usize,
+                frame: 0,
+            }
+        }
+    }
+    impl SoundSource for Silence {
+        fn channels(&self) -> u16 {
+            1
+        }
+        fn sample_rate(&self) -> u32 {
+            RATE
+        }
+        fn reset(&mut self) {
+            self.frame = 0;
+        }
+        fn write_samples(&mut self, buffer: &mut [i16]) -> usize {
+            let len = buffer.len().min(self.frames - self.frame);
+            buffer[..len].iter_mut().for_each(|x| *x = 0);
+            self.frame += len;
+            len
+        }
+    }
+
+    /// Read all the samples of 'source', in small buffers, like the audio thread does.
+    fn render(source: &mut impl SoundSource) -> Vec<i16> {
+        let mut out = Vec::new();
+        let mut buffer = [0; 100];
+        loop {
+            let len = source.write_samples(&mut buffer);
+            out.extend_from_slice(&buffer[..len]);
+            if len == 0 {
+                return out;
+            }
+        }
+    }
+
+    fn peak(samples: &[i16]) -> i16 {
+        samples
+            .iter()
+            .map(|x| x.saturating_abs())
+            .max()
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn fade_in_rises_from_silence() {
+        let mut source = FadeIn::new(Sine::new(200.0, 1.0), 0.5);
+        let out = render(&mut source);
+        assert_eq!(out.len(), RATE as usize);
+        let half = RATE as usize / 2;
+        assert!(peak(&out[..200]) < 1000);
+        assert!(peak(&out[..half]) < 10000);
+        let sine = Sine::new(200.0, 1.0);
+        for i in half..out.len() {
+            assert_eq!(out[i], sine.sample(i));
+        }
+    }
+
+    #[test]
+    fn fade_out_lowers_to_silence_and_ends() {
+        let mut source = FadeOut::new(Sine::new(200.0, 2.0), 0.5, 0.25);
+        let out = render(&mut source);
+        assert_eq!(out.len(), RATE as usize * 3 / 4);
+        let sine = Sine::new(200.0, 1.0);
+        for i in 0..RATE as usize / 2 {
+            assert_eq!(out[i], sine.sample(i));
+        }
+        assert!(peak(&out[out.len() - 100..]) < 1000);
+
+        source.reset();
+        assert_eq!(render(&mut source), out);
+    }
+
+    #[test]
+    fn gain_ramps_to_the_control() {
+        let control = Control::new(1.0);
+        let mut source = Gain::new(Sine::new(200.0, 1.0), control.clone(), 0.1);
+        let mut buffer = vec![0; RATE as usize / 2];
+        source.write_samples(&mut buffer);
+        assert!(peak(&buffer) > 9900);
+
+        control.set(0.5);
+        source.write_samples(&mut buffer);
+        // not a sudden jump
+        assert!(peak(&buffer[..40]) > 9000);
+        let tail = &buffer[RATE as usize / 4..];
+        assert!(peak(tail) > 4900 && peak(tail) <= 5000);
+
+        // the level follows the ramp, not the control
+        let level = Control::new(-1.0);
+        let mut source =
+            Gain::new(Sine::new(200.0, 1.0), control.clone(), 0.1).with_level(level.clone());
+        assert_eq!(level.get(), 0.5);
+        control.set(0.0);
+        source.write_samples(&mut buffer[..RATE as usize / 40]);
+        assert!(level.get() > 0.2 && level.get() < 0.3);
+        source.write_samples(&mut buffer);
+        assert_eq!(level.get(), 0.0);
+    }
+
+    #[test]
+    fn resample_keeps_the_duration_and_pitch() {
+        let mut source = Resample::new(Sine::with_format(100.0, 1.0, 4000, 2), RATE);
+        assert_eq!(source.sample_rate(), RATE);
+        let out = render(&mut source);
+        let frames = out.len() / 2;
+        assert!((frames as i32 - RATE as i32).abs() <= 2);
+        // both channels are the same, and there are two zero crossings per cycle
+        let left = out.iter().step_by(2).copied().collect::<Vec<i16>>();
+        assert!(out.chunks(2).all(|x| x[0] == x[1]));
+        let crossings = left.windows(2).filter(|x| (x[0] < 0) != (x[1] < 0)).count();
+        assert!((crossings as i32 - 200).abs() <= 2);
+
+        // nothing changes when the rate is the same
+        let mut source = Resample::new(Sine::new(200.0, 1.0), RATE);
+        assert_eq!(render(&mut source), render(&mut Sine::new(200.0, 1.0)));
+    }
+
+    #[test]
+    fn speed_follows_the_control_and_loops() {
+        let control = Control::new(1.0);
+        let mut source = Speed::new(Sine::new(200.0, 0.25), control.clone());
+        let mut buffer = vec![0; RATE as usize / 2];
+        assert_eq!(source.write_samples(&mut buffer), buffer.len());
+        // at normal speed it is the inner source, in loop
+        let quarter = RATE as usize / 4;
+        let sine = Sine::new(200.0, 1.0);
+        for i in 0..buffer.len() {
+            assert_eq!(buffer[i], sine.sample(i % quarter));
+        }
+
+        // it ramps down to silence, and stays silent
+        control.set(0.0);
+        source.write_samples(&mut buffer);
+        assert!(peak(&buffer[..40]) > 5000);
+        source.write_samples(&mut buffer);
+        source.write_samples(&mut buffer);
+        assert_eq!(peak(&buffer), 0);
+
+        // and can start again
+        control.set(1.0);
+        source.write_samples(&mut buffer);
+        source.write_samples(&mut buffer);
+        assert!(peak(&buffer) > 9000);
+    }
+
+    /// A stereo source where each frame is its own index, to check where it plays from.
+    struct Counter {
+        frames: usize,
+        frame: usize,
+    }
+    impl SoundSource for Counter {
+        fn channels(&self) -> u16 {
+            2
+        }
+        fn sample_rate(&self) -> u32 {
+            RATE
+        }
+        fn reset(&mut self) {
+            self.frame = 0;
+        }
+        fn write_samples(&mut self, buffer: &mut [i16]) -> usize {
+            let frames = (buffer.len() / 2).min(self.frames - self.frame);
+            for i in 0..frames {
+                buffer[2 * i] = (self.frame + i) as i16;
+                buffer[2 * i + 1] = -((self.frame + i) as i16);
+            }
+            self.frame += frames;
+            frames * 2
+        }
+    }
+
+    #[test]
+    fn loop_points_repeat_the_region() {
+        let counter = || Counter {
+            frames: 3000,
+            frame: 0,
+        };
+        let region = LoopRegion::parse("loop_start 1000 # after the intro\nloop_end 2500").unwrap();
+        let mut source = LoopPoints::new(counter(), counter(), region);
+        let mut out = Vec::new();
+        let mut buffer = [0; 70];
+        while out.len() < 2 * 6000 {
+            assert_eq!(source.write_samples(&mut buffer), buffer.len());
+            out.extend_from_slice(&buffer);
+        }
+        let expected = (0..2500).chain((0..).flat_map(|_| 1000..2500));
+        for (frame, x) in out.chunks(2).zip(expected) {
+            assert_eq!(frame, [x as i16, -(x as i16)]);
+        }
+
+        // without an end, it loops at the end of the source
+        let region = LoopRegion::parse("loop_start 1000").unwrap();
+        let mut source = LoopPoints::new(counter(), counter(), region);
+        let mut buffer = vec![0; 2 * 4000];
+        source.write_samples(&mut buffer);
+        assert_eq!(buffer[2 * 2999], 2999);
+        assert_eq!(buffer[2 * 3000], 1000);
+        assert_eq!(buffer[2 * 3999], 1999);
+    }
+
+    #[test]
+    fn loop_region_rejects_invalid_files() {
+        assert!(LoopRegion::parse("").is_err());
+        assert!(LoopRegion::parse("loop_end 10").is_err());
+        assert!(LoopRegion::parse("loop_start 10\nloop_end 10").is_err());
+        assert!(LoopRegion::parse("loop_start ten").is_err());
+        assert!(LoopRegion::parse("loop_start 10\nloop 20").is_err());
+        assert_eq!(
+            LoopRegion::parse(include_str!("../res/music/pipe.loop")),
+            Ok(LoopRegion {
+                start: 302080,
+                end: None
+            })
+        );
+    }
+
+    #[test]
+    fn pitch_changes_the_frequency() {
+        let crossings = |x: &[i16]| x.windows(2).filter(|x| (x[0] < 0) != (x[1] < 0)).count();
+        let out = render(&mut Pitch::new(Sine::new(100.0, 1.0), 2.0));
+        assert_eq!(out.len(), RATE as usize / 2);
+        // the same cycles of the sine, in half of the time
+        assert!((crossings(&out) as i32 - 200).abs() <= 2);
+
+        let out = render(&mut Pitch::new(Sine::new(100.0, 1.0), 0.5));
+        assert!((out.len() as i32 - 2 * RATE as i32).abs() <= 2);
+        assert!((crossings(&out) as i32 - 200).abs() <= 2);
+    }
+
+    #[test]
+    fn pan_moves_between_the_speakers() {
+        let sine = Sine::new(200.0, 1.0);
+        let mut source = Pan::new(Sine::new(200.0, 1.0), 0.0);
+        assert_eq!(source.channels(), 2);
+        let out = render(&mut source);
+        assert_eq!(out.len(), 2 * RATE as usize);
+        for i in 0..RATE as usize {
+            assert_eq!(out[2 * i], sine.sample(i));
+            assert_eq!(out[2 * i + 1], sine.sample(i));
+        }
+
+        let out = render(&mut Pan::new(Sine::new(200.0, 1.0), -1.0));
+        assert!(peak(&out.iter().step_by(2).copied().collect::<Vec<_>>()) > 9900);
+        assert_eq!(
+            peak(&out.iter().skip(1).step_by(2).copied().collect::<Vec<_>>()),
+            0
+        );
+
+        let out = render(&mut Pan::new(Sine::with_format(200.0, 1.0, RATE, 2), 0.5));
+        for i in 0..RATE as usize {
+            assert_eq!(out[2 * i + 1], sine.sample(i));
+            assert!((out[2 * i] - sine.sample(i) / 2).abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn tone_rises_and_fades() {
+        let mut source = Tone::new(200.0, 400.0, 10000.0, 0.5, RATE);
+        let out = render(&mut source);
+        assert_eq!(out.len(), RATE as usize / 2);
+        assert_eq!(out[0], 0);
+        let crossings = |x: &[i16]| x.windows(2).filter(|x| (x[0] < 0) != (x[1] < 0)).count();
+        let quarter = RATE as usize / 8;
+        assert!(crossings(&out[3 * quarter..]) > crossings(&out[..quarter]));
+        assert!(peak(&out[..quarter]) > peak(&out[3 * quarter..]));
+        assert!(peak(&out) <= 10000);
+    }
+
+    #[test]
+    fn low_pass_keeps_low_frequencies() {
+        let low = render(&mut LowPass::new(Sine::new(50.0, 1.0), 500.0));
+        let high = render(&mut LowPass::new(Sine::new(3000.0, 1.0), 500.0));
+        assert!(peak(&low[RATE as usize / 2..]) > 9000);
+        assert!(peak(&high[RATE as usize / 2..]) < 3000);
+        assert_eq!(
+            peak(&render(&mut LowPass::new(Silence::new(1.0), 500.0))),
+            0
+        );
+    }
+
+    #[test]
+    fn high_pass_keeps_high_frequencies() {
+        let low = render(&mut HighPass::new(Sine::new(50.0, 1.0), 500.0));
+        let high = render(&mut HighPass::new(Sine::new(3000.0, 1.0), 500.0));
+        assert!(peak(&low[RATE as usize / 2..]) < 1500);
+        assert!(peak(&high[RATE as usize / 2..]) > 8000);
+        assert_eq!(
+            peak(&render(&mut HighPass::new(Silence::new(1.0), 500.0))),
+            0
+        );
+    }
+
+    #[test]
+    fn mix_sums_the_sources() {
+        let mut source = Mix::new(vec![
+            Box::new(Sine::new(200.0, 1.0)),
+            Box::new(Sine::new(200.0, 0.5)),
+            Box::new(Silence::new(0.25)),
+        ]);
+        let out = render(&mut source);
+        assert_eq!(out.len(), RATE as usize);
+        let sine = Sine::new(200.0, 1.0);
+        for i in 0..out.len() {
+            let expected = if i < RATE as usize / 2 { 2 } else { 1 };
+            assert_eq!(out[i], sine.sample(i) * expected);
+        }
 
-            buffer.len()
+        // loud sources are clipped, instead of wrapping around
+        let loud = (0..4).map(|_| Box::new(Sine::new(200.0, 1.0)) as Box<dyn SoundSource + Send>);
+        let out = render(&mut Mix::new(loud.collect()));
+        for i in 0..out.len() {
+            let expected = (sine.sample(i) as i32 * 4).clamp(i16::MIN as i32, i16::MAX as i32);
+            assert_eq!(out[i] as i32, expected);
         }
     }
 }
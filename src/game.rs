@@ -1,62 +1,199 @@
 #![allow(clippy::needless_range_loop)]
 
-use audio_engine::{Sound, WavDecoder};
 use sprite_render::{Camera, SpriteInstance, SpriteRender, Texture, TextureId};
 
-use winit::{dpi::PhysicalSize, window::WindowId};
+use winit::{dpi::PhysicalSize, event::VirtualKeyCode, window::WindowId};
 
-use rand::seq::index::sample;
 use rand::Rng;
 
 use std::f32::consts::PI;
-use std::io::Cursor;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
 
+use crate::mixer::{Bus, Mixer};
+use crate::music::{MusicDirector, Scene};
+use crate::sound_bank::SoundBank;
 use crate::time::Instant;
 
+#[cfg(all(debug_assertions, not(target_arch = "wasm32")))]
+mod assets;
+mod balance;
+mod classic;
+mod coloring;
+mod input;
+mod locale;
+mod maze;
+mod mode;
+mod rating;
+mod scramble;
+mod settings;
+mod text;
+mod theme;
 mod utils;
 
+pub use input::Action;
+use locale::Locale;
+pub use maze::{Algorithm, MazeConfig};
+pub use mode::GameMode;
+pub use settings::Settings;
+use text::Label;
+use theme::Theme;
+
 use ezing::*;
 fn lerp(t: f32, a: f32, b: f32) -> f32 {
     a + (b - a) * t
 }
 
-const COLORS: &[[u8; 4]] = &[
-    [0, 255, 0, 255],
-    [0, 204, 255, 255],
-    [102, 0, 255, 255],
-    [255, 0, 102, 255],
-    [255, 204, 0, 255],
-    [0, 255, 76, 255],
-    [0, 128, 255, 255],
-    [178, 0, 255, 255],
-    [255, 0, 26, 255],
-    [230, 255, 0, 255],
-    [0, 255, 153, 255],
-    [0, 51, 255, 255],
-    [255, 0, 255, 255],
-    [255, 51, 0, 255],
-    [153, 255, 0, 255],
-    [0, 255, 229, 255],
-    [25, 0, 255, 255],
-    [255, 0, 179, 255],
-    [255, 127, 0, 255],
-    [77, 255, 0, 255],
-];
+/// The atlas packed by `build.rs`, with all the art of the game.
+static ATLAS: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/atlas.png"));
 
 mod atlas {
     include!(concat!(env!("OUT_DIR"), "/atlas.rs"));
     pub const PIPES: [[f32; 4]; 5] = [PIPE_ONE, PIPE_TWO_L, PIPE_TWO, PIPE_TREE, PIPE_FOUR];
     pub const NUMBERS: [[f32; 4]; 10] = [N0, N1, N2, N3, N4, N5, N6, N7, N8, N9];
+    pub const MARKERS: [[f32; 4]; 8] = [
+        MARKER_DOT,
+        MARKER_RING,
+        MARKER_TRIANGLE,
+        MARKER_SQUARE,
+        MARKER_DIAMOND,
+        MARKER_PLUS,
+        MARKER_CROSS,
+        MARKER_STAR,
+    ];
+}
+
+/// The connections of a pipe, as a bitmask of the directions right, down, left and up.
+fn pipe_mask(kind: u8, dir: u8) -> u8 {
+    match kind {
+        0 => 0b00010001u8,
+        1 => 0b00110011,
+        2 => 0b01010101,
+        3 => 0b11101110,
+        4 => 0b11111111,
+        _ => 0,
+    }
+    .rotate_left(dir as u32)
+        & 0b1111
+}
+
+/// The space beside the board taken by the HUD, in world units.
+const HUD_SIZE: f32 = 1.1 * 1280.0 / 720.0 - 1.1;
+/// The border of the panel around the board.
+const BOARD_MARGIN: f32 = 0.1;
+
+/// The kind of a tile without a pipe.
+const EMPTY: u8 = 5;
+const EMPTY_COLOR: [u8; 4] = [0, 90, 0, 255];
+
+/// The rules of the game being played.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Ruleset {
+    /// Rotate the tiles until all pipes are connected.
+    NetWalk,
+    /// Place pieces from a queue, to build a path for the water.
+    Classic,
+    /// Rotate the tiles until all terminals are connected to the power source.
+    Power,
+}
+impl Ruleset {
+    fn name(self) -> &'static str {
+        match self {
+            Ruleset::NetWalk => "netwalk",
+            Ruleset::Classic => "classic",
+            Ruleset::Power => "power",
+        }
+    }
+
+    fn icon(self) -> [f32; 4] {
+        match self {
+            Ruleset::NetWalk => atlas::PIPE_FOUR,
+            Ruleset::Classic => atlas::PIPE_TWO_L,
+            Ruleset::Power => atlas::SOURCE,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "netwalk" => Ruleset::NetWalk,
+            "classic" => Ruleset::Classic,
+            "power" => Ruleset::Power,
+            _ => return None,
+        })
+    }
 }
 
+const POWERED_COLOR: [u8; 4] = [255, 230, 60, 255];
+const UNPOWERED_COLOR: [u8; 4] = [90, 90, 90, 255];
+
 mod sounds {
-    pub static CLICK: &[u8] = include_bytes!("../res/sound/click.wav");
-    pub static CONFIRM: &[u8] = include_bytes!("../res/sound/confirm.wav");
-    pub static NEGATE: &[u8] = include_bytes!("../res/sound/negate.wav");
-    pub static WHOOSH: &[u8] = include_bytes!("../res/sound/whoosh.wav");
+    use crate::audio_effect::Tone;
+    use crate::sound_bank::{Polyphony, SoundBank};
+
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Effect {
+        Click,
+        Confirm,
+        Negate,
+        Whoosh,
+        /// A rising tone, made by the game instead of read from a file, played at a higher
+        /// pitch for bigger regions.
+        Merge,
+        /// A short falling tone, made by the game, played for each point lost when the life is
+        /// low.
+        Tick,
+    }
+
+    static CLICK: &[u8] = include_bytes!("../res/sound/click.wav");
+    static CONFIRM: &[u8] = include_bytes!("../res/sound/confirm.wav");
+    static NEGATE: &[u8] = include_bytes!("../res/sound/negate.wav");
+    static WHOOSH: &[u8] = include_bytes!("../res/sound/whoosh.wav");
+
+    /// Each effect, with its file in `res/sound`, its embedded contents, and its polyphony.
+    fn effects() -> [(Effect, &'static str, &'static [u8], Polyphony); 4] {
+        [
+            // fast clicking should not pile up voices
+            (Effect::Click, "click.wav", CLICK, Polyphony::new(4, 0.03)),
+            (
+                Effect::Confirm,
+                "confirm.wav",
+                CONFIRM,
+                Polyphony::new(1, 0.1),
+            ),
+            (
+                Effect::Negate,
+                "negate.wav",
+                NEGATE,
+                Polyphony::new(2, 0.08),
+            ),
+            (Effect::Whoosh, "whoosh.wav", WHOOSH, Polyphony::new(1, 0.2)),
+        ]
+    }
+
+    /// Decode all the sound effects into 'bank'.
+    pub fn load(bank: &mut SoundBank<Effect>) {
+        for &(effect, _, wav, polyphony) in effects().iter() {
+            assert!(bank.load(effect, wav, polyphony), "invalid embedded sound");
+        }
+        let tone = Tone::new(220.0, 330.0, 6000.0, 0.15, 44100);
+        bank.load_source(Effect::Merge, tone, Polyphony::new(2, 0.05));
+        let tick = Tone::new(1760.0, 1320.0, 3000.0, 0.04, 44100);
+        bank.load_source(Effect::Tick, tick, Polyphony::new(2, 0.03));
+    }
+
+    /// Decode again the sound effects from their files in `res/sound`, keeping the ones that
+    /// could not be read.
+    #[cfg(all(debug_assertions, not(target_arch = "wasm32")))]
+    pub fn reload(bank: &mut SoundBank<Effect>) {
+        for &(effect, name, _, polyphony) in effects().iter() {
+            let path = super::assets::path("res/sound").join(name);
+            match std::fs::read(&path).map(|wav| bank.load(effect, wav, polyphony)) {
+                Ok(true) => eprintln!("reloaded '{}'", path.display()),
+                Ok(false) => eprintln!("invalid sound file '{}'", path.display()),
+                Err(err) => eprintln!("could not read '{}': {}", path.display(), err),
+            }
+        }
+    }
 }
+use sounds::Effect;
 
 struct Pipe {
     dir: u8,
@@ -68,32 +205,50 @@ struct Pipe {
     color_time: f32,
     previous_color: [u8; 4],
     target_color: u16,
+    /// The marker of the region of the pipe, in `atlas::MARKERS`.
+    marker: u16,
+    target_rgba: [u8; 4],
+    color: [u8; 4],
+    locked: bool,
 }
 impl Pipe {
     fn new(x: f32, y: f32, size: f32, texture: TextureId, kind: u8, dir: u8) -> Self {
         Self {
             dir,
             kind,
-            sprite: SpriteInstance::new(
-                x,
-                y,
-                size * 1.01,
-                size * 1.01,
-                texture,
-                atlas::PIPES[kind as usize],
-            ),
+            sprite: if kind == EMPTY {
+                SpriteInstance::new(x, y, size * 0.92, size * 0.92, texture, atlas::BLANCK)
+            } else {
+                SpriteInstance::new(
+                    x,
+                    y,
+                    size * 1.01,
+                    size * 1.01,
+                    texture,
+                    atlas::PIPES[kind as usize],
+                )
+            },
             angle: 0.0,
             previous_angle: 0.0,
             anim_time: 0.000001,
             color_time: 0.000001,
             previous_color: [0; 4],
             target_color: 0,
+            marker: 0,
+            target_rgba: [0; 4],
+            color: [0; 4],
+            locked: false,
         }
     }
 
-    fn animate(&mut self, dt: f32) {
+    /// With 'reduced_motion', the pipe turns right away instead of with a elastic animation.
+    fn animate(&mut self, dt: f32, reduced_motion: bool) {
         if self.anim_time != 0.0 {
-            self.anim_time = (self.anim_time - dt * 0.7).max(0.0);
+            self.anim_time = if reduced_motion {
+                0.0
+            } else {
+                (self.anim_time - dt * 0.7).max(0.0)
+            };
             let d =
                 ((self.dir as f32 * PI / 2.0) - self.previous_angle + PI).rem_euclid(2.0 * PI) - PI;
             let t = elastic_out(1.0 - self.anim_time);
@@ -104,49 +259,83 @@ impl Pipe {
             self.color_time = (self.color_time - dt * 3.0).max(0.0);
             let t = 1.0 - self.color_time;
             let color = [
-                lerp(
-                    t,
-                    self.previous_color[0] as f32,
-                    COLORS[self.target_color as usize % COLORS.len()][0] as f32,
-                ) as u8,
-                lerp(
-                    t,
-                    self.previous_color[1] as f32,
-                    COLORS[self.target_color as usize % COLORS.len()][1] as f32,
-                ) as u8,
-                lerp(
-                    t,
-                    self.previous_color[2] as f32,
-                    COLORS[self.target_color as usize % COLORS.len()][2] as f32,
-                ) as u8,
+                lerp(t, self.previous_color[0] as f32, self.target_rgba[0] as f32) as u8,
+                lerp(t, self.previous_color[1] as f32, self.target_rgba[1] as f32) as u8,
+                lerp(t, self.previous_color[2] as f32, self.target_rgba[2] as f32) as u8,
                 255,
             ];
-            self.sprite.set_color(color);
+            self.color = color;
+            self.sprite.set_color(self.shade(color));
         }
     }
 
-    /// Is called when there is a mouse click over self.
-    /// When right is true it is a right mouse button click, otherwise is left.
-    fn click(&mut self, right: bool, play_sound: bool) {
-        if right {
-            self.dir = (self.dir + 1) % 4;
+    /// Darken the color of locked pipes.
+    fn shade(&self, color: [u8; 4]) -> [u8; 4] {
+        if self.locked {
+            [color[0] / 3, color[1] / 3, color[2] / 3, color[3]]
         } else {
-            self.dir = (self.dir + 4 - 1) % 4;
+            color
         }
-        if play_sound {
-            crate::audio_engine()
-                .new_sound(WavDecoder::new(Cursor::new(sounds::CLICK)).unwrap())
-                .unwrap()
-                .play();
+    }
+
+    /// Move the pipe to the tile at ('x', 'y'), with 'size'.
+    fn set_layout(&mut self, x: f32, y: f32, size: f32) {
+        let scale = if self.kind == EMPTY { 0.92 } else { 1.01 };
+        self.sprite.set_position(x, y);
+        self.sprite.set_size(size * scale, size * scale);
+    }
+
+    /// Set the color right away, without animation.
+    fn set_rgba(&mut self, color: [u8; 4]) {
+        self.color_time = 0.0;
+        self.color = color;
+        self.target_rgba = color;
+        self.sprite.set_color(self.shade(color));
+    }
+
+    /// Replace the pipe by another one, like when placing a piece in a empty tile.
+    fn set_kind(&mut self, kind: u8, dir: u8, size: f32) {
+        self.kind = kind;
+        self.dir = dir;
+        self.sprite.set_uv_rect(atlas::PIPES[kind as usize]);
+        self.sprite.set_size(size * 1.01, size * 1.01);
+        self.angle = dir as f32 * PI / 2.0;
+        self.previous_angle = self.angle;
+        self.anim_time = 0.0;
+        self.sprite.set_angle(self.angle);
+    }
+
+    fn set_locked(&mut self, locked: bool) {
+        self.locked = locked;
+        self.sprite.set_color(self.shade(self.color));
+    }
+
+    /// Is called when the pipe is rotated by the player.
+    /// When clockwise is false, it rotates counterclockwise.
+    fn click(&mut self, clockwise: bool) {
+        if clockwise {
+            self.turn_to((self.dir + 1) % 4);
+        } else {
+            self.turn_to((self.dir + 4 - 1) % 4);
         }
+    }
+
+    fn turn_to(&mut self, dir: u8) {
+        self.dir = dir;
         self.anim_time = 1.0;
         self.previous_angle = self.angle;
     }
 
-    fn change_color(&mut self, target: u16) {
-        if target != self.target_color {
-            self.previous_color = self.sprite.color;
-            self.target_color = target;
+    fn change_color(&mut self, target: u16, colors: &[[u8; 4]]) {
+        self.target_color = target;
+        self.fade_to(colors[target as usize % colors.len()]);
+    }
+
+    /// Change the color with a animated transition.
+    fn fade_to(&mut self, color: [u8; 4]) {
+        if color != self.target_rgba {
+            self.previous_color = self.color;
+            self.target_rgba = color;
             self.color_time = 1.0;
         }
     }
@@ -157,7 +346,14 @@ pub struct Input {
     pub mouse_x: f32,
     pub mouse_y: f32,
     pub mouse_left_state: u8,
+    /// If the left button is held down, to drag things.
+    pub mouse_left_down: bool,
     pub mouse_rigth_state: u8,
+    pub mouse_middle_state: u8,
+    /// The amount scrolled since the last update, in lines. Positive is up.
+    pub mouse_wheel: f32,
+    /// The keys pressed since the last update.
+    pub keys: Vec<VirtualKeyCode>,
 }
 impl Input {
     /// update button state (from 'Pressed' to 'Down)
@@ -174,91 +370,171 @@ impl Input {
             3 => 1,
             _ => 0,
         };
+        self.mouse_middle_state = match self.mouse_middle_state {
+            0 => 0,
+            1 => 2,
+            3 => 1,
+            _ => 0,
+        };
+        self.mouse_wheel = 0.0;
+        self.keys.clear();
     }
 }
 
 struct GameBoard<R: Rng> {
-    sound_effects: bool,
-    music: Sound,
-    slow_down_effect: Arc<AtomicBool>,
+    ruleset: Ruleset,
+    mode: GameMode,
+    /// The score of the run that just ended, not yet recorded in the settings.
+    finished_score: Option<u32>,
+    classic: classic::Classic,
+    queue_sprites: Vec<SpriteInstance>,
+    queue_dirty: bool,
+    power_source: usize,
+    powered: Vec<bool>,
+    power_sprites: Vec<(usize, SpriteInstance)>,
+    maze_config: MazeConfig,
+    balance: balance::Balance,
+    balance_watcher: balance::Watcher,
+    /// If true, the solution is a tree, and a closed loop is not accepted.
+    loop_free: bool,
+    loop_tiles: Vec<usize>,
+    loop_sprites: Vec<SpriteInstance>,
+    loop_anim: f32,
+    mixer: Mixer,
+    sound_bank: SoundBank<Effect>,
+    theme: Theme,
+    locale: Locale,
+    /// Draw a glyph over each region, so they can be told apart without their colors.
+    region_markers: bool,
+    /// Avoid animations that move or spin things around.
+    reduced_motion: bool,
+    /// The music, that speeds up when life runs low, and stops on a loss.
+    music: MusicDirector,
     width: u8,
     height: u8,
+    /// The size of the screen, in world units.
+    screen: [f32; 2],
     pipes: Vec<Pipe>,
+    solution: Box<[i8]>,
+    hovered: Option<usize>,
+    undo_stack: Vec<(usize, bool)>,
     regions: Vec<u16>,
     region_id_pool: Vec<u16>,
     number_regions: u16,
-    color_pool: Vec<u16>,
-    number_colors: u16,
     texture: TextureId,
     win_anim: f32,
     lose_anim: f32,
-    win_sprite: SpriteInstance,
+    /// The message shown when a level is won or the run is lost.
+    banner: Label,
     highlight_sprite: SpriteInstance,
     again_button: Button,
     level: u32,
     life: u32,
     life_time: f32,
     life_dirty: bool,
-    life_text: SpriteInstance,
+    life_text: Label,
     life_number: Vec<SpriteInstance>,
+    /// If the life is low, and the player is being warned.
+    warning: bool,
+    /// The phase of the pulse of the life digits, while warning.
+    warning_anim: f32,
+    /// A red border that flashes on each click that costs life, while warning.
+    vignette: SpriteInstance,
+    vignette_anim: f32,
     score: u32,
     level_score: u32,
     score_dirty: bool,
-    score_text: SpriteInstance,
+    score_text: Label,
     score_number: Vec<SpriteInstance>,
     click_count: u32,
+    /// The par of the level: the minimum number of clicks to solve it.
     expect_min_click_count: u32,
+    par_time: f32,
+    rating: rating::LevelRating,
+    run_summary: rating::RunSummary,
+    star_sprites: Vec<SpriteInstance>,
+    bonus_number: Vec<SpriteInstance>,
+    summary_sprites: Vec<SpriteInstance>,
+    summary_text: Label,
     game_start: Instant,
     level_start: Instant,
     rng: R,
 }
 impl<R: Rng> GameBoard<R> {
-    pub fn new(
-        texture: TextureId,
-        rng: R,
-        music: Sound,
-        slow_down_effect: Arc<AtomicBool>,
-    ) -> Self {
+    pub fn new(texture: TextureId, rng: R, music: MusicDirector, mixer: Mixer) -> Self {
         let mut highlight_sprite =
             SpriteInstance::new(-100.0, 0.0, 1.0, 1.0, texture, atlas::BLANCK);
         highlight_sprite.set_color([255, 255, 255, 64]);
+        let balance = balance::Balance::load();
+        let mut sound_bank = SoundBank::new(mixer.clone(), Bus::Effects);
+        sounds::load(&mut sound_bank);
         Self {
-            sound_effects: true,
+            ruleset: Ruleset::NetWalk,
+            mode: GameMode::Standard,
+            finished_score: None,
+            classic: classic::Classic::default(),
+            queue_sprites: Vec::new(),
+            queue_dirty: false,
+            power_source: 0,
+            powered: Vec::new(),
+            power_sprites: Vec::new(),
+            maze_config: MazeConfig::default(),
+            life: balance.start_life,
+            life_time: balance.drain_delay,
+            balance,
+            balance_watcher: balance::Watcher::default(),
+            loop_free: false,
+            loop_tiles: Vec::new(),
+            loop_sprites: Vec::new(),
+            loop_anim: 0.0,
+            mixer,
+            sound_bank,
+            theme: Theme::default(),
+            locale: Locale::new("en"),
+            region_markers: false,
+            reduced_motion: false,
             music,
-            slow_down_effect,
             width: 0,
             height: 0,
+            screen: [2.2, 2.2],
             pipes: Vec::new(),
+            solution: Box::new([]),
+            hovered: None,
+            undo_stack: Vec::new(),
             regions: Vec::new(),
             region_id_pool: Vec::new(),
             number_regions: 0,
-            color_pool: Vec::new(),
-            number_colors: 0,
             win_anim: 0.0,
             lose_anim: 0.0,
-            win_sprite: SpriteInstance::new(0.0, 0.0, 1.0, 1.0, texture, atlas::YOU_WIN),
+            banner: Label::new("", 0.0, 0.0, 0.45, texture),
             highlight_sprite,
-            again_button: Button::new(
-                SpriteInstance::new_height_prop(1.2, -0.9, 0.1, texture, atlas::PLAY_AGAIN)
-                    .with_color([255, 255, 0, 255]),
-                [-0.30, 0.30, -0.06, 0.06],
+            again_button: Button::with_label(
+                Label::new("PLAY AGAIN", 1.2, -0.9, 0.14, texture).with_color([255, 255, 0, 255]),
+                texture,
             ),
             texture,
             level: 0,
-            life: 10,
-            life_time: 1.0,
             life_dirty: true,
-            life_text: SpriteInstance::new_height_prop(1.2, -0.9, 0.1, texture, atlas::TIME)
-                .with_color([0, 240, 0, 255]),
+            life_text: Label::new("TIME", 1.2, -0.9, 0.14, texture).with_color([0, 240, 0, 255]),
             life_number: Vec::new(),
+            warning: false,
+            warning_anim: 0.0,
+            vignette: SpriteInstance::new(0.0, 0.0, 2.2, 2.2, texture, atlas::VIGNETTE),
+            vignette_anim: 0.0,
             score: 0,
             level_score: 0,
             score_dirty: true,
-            score_text: SpriteInstance::new_height_prop(1.2, -0.9, 0.1, texture, atlas::SCORE)
-                .with_color([0, 240, 0, 255]),
+            score_text: Label::new("SCORE", 1.2, -0.9, 0.14, texture).with_color([0, 240, 0, 255]),
             score_number: Vec::new(),
             click_count: 0,
             expect_min_click_count: 0,
+            par_time: 0.0,
+            rating: rating::LevelRating::default(),
+            run_summary: rating::RunSummary::default(),
+            star_sprites: Vec::new(),
+            bonus_number: Vec::new(),
+            summary_sprites: Vec::new(),
+            summary_text: Label::new("", 0.0, 0.0, 0.1, texture).with_color([0, 240, 0, 255]),
             game_start: Instant::now(),
             level_start: Instant::now(),
             rng,
@@ -266,98 +542,300 @@ impl<R: Rng> GameBoard<R> {
     }
 
     pub fn reset(&mut self) {
-        self.music.play();
+        // a new run starts the music from its intro
+        self.music.restart(Scene::Playing);
         self.win_anim = 0.0;
         self.lose_anim = 0.0;
-        self.again_button
-            .sprite
-            .set_position(-10000000.0, -1000000.0);
+        self.vignette_anim = 0.0;
+        self.again_button.set_position(-10000000.0, -1000000.0);
         self.level = 0;
-        self.life = 0;
-        self.life_time = 1.0;
+        self.finished_score = None;
+        self.life = match self.mode {
+            GameMode::TimeAttack => self.balance.time_attack_time,
+            _ => self.balance.start_life,
+        };
+        self.life_time = self.balance.drain_delay;
         self.life_dirty = true;
         self.score = 0;
         self.score_dirty = true;
         self.click_count = 0;
+        self.run_summary = rating::RunSummary::default();
+        self.summary_sprites.clear();
         self.game_start = Instant::now();
 
-        if self.life_text.get_x() > 1.0 {
-            self.resize(2.0, 1.0);
+        self.resize(self.screen[0], self.screen[1]);
+
+        match self.ruleset {
+            Ruleset::NetWalk | Ruleset::Power if self.mode == GameMode::Endless => {
+                self.new_level(self.balance.endless_size)
+            }
+            Ruleset::NetWalk | Ruleset::Power => self.new_level(self.balance.first_size),
+            Ruleset::Classic => self.new_classic_board(self.balance.classic_size),
+        }
+    }
+
+    fn new_classic_board(&mut self, size: u8) {
+        let (width, height) = self.board_dims(size);
+        self.width = width;
+        self.height = height;
+        self.level += 1;
+        self.level_score = 0;
+        self.level_start = Instant::now();
+        self.regions = vec![0u16; width as usize * height as usize];
+        self.classic = classic::Classic::new(
+            width,
+            height,
+            self.balance.classic_countdown,
+            self.balance.classic_fill_time,
+            &mut self.rng,
+        );
+        self.power_sprites.clear();
+        self.loop_tiles.clear();
+        self.loop_sprites.clear();
+
+        let size = self.tile_size();
+        self.highlight_sprite.set_size(size * 0.9, size * 0.9);
+        self.pipes = Vec::with_capacity(width as usize * height as usize);
+        for i in 0..width as usize * height as usize {
+            let (x, y) = self.tile_position(i);
+            let mut pipe = Pipe::new(x, y, size, self.texture, EMPTY, 0);
+            pipe.set_rgba(EMPTY_COLOR);
+            self.pipes.push(pipe);
+        }
+        let source = self.classic.source;
+        self.pipes[source].set_kind(0, self.classic.source_dir, size);
+        self.pipes[source].set_rgba(classic::SOURCE_COLOR);
+        self.queue_dirty = true;
+        self.life = self.classic.countdown.ceil() as u32;
+        self.life_dirty = true;
+    }
+
+    /// Place the next piece of the queue in the tile 'i'.
+    fn place_piece(&mut self, i: usize) {
+        let size = self.tile_size();
+        if self.classic.place(i, &mut self.pipes, size, &mut self.rng) {
+            self.click_count += 1;
+            self.queue_dirty = true;
+            self.play_tile_sound(Effect::Click, i);
         } else {
-            self.resize(1.0, 2.0);
+            self.play_tile_sound(Effect::Negate, i);
+        }
+    }
+
+    fn animate_classic(&mut self, dt: f32) {
+        let filled = self.classic.update(dt, &mut self.pipes, self.width);
+        if filled > 0 {
+            self.score += filled;
+            self.level_score += filled;
+            self.score_dirty = true;
+        }
+        let countdown = self.classic.countdown.ceil() as u32;
+        if countdown != self.life {
+            self.life = countdown;
+            self.life_dirty = true;
+        }
+        if self.classic.leaked {
+            self.trigger_lose();
         }
+    }
 
-        self.new_level(4, 4);
+    /// Show the next pieces beside the board, the next one being the bigger one.
+    fn layout_queue(&mut self) {
+        self.queue_sprites.clear();
+        if self.ruleset != Ruleset::Classic {
+            return;
+        }
+        let [ex, ey] = self.extents();
+        let landscape = self.landscape();
+        for (i, &(kind, dir)) in self.classic.queue.iter().enumerate() {
+            let size = if i == 0 { 0.2 } else { 0.14 };
+            let offset = if i == 0 { 0.0 } else { 0.05 + 0.17 * i as f32 };
+            let (x, y) = if landscape {
+                (ex + 0.23, 0.3 + offset)
+            } else {
+                (-0.45 + offset, -ey - 0.6)
+            };
+            let mut sprite =
+                SpriteInstance::new(x, y, size, size, self.texture, atlas::PIPES[kind as usize]);
+            sprite.set_angle(dir as f32 * PI / 2.0);
+            sprite.set_color(if i == 0 {
+                classic::DRY_COLOR
+            } else {
+                [120, 120, 120, 255]
+            });
+            self.queue_sprites.push(sprite);
+        }
     }
 
-    fn new_level(&mut self, width: u8, height: u8) {
+    /// Start a new level, with 'size' tiles in the shorter side of the board.
+    fn new_level(&mut self, size: u8) {
+        let (width, height) = self.board_dims(size);
         self.width = width;
         self.height = height;
         self.region_id_pool.clear();
         self.number_regions = 0;
-        self.color_pool.clear();
-        self.number_colors = 0;
         self.pipes = Vec::with_capacity(width as usize * height as usize);
-        self.life_time = 1.0;
-        let maze = Self::gen_maze(width, height, &mut self.rng);
-        let mut i = 0usize;
-        let size = 2.0 / self.height as f32;
+        self.life_time = self.balance.drain_delay;
+        // in the power mode, the dead ends are the terminals, and removing them would create loops
+        let maze = if self.ruleset == Ruleset::Power || self.loop_free {
+            maze::gen_maze(width, height, &self.maze_config.tree(), &mut self.rng)
+        } else {
+            maze::gen_maze(width, height, &self.maze_config, &mut self.rng)
+        };
+        let size = self.tile_size();
         self.highlight_sprite.set_size(size * 0.9, size * 0.9);
 
         self.level_start = Instant::now();
         self.click_count = 0;
+        self.undo_stack.clear();
 
         self.level += 1;
         self.level_score = 0;
 
+        let offsets =
+            scramble::scramble(&maze, width, &self.balance.scramble_config(), &mut self.rng);
         let mut total_diff = 0u32;
 
-        for y in 0..height {
-            for x in 0..width {
-                let (kind, dir) = match maze[i] {
-                    0b0001 => (0, 0),
-                    0b0010 => (0, 1),
-                    0b0100 => (0, 2),
-                    0b1000 => (0, 3),
-
-                    0b0011 => (1, 0),
-                    0b0110 => (1, 1),
-                    0b1100 => (1, 2),
-                    0b1001 => (1, 3),
-
-                    0b0101 => (2, 0),
-                    0b1010 => (2, 1),
-
-                    0b1110 => (3, 0),
-                    0b1101 => (3, 1),
-                    0b1011 => (3, 2),
-                    0b0111 => (3, 3),
-
-                    0b1111 => (4, 0),
-                    _ => (5, 0),
-                };
+        for i in 0..width as usize * height as usize {
+            let (kind, dir) = match maze[i] {
+                0b0001 => (0, 0),
+                0b0010 => (0, 1),
+                0b0100 => (0, 2),
+                0b1000 => (0, 3),
 
-                let diff = self.rng.gen_range(0, 4);
-                total_diff += if diff <= 2 { diff } else { 4 - diff };
+                0b0011 => (1, 0),
+                0b0110 => (1, 1),
+                0b1100 => (1, 2),
+                0b1001 => (1, 3),
 
-                self.pipes.push(Pipe::new(
-                    (x as f32 - width as f32 / 2.0) * size + size / 2.0,
-                    (y as f32 - height as f32 / 2.0) * size + size / 2.0,
-                    size,
-                    self.texture,
-                    kind,
-                    (dir + diff as u8) % 4,
-                ));
-                i += 1;
-            }
+                0b0101 => (2, 0),
+                0b1010 => (2, 1),
+
+                0b1110 => (3, 0),
+                0b1101 => (3, 1),
+                0b1011 => (3, 2),
+                0b0111 => (3, 3),
+
+                0b1111 => (4, 0),
+                _ => (5, 0),
+            };
+
+            let diff = offsets[i];
+            total_diff += scramble::min_clicks(maze[i], diff);
+
+            let (x, y) = self.tile_position(i);
+            self.pipes
+                .push(Pipe::new(x, y, size, self.texture, kind, (dir + diff) % 4));
+        }
+        self.solution = maze;
+        if self.ruleset == Ruleset::Power {
+            self.new_power_board();
+        } else {
+            self.power_sprites.clear();
+            self.trace_regions();
         }
-        self.trace_regions();
+        self.update_loops();
 
         self.expect_min_click_count = total_diff;
         let area = self.width as u32 * self.height as u32;
-        let expect_time = 30.0 + 0.307 * area as f32; // + 0.00120 * area as f32 * area as f32;
-        let expect_click = 30.0 + 0.542 * area as f32; // + 0.00154 * area as f32 * area as f32;
-        self.add_life((expect_time * 2.0 + expect_click) as i32);
+        self.par_time = self.balance.par_time(area);
+        match self.mode {
+            GameMode::Standard | GameMode::Endless => self.add_life(self.balance.level_life(area)),
+            GameMode::MoveLimit => {
+                self.life = total_diff + self.balance.move_margin;
+                self.life_dirty = true;
+            }
+            GameMode::TimeAttack | GameMode::Zen => {}
+        }
+    }
+
+    /// If the screen is wider than tall. The HUD goes to the right of the board in landscape,
+    /// and above it in portrait.
+    fn landscape(&self) -> bool {
+        self.screen[0] > self.screen[1]
+    }
+
+    /// The half width and half height of the area the board may take, in world units. The area
+    /// is stretched to fill the screen, in the direction the HUD goes.
+    fn extents(&self) -> [f32; 2] {
+        let [w, h] = self.screen;
+        let (long, short) = (w.max(h), w.min(h));
+        let ratio = ((long - 2.0 * BOARD_MARGIN - HUD_SIZE) / (short - 2.0 * BOARD_MARGIN))
+            .clamp(1.0, self.balance.max_board_ratio);
+        if self.landscape() {
+            [ratio, 1.0]
+        } else {
+            [1.0, ratio]
+        }
+    }
+
+    /// The width and height, in tiles, of a board with 'size' tiles in the shorter side, with the
+    /// same proportion of the area it takes.
+    fn board_dims(&self, size: u8) -> (u8, u8) {
+        let [ex, ey] = self.extents();
+        let stretch = |ratio: f32| (size as f32 * ratio).round().min(255.0) as u8;
+        if ex >= ey {
+            (stretch(ex / ey), size)
+        } else {
+            (size, stretch(ey / ex))
+        }
+    }
+
+    /// The size of a tile, so that the board fits in its area. A board made for another
+    /// orientation of the screen is shrunk to fit.
+    fn tile_size(&self) -> f32 {
+        let [ex, ey] = self.extents();
+        (2.0 * ex / self.width as f32).min(2.0 * ey / self.height as f32)
+    }
+
+    /// The center of the tile 'i'. The board is centered at the origin.
+    fn tile_position(&self, i: usize) -> (f32, f32) {
+        let size = self.tile_size();
+        let x = (i % self.width as usize) as f32;
+        let y = (i / self.width as usize) as f32;
+        (
+            (x - self.width as f32 / 2.0) * size + size / 2.0,
+            (y - self.height as f32 / 2.0) * size + size / 2.0,
+        )
+    }
+
+    /// The tile under the point ('x', 'y'), if any.
+    fn tile_at(&self, x: f32, y: f32) -> Option<usize> {
+        let size = self.tile_size();
+        let x = (x / size + self.width as f32 / 2.0).floor();
+        let y = (y / size + self.height as f32 / 2.0).floor();
+        if x >= 0.0 && x < self.width as f32 && y >= 0.0 && y < self.height as f32 {
+            Some(y as usize * self.width as usize + x as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Move the tiles, and everything over them, to fit the current screen.
+    fn layout_board(&mut self) {
+        let size = self.tile_size();
+        self.highlight_sprite.set_size(size * 0.9, size * 0.9);
+        for i in 0..self.pipes.len() {
+            let (x, y) = self.tile_position(i);
+            self.pipes[i].set_layout(x, y, size);
+        }
+        for (i, sprite) in self.power_sprites.iter_mut() {
+            let pipe = &self.pipes[*i].sprite;
+            sprite.set_position(pipe.get_x(), pipe.get_y());
+            sprite.set_size(pipe.get_width(), pipe.get_height());
+        }
+        self.update_loops();
+    }
+
+    /// The time between each point of life drained, or None if the mode has no timer.
+    fn drain_interval(&self) -> Option<f32> {
+        match self.mode {
+            GameMode::Standard => Some(self.balance.drain_interval),
+            GameMode::Endless => Some(self.balance.endless_drain(self.level)),
+            // in the time attack the life is the remaining time, in seconds
+            GameMode::TimeAttack => Some(1.0),
+            GameMode::MoveLimit | GameMode::Zen => None,
+        }
     }
 
     // preference == 0 mean no preference
@@ -385,37 +863,11 @@ impl<R: Rng> GameBoard<R> {
     // If a region is not more valid, it is add back to the pool,
     // and its region size is removed
     fn add_region_to_pool(&mut self, region: u16) {
-        if region != 0 && !self.region_id_pool.iter().any(|&x| x == region) {
+        if region != 0 && !self.region_id_pool.contains(&region) {
             self.region_id_pool.push(region);
         }
     }
 
-    fn next_color(&mut self) -> u16 {
-        if self.color_pool.is_empty() {
-            self.number_colors += 1;
-            self.number_colors
-        } else {
-            self.color_pool.pop().unwrap()
-        }
-    }
-
-    // If a color is not more used, it is add back to the pool
-    fn add_color_to_pool(&mut self, color: u16) {
-        if !self.color_pool.iter().any(|&x| x == color) {
-            self.color_pool.push(color);
-        }
-    }
-
-    // Remove a color from to pool to be used
-    fn remove_color_from_pool(&mut self, color: u16) {
-        match self.color_pool.iter().position(|&x| x == color) {
-            Some(i) => {
-                self.color_pool.swap_remove(i);
-            }
-            None => panic!("there is no way that I will remove a color wich is not in the pool"),
-        }
-    }
-
     fn trace_region(&mut self, start: i32, region: u16) {
         let neights: [i32; 4] = [1, self.width as i32, -1, -(self.width as i32)];
         let mut explore: Vec<i32> = vec![start];
@@ -440,7 +892,7 @@ impl<R: Rng> GameBoard<R> {
                     && !visited[next]
                 {
                     // if it is inexpored
-                    let next_dir = match self.pipes[next as usize].kind {
+                    let next_dir = match self.pipes[next].kind {
                         0 => 0b00010001u8,
                         1 => 0b00110011,
                         2 => 0b01010101,
@@ -448,7 +900,7 @@ impl<R: Rng> GameBoard<R> {
                         4 => 0b11111111,
                         _ => 0,
                     }
-                    .rotate_left(self.pipes[next as usize].dir as u32 + 2);
+                    .rotate_left(self.pipes[next].dir as u32 + 2);
                     if curr_dir & (1 << i) & next_dir != 0 {
                         explore.push(next as i32);
                         self.regions[next] = region;
@@ -469,13 +921,62 @@ impl<R: Rng> GameBoard<R> {
                 self.trace_region(i as i32, region);
             }
         }
-        for i in 0..(self.width as usize * self.height as usize) {
-            self.pipes[i].change_color(self.regions[i] - 1);
+        let all = (1..=self.number_regions).collect::<Vec<u16>>();
+        self.color_regions(&all);
+    }
+
+    /// Use the colors of 'theme', choosing again the colors of the regions on the board.
+    fn set_theme(&mut self, theme: Theme) {
+        self.theme = theme;
+        // the other rules color the pipes by the flow, not by the regions
+        if self.ruleset == Ruleset::NetWalk && !self.pipes.is_empty() {
+            let all = (1..=self.number_regions).collect::<Vec<u16>>();
+            self.color_regions(&all);
+        }
+    }
+
+    /// Choose again the colors of the regions in 'changed', keeping the others.
+    fn color_regions(&mut self, changed: &[u16]) {
+        let colors = self
+            .pipes
+            .iter()
+            .map(|x| x.target_color)
+            .collect::<Vec<u16>>();
+        let assigned = coloring::assign(
+            &self.regions,
+            self.width as usize,
+            &colors,
+            changed,
+            self.theme.colors(),
+        );
+        for (region, color) in assigned {
+            for i in 0..self.regions.len() {
+                if self.regions[i] == region {
+                    self.pipes[i].change_color(color, self.theme.colors());
+                }
+            }
+        }
+
+        let markers = self.pipes.iter().map(|x| x.marker).collect::<Vec<u16>>();
+        let assigned = coloring::assign_markers(
+            &self.regions,
+            self.width as usize,
+            &markers,
+            changed,
+            atlas::MARKERS.len(),
+        );
+        for (region, marker) in assigned {
+            for i in 0..self.regions.len() {
+                if self.regions[i] == region {
+                    self.pipes[i].marker = marker;
+                }
+            }
         }
-        self.number_colors = self.number_regions;
     }
 
-    fn update_regions(&mut self, i: i32) {
+    /// Trace again the regions around the tile 'i', after it has changed. Return the number of
+    /// tiles of the region of 'i', if it was made by merging other regions.
+    fn update_regions(&mut self, i: i32) -> Option<usize> {
         let neights: [i32; 4] = [1, self.width as i32, -1, -(self.width as i32)];
 
         let mut to_check: Vec<(usize, u16)> = Vec::with_capacity(5);
@@ -491,6 +992,9 @@ impl<R: Rng> GameBoard<R> {
             }
         }
 
+        // the regions of the neighbours before the move. The old region of 'i' is left out, so
+        // moving a tile from a region to another is not taken as a merge
+        let old_regions = to_check.clone();
         to_check.push((i as usize, self.regions[i as usize]));
         self.add_region_to_pool(self.regions[i as usize]);
         self.regions[i as usize] = 0;
@@ -501,147 +1005,216 @@ impl<R: Rng> GameBoard<R> {
                 self.trace_region(*curr as i32, *region);
             }
         }
-        let mut colors = Vec::new();
-        let mut start = 0;
-        let mut used_regions = Vec::new(); //TODO: check used in insertion time
-        for (_, region) in to_check.iter() {
-            if used_regions.iter().any(|x| *x == *region) {
-                continue;
-            }
-            used_regions.push(*region);
-            let mut has_some_region = false;
-            for i in 0..(self.width as usize * self.height as usize) {
-                if self.regions[i] == *region {
-                    has_some_region = true;
-                    let color = self.pipes[i].target_color;
-                    match colors[start..].iter().position(|(_, x, _)| *x == color) {
-                        Some(i) => {
-                            colors[start + i].2 += 1;
-                        }
-                        None => {
-                            colors.push((*region, color, 1));
-                            self.add_color_to_pool(color);
-                        }
-                    }
+        let merged = {
+            let region = self.regions[i as usize];
+            let mut joined = Vec::new();
+            for &(curr, old) in &old_regions {
+                if self.regions[curr] == region && !joined.contains(&old) {
+                    joined.push(old);
                 }
             }
-            if !has_some_region {
-                used_regions.pop();
+            if joined.len() > 1 {
+                Some(self.regions.iter().filter(|&&x| x == region).count())
+            } else {
+                None
             }
-            start = colors.len();
-        }
-        colors.sort_by_key(|x| x.2);
-        let mut used_colors = Vec::new();
-        while let Some((region, color, _)) = colors.pop() {
-            if used_colors.iter().any(|x| *x == color) {
+        };
+        let changed = to_check.iter().map(|x| x.1).collect::<Vec<u16>>();
+        self.color_regions(&changed);
+        merged
+    }
+
+    /// Place the power source in one of the most connected tiles, and put the terminals in
+    /// the dead ends.
+    fn new_power_board(&mut self) {
+        let len = self.width as usize * self.height as usize;
+        self.regions = vec![0u16; len];
+        let max = self
+            .solution
+            .iter()
+            .map(|x| x.count_ones())
+            .max()
+            .unwrap_or(0);
+        let candidates = (0..len)
+            .filter(|&i| self.solution[i].count_ones() == max)
+            .collect::<Vec<usize>>();
+        self.power_source = candidates[self.rng.gen_range(0, candidates.len())];
+
+        self.power_sprites.clear();
+        for (i, pipe) in self.pipes.iter().enumerate() {
+            let uv = if i == self.power_source {
+                atlas::SOURCE
+            } else if pipe.kind == 0 {
+                atlas::TERMINAL
+            } else {
                 continue;
-            }
-            used_colors.push(color);
-            match used_regions.iter().position(|x| *x == region) {
-                Some(i) => used_regions.swap_remove(i),
-                None => continue,
             };
-            self.remove_color_from_pool(color);
-            for i in 0..(self.width as usize * self.height as usize) {
-                if self.regions[i] == region {
-                    self.pipes[i].change_color(color);
-                }
+            let mut sprite = SpriteInstance::new(
+                pipe.sprite.get_x(),
+                pipe.sprite.get_y(),
+                pipe.sprite.get_width(),
+                pipe.sprite.get_height(),
+                self.texture,
+                uv,
+            );
+            sprite.set_color(UNPOWERED_COLOR);
+            self.power_sprites.push((i, sprite));
+        }
+        self.powered = vec![false; len];
+        self.update_power(None);
+    }
+
+    /// Return the index of the neighbor of the tile 'i' in the direction 'dir', if any.
+    fn neighbor(&self, i: usize, dir: u8) -> Option<usize> {
+        let (x, y) = (i % self.width as usize, i / self.width as usize);
+        match dir {
+            0 if x + 1 < self.width as usize => Some(i + 1),
+            1 if y + 1 < self.height as usize => Some(i + self.width as usize),
+            2 if x > 0 => Some(i - 1),
+            3 if y > 0 => Some(i - self.width as usize),
+            _ => None,
+        }
+    }
+
+    /// Check if the tile 'i' is connected to its neighbor in the direction 'dir'.
+    fn is_connected(&self, i: usize, dir: u8) -> Option<usize> {
+        let next = self.neighbor(i, dir)?;
+        let curr = &self.pipes[i];
+        let other = &self.pipes[next];
+        if pipe_mask(curr.kind, curr.dir) & (1 << dir) != 0
+            && pipe_mask(other.kind, other.dir) & (1 << ((dir + 2) % 4)) != 0
+        {
+            Some(next)
+        } else {
+            None
+        }
+    }
+
+    /// Find the tiles that are connected to the power source. 'changed' is the tile that was
+    /// rotated, or None to recompute everything.
+    fn update_power(&mut self, changed: Option<usize>) {
+        if let Some(i) = changed {
+            // if there is no power around the tile, nothing could have changed
+            if !self.powered[i]
+                && !(0..4).any(|d| matches!(self.neighbor(i, d), Some(n) if self.powered[n]))
+            {
+                return;
             }
         }
-        for region in used_regions {
-            let color = self.next_color();
-            for i in 0..(self.width as usize * self.height as usize) {
-                if self.regions[i] == region {
-                    self.pipes[i].change_color(color);
+        let mut powered = vec![false; self.pipes.len()];
+        let mut explore = vec![self.power_source];
+        powered[self.power_source] = true;
+        while let Some(curr) = explore.pop() {
+            for dir in 0..4 {
+                if let Some(next) = self.is_connected(curr, dir) {
+                    if !powered[next] {
+                        powered[next] = true;
+                        explore.push(next);
+                    }
                 }
             }
         }
+        for (i, pipe) in self.pipes.iter_mut().enumerate() {
+            pipe.fade_to(if powered[i] {
+                POWERED_COLOR
+            } else {
+                UNPOWERED_COLOR
+            });
+        }
+        for (i, sprite) in self.power_sprites.iter_mut() {
+            sprite.set_color(if powered[*i] {
+                POWERED_COLOR
+            } else {
+                UNPOWERED_COLOR
+            });
+        }
+        self.powered = powered;
     }
 
-    fn gen_maze(width: u8, height: u8, rng: &mut R) -> Box<[i8]> {
-        let neights: [i32; 4] = [1, width as i32, -1, -(width as i32)];
-
-        let mut grid = vec![0i8; width as usize * height as usize].into_boxed_slice();
-
-        let start = rng.gen_range(0, grid.len());
-        let mut path: Vec<i32> = vec![start as i32];
-        grid[start] = 0;
-
-        'path: while !path.is_empty() {
-            let r = rng.gen_range(0, path.len());
-            let curr = path[r];
-            for i in sample(rng, 4, 4).iter() {
-                let next = (curr + neights[i]) as usize;
-                if (curr % width as i32 - next as i32 % width as i32).abs() <= 1
-                    && next < grid.len()
-                    && grid[next] == 0
-                {
-                    // if it is inexpored
-                    grid[curr as usize] |= 1 << i; // set dir bitmask
-                    grid[next] |= 1 << ((i + 2) % 4); // set dir bitmask
-                    path.push(next as i32); // add to the backtrack path
-                    continue 'path;
-                }
+    /// Find the tiles that are part of a closed loop, or in a path between two loops.
+    fn find_loops(&self) -> Vec<usize> {
+        let len = self.pipes.len();
+        // remove the tiles with one or no connection, until only the loops remain
+        let mut degree = (0..len)
+            .map(|i| {
+                (0..4)
+                    .filter(|&d| self.is_connected(i, d).is_some())
+                    .count()
+            })
+            .collect::<Vec<usize>>();
+        let mut removed = vec![false; len];
+        let mut to_remove = (0..len).filter(|&i| degree[i] <= 1).collect::<Vec<usize>>();
+        while let Some(curr) = to_remove.pop() {
+            if removed[curr] {
+                continue;
             }
-            // If there is no neighbor inexpored, backtrack
-            path.swap_remove(r);
-        }
-        // Remove all the dead ends
-        for curr in 0..grid.len() as i32 {
-            if let 0b0001 | 0b0010 | 0b0100 | 0b1000 = grid[curr as usize] {
-                for i in sample(rng, 4, 4).iter() {
-                    let next = (curr + neights[i]) as usize;
-                    if (curr % width as i32 - next as i32 % width as i32).abs() <= 1
-                        && next < grid.len()
-                        && grid[next] & (1 << ((i + 2) % 4)) == 0
-                    {
-                        grid[curr as usize] |= 1 << i; // set dir bitmask
-                        grid[next] |= 1 << ((i + 2) % 4); // set dir bitmask
-                        break;
+            removed[curr] = true;
+            for dir in 0..4 {
+                if let Some(next) = self.is_connected(curr, dir) {
+                    if !removed[next] {
+                        degree[next] -= 1;
+                        if degree[next] <= 1 {
+                            to_remove.push(next);
+                        }
                     }
                 }
             }
         }
+        (0..len).filter(|&i| !removed[i]).collect()
+    }
 
-        let mut i = 0;
-        while i < width as usize * height as usize {
-            match grid[i] {
-                0b0001 => print!("╒"),
-                0b0010 => print!("╓"),
-                0b0100 => print!("╕"),
-                0b1000 => print!("╙"),
-
-                0b0011 => print!("╔"),
-                0b0110 => print!("╗"),
-                0b1100 => print!("╝"),
-                0b1001 => print!("╚"),
-
-                0b0101 => print!("═"),
-                0b1010 => print!("║"),
-
-                0b1110 => print!("╣"),
-                0b1101 => print!("╩"),
-                0b1011 => print!("╠"),
-                0b0111 => print!("╦"),
-
-                0b1111 => print!("╬"),
-                _ => print!(" "),
-            };
-            i += 1;
-            if i % width as usize == 0 {
-                println!();
-            }
+    /// When loops are not allowed, find and mark the tiles in loops.
+    /// Return true if there is any loop.
+    fn update_loops(&mut self) -> bool {
+        self.loop_sprites.clear();
+        if !self.loop_free || self.ruleset == Ruleset::Classic {
+            self.loop_tiles.clear();
+            return false;
+        }
+        self.loop_tiles = self.find_loops();
+        let size = self.tile_size();
+        for &i in self.loop_tiles.iter() {
+            let pipe = &self.pipes[i].sprite;
+            let mut sprite = SpriteInstance::new(
+                pipe.get_x(),
+                pipe.get_y(),
+                size,
+                size,
+                self.texture,
+                atlas::BLANCK,
+            );
+            sprite.set_color([255, 0, 0, 0]);
+            self.loop_sprites.push(sprite);
         }
-        grid
+        !self.loop_tiles.is_empty()
     }
 
-    fn check_is_done(&self) -> bool {
-        let neights: [i32; 2] = [1, self.width as i32];
+    /// In the power mode, it is done when all terminals are powered, and there is no pipe
+    /// connected to nowhere.
+    fn check_power_is_done(&self) -> bool {
+        self.pipes
+            .iter()
+            .enumerate()
+            .all(|(i, pipe)| pipe.kind != 0 || self.powered[i])
+            && !self.has_open_ends()
+            && self.loop_tiles.is_empty()
+    }
 
+    fn check_is_done(&self) -> bool {
         // if there is more than one region, it is not done
         if self.number_regions as usize - self.region_id_pool.len() > 1 {
             return false;
         }
+        // if loops are not allowed, and there is one, it is not done
+        if !self.loop_tiles.is_empty() {
+            return false;
+        }
+        !self.has_open_ends()
+    }
+
+    /// Check if there is some pipe with a unparied connection.
+    fn has_open_ends(&self) -> bool {
+        let neights: [i32; 2] = [1, self.width as i32];
 
         // upper row
         for curr in 0..self.width as i32 {
@@ -656,8 +1229,8 @@ impl<R: Rng> GameBoard<R> {
             .rotate_left(self.pipes[curr as usize].dir as u32);
 
             if curr_dir & (1 << 3) != 0 {
-                // if curr is connect to nowhere, it is not done
-                return false;
+                // curr is connect to nowhere
+                return true;
             }
         }
 
@@ -674,8 +1247,8 @@ impl<R: Rng> GameBoard<R> {
             .rotate_left(self.pipes[curr as usize].dir as u32);
 
             if curr_dir & (1 << 2) != 0 {
-                // if curr is connect to nowhere, it is not done
-                return false;
+                // curr is connect to nowhere
+                return true;
             }
         }
 
@@ -695,7 +1268,7 @@ impl<R: Rng> GameBoard<R> {
                 if (curr % self.width as i32 - next as i32 % self.width as i32).abs() <= 1
                     && next < self.regions.len()
                 {
-                    let next_dir = match self.pipes[next as usize].kind {
+                    let next_dir = match self.pipes[next].kind {
                         0 => 0b00010001u8,
                         1 => 0b00110011,
                         2 => 0b01010101,
@@ -703,20 +1276,19 @@ impl<R: Rng> GameBoard<R> {
                         4 => 0b11111111,
                         _ => 0,
                     }
-                    .rotate_left(self.pipes[next as usize].dir as u32 + 2);
+                    .rotate_left(self.pipes[next].dir as u32 + 2);
 
-                    // If curr and next have a unparied connection, it is not done
+                    // curr and next have a unparied connection
                     if (curr_dir & (1 << i) != 0) != (next_dir & (1 << i) != 0) {
-                        return false;
+                        return true;
                     }
                 } else if curr_dir & (1 << i) != 0 {
-                    // if curr is connect to nowhere, it is not done
-                    return false;
+                    // curr is connect to nowhere
+                    return true;
                 }
             }
         }
-        // if there is no pipe with unparied connection, it is done
-        true
+        false
     }
 
     fn count_connections(&self) -> u32 {
@@ -739,7 +1311,7 @@ impl<R: Rng> GameBoard<R> {
                 if (curr % self.width as i32 - next as i32 % self.width as i32).abs() <= 1
                     && next < self.regions.len()
                 {
-                    let next_dir = match self.pipes[next as usize].kind {
+                    let next_dir = match self.pipes[next].kind {
                         0 => 0b00010001u8,
                         1 => 0b00110011,
                         2 => 0b01010101,
@@ -747,7 +1319,7 @@ impl<R: Rng> GameBoard<R> {
                         4 => 0b11111111,
                         _ => 0,
                     }
-                    .rotate_left(self.pipes[next as usize].dir as u32 + 2);
+                    .rotate_left(self.pipes[next].dir as u32 + 2);
 
                     // If curr and next have a paried connection, count it
                     if (curr_dir & (1 << i) != 0) && (next_dir & (1 << i) != 0) {
@@ -760,187 +1332,591 @@ impl<R: Rng> GameBoard<R> {
     }
 
     fn add_life(&mut self, value: i32) {
+        if !self.mode.has_life() {
+            return;
+        }
         self.life_dirty = true;
         self.life = (self.life as i32 + value).max(0) as u32;
         if self.life == 0 && self.win_anim == 0.0 {
             self.trigger_lose();
+        } else if value < 0 && self.low_life() {
+            self.play_sound(Effect::Tick);
         }
     }
 
-    fn trigger_lose(&mut self) {
-        self.lose_anim = 1.0;
-        self.win_sprite.set_uv_rect(atlas::YOU_LOSE);
-        self.win_sprite
-            .set_size(atlas::YOU_LOSE[2] / atlas::YOU_LOSE[3], 1.0);
-        self.win_sprite.set_color([255, 0, 0, 255]);
-        self.win_sprite.set_angle(0.0);
-        self.slow_down_effect.store(true, Ordering::Relaxed);
+    /// The life as a fraction of 'threshold', or of 'move_threshold' in the move limit mode,
+    /// where the life is the clicks left. None if the life can not run out.
+    fn life_ratio(&self, threshold: u32, move_threshold: u32) -> Option<f32> {
+        let threshold = match self.mode {
+            GameMode::MoveLimit => move_threshold,
+            _ => threshold,
+        };
+        // in the classic rules the life is only the time before the water flows
+        if !self.mode.has_life() || self.ruleset == Ruleset::Classic || threshold == 0 {
+            return None;
+        }
+        Some(self.life as f32 / threshold as f32)
     }
 
-    fn trigger_win(&mut self) {
-        self.win_anim = 1.0;
-        self.win_sprite.set_uv_rect(atlas::YOU_WIN);
-        self.win_sprite
-            .set_size(atlas::YOU_WIN[2] / atlas::YOU_WIN[3], 1.0);
-        self.win_sprite.set_color([255, 255, 255, 255]);
-        if self.sound_effects {
-            crate::audio_engine()
-                .new_sound(WavDecoder::new(Cursor::new(sounds::WHOOSH)).unwrap())
-                .unwrap()
-                .play();
-        }
+    /// If the life is under the warning threshold, while a level is being played.
+    fn low_life(&self) -> bool {
+        let ratio = self.life_ratio(self.balance.warning_life, self.balance.move_warning);
+        self.win_anim == 0.0
+            && self.lose_anim == 0.0
+            && self.life > 0
+            && matches!(ratio, Some(x) if x <= 1.0)
     }
 
-    /// Receive the in world space coordinate of the mouse position.
-    /// 'pressed' is 0 if none, 1 if is left button, 2 if is rigth button
-    pub fn mouse_input(&mut self, x: f32, y: f32, pressed: u8) {
-        if self.win_anim > 0.0 {
-            self.highlight_sprite.pos[0] = -100.0;
-            return;
+    /// Flash the border of the screen, if a click has cost life while it is low.
+    fn flash_warning(&mut self) {
+        if self.low_life() {
+            self.vignette_anim = 1.0;
         }
-        if self.lose_anim > 0.0 {
-            self.again_button.mouse_input(x, y);
+    }
 
-            self.highlight_sprite.pos[0] = -100.0;
-            if pressed == 1 && self.again_button.is_over {
-                self.reset();
-            }
-            return;
-        }
+    fn trigger_lose(&mut self) {
+        self.finished_score = Some(self.score);
+        self.lose_anim = 1.0;
+        self.update_summary_text();
+        self.show_banner("you_lose", [255, 0, 0, 255]);
+        self.banner.set_angle(0.0);
+    }
 
-        let x = ((x + self.width as f32 / self.height as f32) / 2.0 * self.height as f32).floor()
-            as i32;
-        let y = ((y + 1.0) / 2.0 * self.height as f32).floor() as i32;
-        if x >= 0 && x < self.width as i32 && y >= 0 && y < self.height as i32 {
-            if pressed == 0 {
-                self.highlight_sprite.pos = [
-                    2.0 * (x as f32 + 0.5) / self.width as f32 - 1.0,
-                    2.0 * (y as f32 + 0.5) / self.height as f32 - 1.0,
-                ];
-            } else {
-                let i = y * self.width as i32 + x;
-                self.click_count += 1;
-                self.pipes[i as usize].click(pressed != 1, self.sound_effects);
-                self.update_regions(i);
-                let new_max = self.count_connections();
-                if new_max > self.level_score {
-                    self.score += new_max - self.level_score;
-                    self.level_score = new_max;
-                    self.score_dirty = true;
-                }
-                if self.check_is_done() {
-                    self.trigger_win();
-                }
-                self.add_life(-1);
-            }
-        } else {
-            self.highlight_sprite.pos[0] = -100.0;
-        }
+    /// Show the message 'key' in the middle of the screen, shrunk to fit in the board if needed.
+    fn show_banner(&mut self, key: &str, color: [u8; 4]) {
+        let text = self.locale.get(key);
+        let size = (2.0 / text::measure(text, 1.0)[0]).min(0.45);
+        self.banner.set_text(text);
+        self.banner.set_size(size);
+        self.banner.set_color(color);
     }
 
-    pub fn animate(&mut self, dt: f32) {
+    /// Update the text of the HUD to the language of the locale.
+    fn update_texts(&mut self) {
+        self.life_text.set_text(self.locale.get("time"));
+        self.score_text.set_text(self.locale.get("score"));
+        self.again_button.set_text(self.locale.get("play_again"));
+        self.update_summary_text();
+        self.life_dirty = true;
+        self.score_dirty = true;
+    }
+
+    /// The speed the music should play at: faster as the life runs low, and stopped after a
+    /// loss.
+    fn target_music_speed(&self) -> f32 {
+        if self.lose_anim > 0.0 {
+            return 0.0;
+        }
+        match self.life_ratio(self.balance.music_rush_life, self.balance.move_rush) {
+            Some(ratio) => lerp(1.0 - ratio.min(1.0), 1.0, self.balance.music_rush_speed),
+            None => 1.0,
+        }
+    }
+
+    /// Play one of the sound effects, through the effects bus of the mixer.
+    fn play_sound(&mut self, effect: Effect) {
+        self.sound_bank.play(effect);
+    }
+
+    /// Where the tile 'i' is heard, from -1 at the left to 1 at the right.
+    fn tile_pan(&self, i: usize) -> f32 {
+        // not all the way to the side, that would be tiring to hear
+        (self.tile_position(i).0 / self.extents()[0]).clamp(-1.0, 1.0) * 0.6
+    }
+
+    /// Play a sound effect from the place of the tile 'i', a bit higher for the pipes with less
+    /// connections.
+    fn play_tile_sound(&mut self, effect: Effect, i: usize) {
+        let kind = self.pipes[i].kind.min(4);
+        let pitch = 1.0 + (2.0 - kind as f32) * 0.05;
+        let pan = self.tile_pan(i);
+        self.sound_bank.play_with(effect, pan, pitch);
+    }
+
+    /// A rising tone, higher for larger regions, for the region of the tile 'i' that has just
+    /// grown to 'size' tiles.
+    fn play_merge_tone(&mut self, i: usize, size: usize) {
+        let pitch = 2f32.powf(size.min(24) as f32 / 12.0);
+        let pan = self.tile_pan(i);
+        self.sound_bank.play_with(Effect::Merge, pan, pitch);
+    }
+
+    fn trigger_win(&mut self) {
+        self.rate_level();
+        self.win_anim = 1.0;
+        self.show_banner("you_win", [255, 255, 255, 255]);
+        if self.reduced_motion {
+            // stay still in the middle, instead of spinning across the screen
+            self.banner.set_position(0.0, 0.0);
+            self.banner.set_angle(0.0);
+        }
+        self.play_sound(Effect::Whoosh);
+    }
+
+    /// Rate the solved level against its par, and add the bonus of the stars to the score.
+    fn rate_level(&mut self) {
+        let area = self.width as u32 * self.height as u32;
+        self.rating = rating::LevelRating::new(
+            self.click_count,
+            self.expect_min_click_count,
+            self.level_start.elapsed().as_secs_f32(),
+            self.par_time,
+            area,
+        );
+        self.run_summary
+            .add(&self.rating, self.click_count, self.expect_min_click_count);
+        self.score += self.rating.bonus;
+        self.score_dirty = true;
+
+        self.star_sprites.clear();
+        let rows = [
+            (self.rating.click_stars, 0.45, [255, 220, 0, 255]),
+            (self.rating.time_stars, 0.65, [0, 220, 255, 255]),
+        ];
+        for &(stars, y, color) in rows.iter() {
+            for k in 0..rating::MAX_STARS {
+                let (uv, color) = if k < stars {
+                    (atlas::STAR, color)
+                } else {
+                    (atlas::STAR_EMPTY, [255, 255, 255, 160])
+                };
+                let x = (k as f32 - 1.0) * 0.25;
+                self.star_sprites
+                    .push(SpriteInstance::new(x, y, 0.0, 0.0, self.texture, uv).with_color(color));
+            }
+        }
+        self.bonus_number = utils::number_to_sprites(
+            self.rating.bonus,
+            0.0,
+            0.85,
+            0.15,
+            [255, 220, 0, 255],
+            true,
+            self.texture,
+        );
+    }
+
+    /// Write the totals of the run, in the language of the locale.
+    fn update_summary_text(&mut self) {
+        let summary = &self.run_summary;
+        let text = format!(
+            "{} {}   {} {}\n{} {} / {}",
+            self.locale.get("levels"),
+            summary.levels,
+            self.locale.get("bonus"),
+            summary.bonus,
+            self.locale.get("clicks"),
+            summary.clicks,
+            summary.par_clicks,
+        );
+        self.summary_text.set_text(&text);
+    }
+
+    /// Show the totals of the run under the score: the stars collected, the levels solved with
+    /// their bonus, and the clicks made against the sum of the pars.
+    fn layout_summary(&mut self) {
+        self.summary_sprites.clear();
+        if self.ruleset == Ruleset::Classic {
+            return;
+        }
+        let x = self.score_text.get_x() - self.score_text.get_width() / 2.0;
+        let height = self.score_text.digit_height();
+        let y = self.score_text.get_y() + height * 1.5;
+        let size = height * 1.2;
+        self.summary_sprites.push(
+            SpriteInstance::new(x + size / 2.0, y, size, size, self.texture, atlas::STAR)
+                .with_color([255, 220, 0, 255]),
+        );
+        self.summary_sprites.extend(utils::number_to_sprites(
+            self.run_summary.stars,
+            x + size,
+            y,
+            height,
+            [255, 0, 0, 255],
+            false,
+            self.texture,
+        ));
+        self.summary_text.set_size(self.score_text.get_size() * 0.7);
+        let height = self.summary_text.get_height();
+        self.summary_text.set_position(
+            self.score_text.get_x(),
+            y + size / 2.0 + 0.02 + height / 2.0,
+        );
+    }
+
+    /// Receive the in world space coordinate of the mouse position.
+    /// 'click' is true if the left mouse button was released.
+    pub fn mouse_input(&mut self, x: f32, y: f32, click: bool) {
+        self.hovered = None;
+        if self.win_anim > 0.0 {
+            self.highlight_sprite.pos[0] = -100.0;
+            return;
+        }
+        if self.lose_anim > 0.0 {
+            self.again_button.mouse_input(x, y);
+
+            self.highlight_sprite.pos[0] = -100.0;
+            if click && self.again_button.is_over {
+                self.reset();
+            }
+            return;
+        }
+
+        self.hovered = self.tile_at(x, y);
+        match self.hovered {
+            Some(i) => {
+                let (x, y) = self.tile_position(i);
+                self.highlight_sprite.pos = [x, y];
+            }
+            None => self.highlight_sprite.pos[0] = -100.0,
+        }
+    }
+
+    /// Apply an action of the player. Rotations and locks are applied to the tile under the
+    /// mouse, if any.
+    pub fn action(&mut self, action: Action) {
+        if self.win_anim > 0.0 || self.lose_anim > 0.0 {
+            return;
+        }
+        if self.ruleset == Ruleset::Classic {
+            if let (Action::RotateClockwise, Some(i)) | (Action::RotateCounterClockwise, Some(i)) =
+                (action, self.hovered)
+            {
+                self.place_piece(i);
+            }
+            return;
+        }
+        match action {
+            Action::RotateClockwise | Action::RotateCounterClockwise => {
+                if let Some(i) = self.hovered {
+                    if !self.pipes[i].locked {
+                        let clockwise = action == Action::RotateClockwise;
+                        self.undo_stack.push((i, clockwise));
+                        self.rotate(i, clockwise);
+                    }
+                }
+            }
+            Action::Lock => {
+                if let Some(i) = self.hovered {
+                    let locked = self.pipes[i].locked;
+                    self.pipes[i].set_locked(!locked);
+                }
+            }
+            Action::Undo => {
+                // the moves of tiles locked since then are kept, to be undone once unlocked, and
+                // the moves of different tiles can be undone in any order
+                let last = self
+                    .undo_stack
+                    .iter()
+                    .rposition(|&(i, _)| !self.pipes[i].locked);
+                match last {
+                    Some(k) => {
+                        // taking a move back is free, unlike making it
+                        let (i, clockwise) = self.undo_stack.remove(k);
+                        self.pipes[i].click(!clockwise);
+                        self.play_tile_sound(Effect::Click, i);
+                        self.after_move(i);
+                    }
+                    None => self.play_sound(Effect::Negate),
+                }
+            }
+            Action::Hint => self.hint(),
+            Action::None => {}
+        }
+    }
+
+    fn rotate(&mut self, i: usize, clockwise: bool) {
+        self.click_count += 1;
+        self.pipes[i].click(clockwise);
+        self.play_tile_sound(Effect::Click, i);
+        self.after_move(i);
+        if self.mode.clicks_cost_life() {
+            self.add_life(-1);
+            self.flash_warning();
+        }
+    }
+
+    /// Turn a random misplaced tile to the orientation of the solution, and lock it.
+    fn hint(&mut self) {
+        let wrong = (0..self.pipes.len())
+            .filter(|&i| {
+                let pipe = &self.pipes[i];
+                !pipe.locked && pipe_mask(pipe.kind, pipe.dir) != self.solution[i] as u8
+            })
+            .collect::<Vec<usize>>();
+        if wrong.is_empty() {
+            return;
+        }
+        let i = wrong[self.rng.gen_range(0, wrong.len())];
+        let target = self.solution[i] as u8;
+        let pipe = &mut self.pipes[i];
+        // try the orientations in order of number of clicks
+        let dir = [0, 1, 3, 2]
+            .iter()
+            .map(|d| (pipe.dir + d) % 4)
+            .find(|&d| pipe_mask(pipe.kind, d) == target)
+            .unwrap_or(pipe.dir);
+        pipe.turn_to(dir);
+        pipe.set_locked(true);
+        self.play_tile_sound(Effect::Click, i);
+        self.after_move(i);
+        let cost = match self.mode {
+            GameMode::Zen => 0,
+            GameMode::MoveLimit => self.balance.move_hint_cost,
+            _ => self.balance.hint_cost,
+        };
+        self.add_life(-(cost as i32));
+        if cost > 0 {
+            self.flash_warning();
+        }
+    }
+
+    /// Update the regions and the score after the pipe 'i' has changed.
+    fn after_move(&mut self, i: usize) {
+        if self.ruleset == Ruleset::Power {
+            self.update_power(Some(i));
+        } else if let Some(size) = self.update_regions(i as i32) {
+            self.play_merge_tone(i, size);
+        }
+        let had_loop = self.loop_tiles.contains(&i);
+        if self.update_loops() && !had_loop && self.loop_tiles.contains(&i) {
+            // warn the player that a loop was closed
+            self.play_sound(Effect::Negate);
+        }
+        let new_max = self.count_connections();
+        if new_max > self.level_score {
+            self.score += new_max - self.level_score;
+            self.level_score = new_max;
+            self.score_dirty = true;
+        }
+        let done = if self.ruleset == Ruleset::Power {
+            self.check_power_is_done()
+        } else {
+            self.check_is_done()
+        };
+        if done {
+            self.trigger_win();
+        }
+    }
+
+    pub fn animate(&mut self, dt: f32) {
+        self.balance_watcher.poll(dt, &mut self.balance);
+        self.music.set_playing_speed(self.target_music_speed());
+
+        let warning = self.low_life();
+        if warning != self.warning {
+            // the digits go back to their color when it stops
+            self.warning = warning;
+            self.warning_anim = 0.0;
+            self.life_dirty = true;
+        }
+
         if self.life_dirty {
             let w = self.life_text.get_width().max(self.score_text.get_width()) + 0.03;
-            self.life_number = utils::number_to_sprites(
-                self.life,
-                self.life_text.pos[0] - self.life_text.get_width() / 2.0 + w,
-                self.life_text.pos[1],
-                self.life_text.get_height(),
-                [255, 0, 0, 255],
-                false,
-                self.texture,
-            );
+            let x = self.life_text.get_x() - self.life_text.get_width() / 2.0 + w;
+            let height = self.life_text.digit_height();
+            self.life_number = if self.mode == GameMode::TimeAttack {
+                // the remaining time of the run, in minutes and seconds
+                let time = format!("{}:{:02}", self.life / 60, self.life % 60);
+                text::text_to_sprites(
+                    &time,
+                    x,
+                    self.life_text.get_y(),
+                    height * 1.5,
+                    [255, 0, 0, 255],
+                    text::Align::Left,
+                    self.texture,
+                )
+            } else {
+                utils::number_to_sprites(
+                    self.life,
+                    x,
+                    self.life_text.get_y(),
+                    height,
+                    [255, 0, 0, 255],
+                    false,
+                    self.texture,
+                )
+            };
         }
 
         if self.score_dirty {
             let w = self.life_text.get_width().max(self.score_text.get_width()) + 0.03;
             self.score_number = utils::number_to_sprites(
                 self.score,
-                self.score_text.pos[0] - self.score_text.get_width() / 2.0 + w,
-                self.score_text.pos[1],
-                self.score_text.get_height(),
+                self.score_text.get_x() - self.score_text.get_width() / 2.0 + w,
+                self.score_text.get_y(),
+                self.score_text.digit_height(),
                 [255, 0, 0, 255],
                 false,
                 self.texture,
             );
         }
 
+        if self.warning {
+            self.warning_anim = (self.warning_anim + dt * 1.5) % 1.0;
+            let t = if self.reduced_motion {
+                1.0
+            } else {
+                0.5 - 0.5 * (self.warning_anim * 2.0 * PI).cos()
+            };
+            let shade = lerp(t, 255.0, 90.0) as u8;
+            for sprite in self.life_number.iter_mut() {
+                sprite.set_color([255, shade, shade, 255]);
+            }
+        }
+        if self.vignette_anim > 0.0 {
+            self.vignette_anim = (self.vignette_anim - dt * 2.5).max(0.0);
+            let alpha = 160.0 * self.vignette_anim * self.vignette_anim;
+            self.vignette.set_color([255, 0, 0, alpha as u8]);
+        }
+
+        if self.queue_dirty {
+            self.queue_dirty = false;
+            self.layout_queue();
+        }
+
         for pipe in self.pipes.iter_mut() {
-            pipe.animate(dt);
+            pipe.animate(dt, self.reduced_motion);
+        }
+        if !self.loop_sprites.is_empty() {
+            self.loop_anim = (self.loop_anim + dt) % 1.0;
+            let alpha = if self.reduced_motion {
+                90.0
+            } else {
+                60.0 + 60.0 * (self.loop_anim * 2.0 * PI).sin()
+            };
+            for sprite in self.loop_sprites.iter_mut() {
+                sprite.set_color([255, 0, 0, alpha as u8]);
+            }
         }
         if self.win_anim == 0.0 && self.lose_anim == 0.0 {
-            self.life_time -= dt;
-            if self.life_time < 0.0 {
-                self.add_life(-1);
-                self.life_time += 0.5;
+            match self.ruleset {
+                Ruleset::NetWalk | Ruleset::Power => {
+                    if let Some(interval) = self.drain_interval() {
+                        self.life_time -= dt;
+                        if self.life_time < 0.0 {
+                            self.add_life(-1);
+                            self.life_time += interval;
+                        }
+                    }
+                }
+                Ruleset::Classic => self.animate_classic(dt),
             }
         } else if self.win_anim > 0.0 {
             self.win_anim = (self.win_anim - dt * 0.5).max(0.0);
 
-            let x = ((self.win_anim - 0.5) * PI).tan() * 0.5;
-            let angle = lerp(x, 0.0, PI / 4.0);
+            if !self.reduced_motion {
+                let x = ((self.win_anim - 0.5) * PI).tan() * 0.5;
+                let angle = lerp(x, 0.0, PI / 4.0);
+
+                self.banner.set_angle(angle);
+                self.banner.set_position(x, 0.0);
+            }
 
-            self.win_sprite.set_angle(angle);
-            self.win_sprite.set_position(x, 0.0);
+            // the stars pop in one after the other
+            for (k, sprite) in self.star_sprites.iter_mut().enumerate() {
+                let t = if self.reduced_motion {
+                    1.0
+                } else {
+                    ((1.0 - self.win_anim) * 6.0 - k as f32 * 0.4).clamp(0.0, 1.0)
+                };
+                let size = 0.22 * (1.0 + 0.3 * (t * PI).sin()) * t;
+                sprite.set_size(size, size);
+            }
 
             if self.win_anim == 0.0 {
                 #[cfg(not(target_arch = "wasm32"))]
                 {
                     use std::io::Write;
                     let mut file = std::fs::OpenOptions::new()
-                        .write(true)
                         .append(true)
                         .create(true)
                         .open("log.txt")
                         .unwrap();
                     writeln!(
                         file,
-                        "{},{},{},{},{}",
+                        "{},{},{},{},{},{},{}",
                         self.width,
                         self.height,
                         self.expect_min_click_count,
                         self.click_count,
-                        self.level_start.elapsed().as_secs_f32()
+                        self.level_start.elapsed().as_secs_f32(),
+                        self.rating.click_stars,
+                        self.rating.time_stars,
                     )
                     .unwrap();
                 }
-                self.new_level(self.width + 1, self.height + 1);
+                let growth = match self.mode {
+                    GameMode::Endless => 0,
+                    _ => self.balance.board_growth,
+                };
+                let size = self.width.min(self.height).saturating_add(growth);
+                self.new_level(size.min(balance::MAX_SIZE));
             }
         } else if self.lose_anim > 0.0 {
             self.again_button.update(dt);
             self.lose_anim = (self.lose_anim - dt * 0.5).max(f32::MIN_POSITIVE);
 
             let y = -0.2 * (self.lose_anim * self.lose_anim) / (self.lose_anim - 1.0);
-            self.win_sprite.set_position(0.0, -y - 0.4);
+            self.banner.set_position(0.0, -y - 0.4);
 
             if self.lose_anim < 0.5 {
                 let t = self.lose_anim * 2.0;
                 let y = -0.4 * (t * t * 4.0) / (t - 1.0);
-                self.score_text.set_position(0.0, y + 0.5);
-                self.again_button.sprite.set_position(0.0, y * 3.0 + 0.7);
+                self.score_text.set_position(0.0, y + 0.2);
+                self.again_button.set_position(0.0, y * 3.0 + 0.8);
             } else {
                 let t = self.lose_anim - 1.0;
                 let d = t * t / (t + 0.5);
-                if self.score_text.get_x() > 1.0 {
+                let [ex, ey] = self.extents();
+                if self.landscape() {
                     self.score_text
-                        .set_position(1.13 + self.life_text.get_width() / 2.0 + d, -0.5);
+                        .set_position(ex + 0.13 + self.life_text.get_width() / 2.0 + d, -0.5);
                 } else {
-                    self.score_text.set_position(0.0, -1.3 - d);
+                    self.score_text.set_position(0.0, -ey - 0.3 - d);
                 }
             }
+            self.layout_summary();
         }
     }
 
     pub fn resize(&mut self, width: f32, height: f32) {
-        if width > height {
+        self.screen = [width, height];
+        self.vignette.set_size(width, height);
+        let [ex, ey] = self.extents();
+        if self.landscape() {
             self.life_text
-                .set_position(1.13 + self.life_text.get_width() / 2.0, -0.3);
+                .set_position(ex + 0.13 + self.life_text.get_width() / 2.0, -0.3);
             self.score_text
-                .set_position(1.13 + self.score_text.get_width() / 2.0, -0.0);
+                .set_position(ex + 0.13 + self.score_text.get_width() / 2.0, -0.0);
         } else {
-            self.life_text.set_position(-0.9, -1.3);
-            self.score_text.set_position(0.0, -1.3);
+            self.life_text.set_position(-ex + 0.1, -ey - 0.3);
+            self.score_text.set_position(0.0, -ey - 0.3);
         }
+        self.layout_board();
+        self.queue_dirty = true;
+    }
+
+    /// A glyph in the middle of each pipe, the marker of its region, so a region has the same
+    /// glyph in all of its tiles, and a different one from the regions it touches.
+    fn marker_sprites(&self) -> impl Iterator<Item = SpriteInstance> + '_ {
+        let size = self.tile_size() * 0.3;
+        self.pipes
+            .iter()
+            .filter(|pipe| pipe.kind != EMPTY)
+            .map(move |pipe| {
+                let marker = atlas::MARKERS[pipe.marker as usize];
+                SpriteInstance::new(
+                    pipe.sprite.get_x(),
+                    pipe.sprite.get_y(),
+                    size,
+                    size,
+                    self.texture,
+                    marker,
+                )
+                .with_color([0, 0, 0, 170])
+            })
     }
 
     pub fn get_sprites(&self) -> Vec<SpriteInstance> {
@@ -949,25 +1925,45 @@ impl<R: Rng> GameBoard<R> {
         for pipe in self.pipes.iter() {
             sprites.push(pipe.sprite.clone());
         }
-        sprites.push(self.life_text.clone());
-        sprites.extend(self.life_number.iter().cloned());
-        sprites.push(self.score_text.clone());
+        if self.region_markers && self.ruleset == Ruleset::NetWalk {
+            sprites.extend(self.marker_sprites());
+        }
+        sprites.extend(self.power_sprites.iter().map(|(_, x)| x.clone()));
+        sprites.extend(self.loop_sprites.iter().cloned());
+        if self.mode.has_life() {
+            sprites.extend(self.life_text.sprites());
+            sprites.extend(self.life_number.iter().cloned());
+        }
+        sprites.extend(self.score_text.sprites());
         sprites.extend(self.score_number.iter().cloned());
+        if self.lose_anim == 0.0 {
+            sprites.extend(self.queue_sprites.iter().cloned());
+        }
         if self.win_anim > 0.0 {
-            sprites.push(self.win_sprite.clone());
+            sprites.extend(self.banner.sprites());
+            sprites.extend(self.star_sprites.iter().cloned());
+            sprites.extend(self.bonus_number.iter().cloned());
         }
         if self.lose_anim > 0.0 {
-            sprites.push(self.win_sprite.clone());
+            sprites.extend(self.banner.sprites());
             if self.lose_anim < 0.5 {
-                sprites.push(self.again_button.sprite.clone());
+                // the totals only fit once the score is in the middle of the screen
+                sprites.extend(self.summary_sprites.iter().cloned());
+                sprites.extend(self.summary_text.sprites());
+                sprites.extend(self.again_button.sprites());
             }
         }
+        if self.vignette_anim > 0.0 {
+            sprites.push(self.vignette.clone());
+        }
         sprites
     }
 }
 
 struct Button {
     sprite: SpriteInstance,
+    /// The text drawn in place of the sprite, if any.
+    label: Option<Label>,
     height: f32,
     bounds: [f32; 4],
     anim: f32,
@@ -978,12 +1974,63 @@ impl Button {
         Self {
             height: sprite.get_height(),
             sprite,
+            label: None,
             bounds,
             anim: 0.0,
             is_over: false,
         }
     }
 
+    /// A button drawn as a text, that can be clicked anywhere over it.
+    fn with_label(label: Label, texture: TextureId) -> Self {
+        let sprite = SpriteInstance::new(
+            label.get_x(),
+            label.get_y(),
+            0.0,
+            0.0,
+            texture,
+            atlas::BLANCK,
+        );
+        let mut button = Self {
+            height: label.get_size(),
+            sprite,
+            label: Some(label),
+            bounds: [0.0; 4],
+            anim: 0.0,
+            is_over: false,
+        };
+        button.fit_bounds();
+        button
+    }
+
+    fn set_text(&mut self, text: &str) {
+        if let Some(label) = &mut self.label {
+            label.set_text(text);
+        }
+        self.fit_bounds();
+    }
+
+    /// Make the clickable area cover the label, at its size when not hovered.
+    fn fit_bounds(&mut self) {
+        if let Some(label) = &self.label {
+            let scale = self.height / label.get_size();
+            let (width, height) = (label.get_width() * scale, label.get_height() * scale);
+            self.bounds = [
+                -width / 2.0 - 0.02,
+                width / 2.0 + 0.02,
+                -height / 2.0,
+                height / 2.0,
+            ];
+        }
+    }
+
+    fn set_position(&mut self, x: f32, y: f32) {
+        self.sprite.set_position(x, y);
+        if let Some(label) = &mut self.label {
+            label.set_position(x, y);
+        }
+    }
+
     fn mouse_input(&mut self, x: f32, y: f32) {
         let left = self.sprite.get_x() + self.bounds[0];
         let rigth = self.sprite.get_x() + self.bounds[1];
@@ -995,13 +2042,95 @@ impl Button {
 
     fn update(&mut self, dt: f32) {
         let s = self.anim * 0.1 + 1.0;
-        self.sprite.set_heigh_prop(self.height * s);
+        match &mut self.label {
+            Some(label) => label.set_size(self.height * s),
+            None => self.sprite.set_heigh_prop(self.height * s),
+        }
         if self.is_over {
             self.anim = (self.anim + dt * 6.0).min(1.0);
         } else {
             self.anim = (self.anim - dt * 6.0).max(0.0);
         }
     }
+
+    fn sprites(&self) -> Vec<SpriteInstance> {
+        match &self.label {
+            Some(label) => label.sprites().collect(),
+            None => vec![self.sprite.clone()],
+        }
+    }
+}
+
+/// A horizontal bar to pick a value from 0 to 1, by dragging its knob.
+struct Slider {
+    icon: SpriteInstance,
+    track: SpriteInstance,
+    fill: SpriteInstance,
+    knob: SpriteInstance,
+    value: f32,
+    /// If the mouse was down in the last update, to know when it is pressed.
+    was_down: bool,
+    dragging: bool,
+}
+impl Slider {
+    const WIDTH: f32 = 1.0;
+    const LEFT: f32 = -0.45;
+
+    fn new(y: f32, icon: [f32; 4], texture: TextureId) -> Self {
+        let x = Self::LEFT + Self::WIDTH / 2.0;
+        Self {
+            icon: SpriteInstance::new_height_prop(Self::LEFT - 0.2, y, 0.15, texture, icon)
+                .with_color([0, 240, 0, 255]),
+            track: SpriteInstance::new(x, y, Self::WIDTH, 0.04, texture, atlas::BLANCK)
+                .with_color([0, 90, 0, 255]),
+            fill: SpriteInstance::new(x, y, Self::WIDTH, 0.04, texture, atlas::BLANCK)
+                .with_color([0, 240, 0, 255]),
+            knob: SpriteInstance::new(x, y, 0.12, 0.12, texture, atlas::SLIDER_KNOB),
+            value: 1.0,
+            was_down: false,
+            dragging: false,
+        }
+    }
+
+    fn set_value(&mut self, value: f32) {
+        self.value = value.clamp(0.0, 1.0);
+        let y = self.track.get_y();
+        let width = Self::WIDTH * self.value;
+        self.fill.set_size(width, 0.04);
+        self.fill.set_position(Self::LEFT + width / 2.0, y);
+        self.knob.set_position(Self::LEFT + width, y);
+    }
+
+    /// Drag the knob with the mouse. Return true when a drag ends, and the value is final.
+    fn mouse_input(&mut self, x: f32, y: f32, down: bool) -> bool {
+        let pressed = down && !self.was_down;
+        self.was_down = down;
+        let over = x > Self::LEFT - 0.06
+            && x < Self::LEFT + Self::WIDTH + 0.06
+            && (y - self.track.get_y()).abs() < 0.08;
+        if pressed && over {
+            self.dragging = true;
+        }
+        if !self.dragging {
+            return false;
+        }
+        self.set_value((x - Self::LEFT) / Self::WIDTH);
+        if !down {
+            self.dragging = false;
+            return true;
+        }
+        false
+    }
+
+    fn sprites(&self) -> impl Iterator<Item = SpriteInstance> {
+        vec![
+            self.icon.clone(),
+            self.track.clone(),
+            self.fill.clone(),
+            self.knob.clone(),
+        ]
+        .into_iter()
+    }
 }
 
 pub struct Game<R: Rng, S: SpriteRender> {
@@ -1009,39 +2138,68 @@ pub struct Game<R: Rng, S: SpriteRender> {
     render: S,
     background_painel: SpriteInstance,
     start_button: Button,
+    /// Closes the game. The web version has no window to close, so it is not shown there.
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
     close_button: Button,
     music_button: Button,
     audio_button: Button,
     back_button: Button,
+    ruleset_button: Button,
+    loop_button: Button,
+    mode_button: Button,
+    settings_button: Button,
+    /// Switch to the next language, in the settings screen.
+    language_button: Button,
+    /// Switch to the next color theme, in the settings screen.
+    theme_button: Button,
+    /// Turn the region markers on and off, in the settings screen.
+    markers_button: Button,
+    /// Turn the reduced motion on and off, in the settings screen.
+    motion_button: Button,
+    /// The volume of each bus of the mixer, in the order of `Bus::ALL`.
+    volume_sliders: Vec<Slider>,
+    /// The game mode selected in the menu. The classic rules only have the standard one.
+    mode: GameMode,
+    best_text: Label,
+    best_number: Vec<SpriteInstance>,
     board: GameBoard<R>,
     in_menu: bool,
+    /// If the settings screen is open, over the menu.
+    in_settings: bool,
+    settings: Settings,
+    /// The scroll not yet converted to actions.
+    wheel: f32,
+    /// Reloads the art, the sounds, the music and the themes when their files change, in
+    /// development builds.
+    #[cfg(all(debug_assertions, not(target_arch = "wasm32")))]
+    assets: assets::Watcher,
+    /// The atlas packed from the art in `res/`, in development builds.
+    #[cfg(all(debug_assertions, not(target_arch = "wasm32")))]
+    atlas: Option<assets::Atlas>,
 }
 impl<R: Rng, S: SpriteRender> Game<R, S> {
     pub fn new(
         rng: R,
-        music: Sound,
-        slow_down_effect: Arc<AtomicBool>,
+        music: MusicDirector,
+        mixer: Mixer,
+        settings: Settings,
         camera: Camera,
         mut render: S,
     ) -> Self {
         let texture = {
-            let image =
-                image::load_from_memory(include_bytes!(concat!(env!("OUT_DIR"), "/atlas.png")))
-                    .unwrap()
-                    .to_rgba8();
+            let image = image::load_from_memory(ATLAS).unwrap().to_rgba8();
             Texture::new(image.width(), image.height())
                 .data(image.into_raw().as_slice())
                 .create(&mut render)
                 .unwrap()
         };
-        Self {
+        let mut game = Self {
             camera,
             render,
             background_painel: SpriteInstance::new(0.0, 0.0, 2.2, 2.2, texture, atlas::PAINEL),
-            start_button: Button::new(
-                SpriteInstance::new_height_prop(0.0, 0.0, 0.5, texture, atlas::START_BUTTON)
-                    .with_color([0, 230, 0, 255]),
-                [-0.62, 0.62, -0.20, 0.20],
+            start_button: Button::with_label(
+                Label::new("START", 0.0, 0.0, 0.4, texture).with_color([0, 230, 0, 255]),
+                texture,
             ),
             close_button: Button::new(
                 SpriteInstance::new_height_prop(0.0, 0.5, 0.25, texture, atlas::CLOSE_BUTTON)
@@ -1063,12 +2221,75 @@ impl<R: Rng, S: SpriteRender> Game<R, S> {
                     .with_color([0, 240, 0, 255]),
                 [-0.07, 0.07, -0.07, 0.07],
             ),
-            board: GameBoard::new(texture, rng, music, slow_down_effect),
+            ruleset_button: Button::new(
+                SpriteInstance::new_height_prop(-0.3, -0.45, 0.25, texture, atlas::PIPE_FOUR)
+                    .with_color([0, 240, 0, 255]),
+                [-0.12, 0.12, -0.12, 0.12],
+            ),
+            loop_button: Button::new(
+                SpriteInstance::new_height_prop(0.0, -0.45, 0.2, texture, atlas::LOOPS)
+                    .with_color([0, 240, 0, 255]),
+                [-0.1, 0.1, -0.1, 0.1],
+            ),
+            mode_button: Button::new(
+                SpriteInstance::new_height_prop(0.3, -0.45, 0.2, texture, atlas::MODE_STANDARD)
+                    .with_color([0, 240, 0, 255]),
+                [-0.1, 0.1, -0.1, 0.1],
+            ),
+            settings_button: Button::new(
+                SpriteInstance::new_height_prop(0.55, 0.95, 0.15, texture, atlas::SETTINGS)
+                    .with_color([0, 240, 0, 255]),
+                [-0.07, 0.07, -0.07, 0.07],
+            ),
+            language_button: Button::with_label(
+                Label::new("English", 0.0, 0.28, 0.12, texture).with_color([0, 240, 0, 255]),
+                texture,
+            ),
+            theme_button: Button::with_label(
+                Label::new("", 0.0, 0.41, 0.09, texture).with_color([0, 240, 0, 255]),
+                texture,
+            ),
+            markers_button: Button::with_label(
+                Label::new("", 0.0, 0.52, 0.09, texture).with_color([0, 240, 0, 255]),
+                texture,
+            ),
+            motion_button: Button::with_label(
+                Label::new("", 0.0, 0.63, 0.09, texture).with_color([0, 240, 0, 255]),
+                texture,
+            ),
+            volume_sliders: Bus::ALL
+                .iter()
+                .enumerate()
+                .map(|(i, bus)| {
+                    let icon = match bus {
+                        Bus::Master => atlas::SOUND,
+                        Bus::Music => atlas::MUSIC,
+                        Bus::Effects => atlas::EFFECTS,
+                    };
+                    Slider::new(-0.45 + 0.3 * i as f32, icon, texture)
+                })
+                .collect(),
+            mode: GameMode::Standard,
+            best_text: Label::new("BEST", -0.1, -0.72, 0.11, texture).with_color([0, 240, 0, 255]),
+            best_number: Vec::new(),
+            board: GameBoard::new(texture, rng, music, mixer),
             in_menu: true,
-        }
+            in_settings: false,
+            settings,
+            wheel: 0.0,
+            #[cfg(all(debug_assertions, not(target_arch = "wasm32")))]
+            assets: assets::Watcher::default(),
+            #[cfg(all(debug_assertions, not(target_arch = "wasm32")))]
+            atlas: None,
+        };
+        game.apply_settings();
+        game
     }
 
     pub fn update(&mut self, dt: f32, input: &Input) {
+        #[cfg(all(debug_assertions, not(target_arch = "wasm32")))]
+        self.reload_assets(dt);
+
         let (mouse_x, mouse_y) = self
             .camera
             .position_to_word_space(input.mouse_x, input.mouse_y);
@@ -1077,42 +2298,77 @@ impl<R: Rng, S: SpriteRender> Game<R, S> {
         self.audio_button.mouse_input(mouse_x, mouse_y);
         if input.mouse_left_state == 3 {
             if self.music_button.is_over {
-                if self.music_button.anim == 0.0 {
-                    self.music_button.sprite.set_uv_rect(atlas::MUSIC_OFF);
-                    self.music_button.anim = 1.0;
-                    self.board.music.set_volume(0.0);
-                } else {
-                    self.music_button.sprite.set_uv_rect(atlas::MUSIC);
-                    self.music_button.anim = 0.0;
-                    self.board.music.set_volume(1.0);
-                }
+                self.settings.music = !self.settings.music;
+                self.update_audio();
+                self.settings.save();
             }
             if self.audio_button.is_over {
-                if self.audio_button.anim == 0.0 {
-                    self.audio_button.sprite.set_uv_rect(atlas::SOUND_OFF);
-                    self.audio_button.anim = 1.0;
-                    self.board.sound_effects = false;
-                } else {
-                    self.audio_button.sprite.set_uv_rect(atlas::SOUND);
-                    self.audio_button.anim = 0.0;
-                    self.board.sound_effects = true;
-                }
+                self.settings.sound_effects = !self.settings.sound_effects;
+                self.update_audio();
+                self.settings.save();
             }
         }
 
-        if self.in_menu {
+        if self.in_settings {
+            self.update_settings(dt, input, mouse_x, mouse_y);
+        } else if self.in_menu {
+            self.settings_button.mouse_input(mouse_x, mouse_y);
+            self.settings_button.update(dt);
+            if input.mouse_left_state == 3 && self.settings_button.is_over {
+                self.in_settings = true;
+                self.update_layout();
+            }
+
+            self.ruleset_button.mouse_input(mouse_x, mouse_y);
+            self.ruleset_button.update(dt);
+            if input.mouse_left_state == 3 && self.ruleset_button.is_over {
+                self.board.ruleset = match self.board.ruleset {
+                    Ruleset::NetWalk => Ruleset::Classic,
+                    Ruleset::Classic => Ruleset::Power,
+                    Ruleset::Power => Ruleset::NetWalk,
+                };
+                self.ruleset_button
+                    .sprite
+                    .set_uv_rect(self.board.ruleset.icon());
+                self.update_best();
+            }
+
+            if self.board.ruleset != Ruleset::Classic {
+                self.mode_button.mouse_input(mouse_x, mouse_y);
+                self.mode_button.update(dt);
+                if input.mouse_left_state == 3 && self.mode_button.is_over {
+                    self.mode = self.mode.next();
+                    self.mode_button.sprite.set_uv_rect(self.mode.icon());
+                    self.update_best();
+                }
+            }
+
+            self.loop_button.mouse_input(mouse_x, mouse_y);
+            self.loop_button.update(dt);
+            if input.mouse_left_state == 3 && self.loop_button.is_over {
+                self.board.loop_free = !self.board.loop_free;
+                self.loop_button
+                    .sprite
+                    .set_uv_rect(if self.board.loop_free {
+                        atlas::NO_LOOPS
+                    } else {
+                        atlas::LOOPS
+                    });
+            }
+
             self.start_button.mouse_input(mouse_x, mouse_y);
             self.start_button.update(dt);
             if input.mouse_left_state == 3 && self.start_button.is_over {
                 self.in_menu = false;
                 self.update_layout();
+                self.board.mode = self.selected_mode();
+                self.board.maze_config = self.maze_config();
                 self.board.reset();
-                if self.board.sound_effects {
-                    crate::audio_engine()
-                        .new_sound(WavDecoder::new(Cursor::new(sounds::CONFIRM)).unwrap())
-                        .unwrap()
-                        .play();
-                }
+                self.settings.ruleset = self.board.ruleset;
+                self.settings.mode = self.mode;
+                self.settings.loop_free = self.board.loop_free;
+                self.settings.save();
+                self.board.play_sound(Effect::Confirm);
             }
             #[cfg(not(target_arch = "wasm32"))]
             {
@@ -1127,36 +2383,320 @@ impl<R: Rng, S: SpriteRender> Game<R, S> {
             self.back_button.update(dt);
 
             if input.mouse_left_state == 3 && self.back_button.is_over {
+                // a run left in the middle still counts, like the ones in the zen mode
+                if self.board.lose_anim == 0.0 {
+                    self.record_score(self.board.score);
+                }
                 self.in_menu = true;
                 self.update_layout();
-                if self.board.sound_effects {
-                    crate::audio_engine()
-                        .new_sound(WavDecoder::new(Cursor::new(sounds::NEGATE)).unwrap())
-                        .unwrap()
-                        .play();
+                // start the music again, if the run was lost
+                self.board.music.set_playing_speed(1.0);
+                self.board.play_sound(Effect::Negate);
+            } else {
+                // the click on the back button does not reach the board
+                self.board
+                    .mouse_input(mouse_x, mouse_y, input.mouse_left_state == 3);
+                for action in self.actions(input) {
+                    self.board.action(action);
+                }
+                self.board.animate(dt);
+                if let Some(score) = self.board.finished_score.take() {
+                    self.record_score(score);
                 }
             }
+        }
 
-            self.board.mouse_input(
-                mouse_x,
-                mouse_y,
-                if input.mouse_left_state == 3 {
-                    1
-                } else if input.mouse_rigth_state == 3 {
-                    2
-                } else {
-                    0
-                },
-            );
-            self.board.animate(dt);
+        let scene = if self.in_menu {
+            Scene::Menu
+        } else if self.board.lose_anim > 0.0 {
+            Scene::GameOver
+        } else if self.board.win_anim > 0.0 {
+            Scene::LevelComplete
+        } else {
+            Scene::Playing
+        };
+        self.board.music.set_scene(scene);
+        self.board.music.update();
+    }
+
+    /// Load again the art, the sounds, the music and the themes whose files have changed.
+    #[cfg(all(debug_assertions, not(target_arch = "wasm32")))]
+    fn reload_assets(&mut self, dt: f32) {
+        let changes = self.assets.poll(dt);
+        if changes.textures {
+            let embedded = image::load_from_memory(ATLAS).unwrap().to_rgba8();
+            match assets::Atlas::build(&embedded) {
+                Ok(atlas) => {
+                    let texture = self.board.texture;
+                    match self
+                        .render
+                        .update_texture(texture, atlas.image.as_raw(), None)
+                    {
+                        Ok(()) => {
+                            eprintln!("reloaded the atlas");
+                            self.atlas = Some(atlas);
+                        }
+                        Err(_) => eprintln!("could not update the atlas texture"),
+                    }
+                }
+                Err(err) => eprintln!("could not rebuild the atlas: {}", err),
+            }
+        }
+        if changes.sounds {
+            sounds::reload(&mut self.board.sound_bank);
+        }
+        if changes.themes {
+            self.board.set_theme(Theme::named(&self.settings.theme));
+            eprintln!("reloaded the themes");
+        }
+        if changes.music {
+            match self.board.music.reload(&self.board.mixer) {
+                Ok(()) => eprintln!("reloaded the music"),
+                Err(err) => eprintln!("could not reload the music: {}", err),
+            }
         }
     }
 
+    /// Drag the volume sliders, and go back to the menu.
+    fn update_settings(&mut self, dt: f32, input: &Input, mouse_x: f32, mouse_y: f32) {
+        for (slider, &bus) in self.volume_sliders.iter_mut().zip(Bus::ALL.iter()) {
+            let released = slider.mouse_input(mouse_x, mouse_y, input.mouse_left_down);
+            if slider.dragging || released {
+                // change the volume while dragging, so the player can hear it
+                self.settings.volumes[bus as usize] = slider.value;
+                self.settings.apply_volumes(&self.board.mixer);
+            }
+            if released {
+                self.settings.save();
+                if bus != Bus::Music {
+                    self.board.play_sound(Effect::Click);
+                }
+            }
+        }
+
+        self.language_button.mouse_input(mouse_x, mouse_y);
+        self.language_button.update(dt);
+        if input.mouse_left_state == 3 && self.language_button.is_over {
+            self.settings.language = self.board.locale.next_code().to_string();
+            self.settings.save();
+            self.update_texts();
+            self.update_layout();
+            self.board.play_sound(Effect::Click);
+        }
+
+        self.theme_button.mouse_input(mouse_x, mouse_y);
+        self.theme_button.update(dt);
+        if input.mouse_left_state == 3 && self.theme_button.is_over {
+            let names = Theme::names();
+            let i = names.iter().position(|x| *x == self.settings.theme);
+            let next = i.map_or(0, |i| (i + 1) % names.len());
+            self.settings.theme = names[next].clone();
+            self.settings.save();
+            self.board.set_theme(Theme::named(&self.settings.theme));
+            self.update_texts();
+            self.board.play_sound(Effect::Click);
+        }
+
+        self.markers_button.mouse_input(mouse_x, mouse_y);
+        self.markers_button.update(dt);
+        if input.mouse_left_state == 3 && self.markers_button.is_over {
+            self.settings.region_markers = !self.settings.region_markers;
+            self.settings.save();
+            self.board.region_markers = self.settings.region_markers;
+            self.update_texts();
+            self.board.play_sound(Effect::Click);
+        }
+
+        self.motion_button.mouse_input(mouse_x, mouse_y);
+        self.motion_button.update(dt);
+        if input.mouse_left_state == 3 && self.motion_button.is_over {
+            self.settings.reduced_motion = !self.settings.reduced_motion;
+            self.settings.save();
+            self.board.reduced_motion = self.settings.reduced_motion;
+            self.update_texts();
+            self.board.play_sound(Effect::Click);
+        }
+
+        self.back_button.mouse_input(mouse_x, mouse_y);
+        self.back_button.update(dt);
+        if input.mouse_left_state == 3 && self.back_button.is_over {
+            self.in_settings = false;
+            self.update_layout();
+            self.board.play_sound(Effect::Negate);
+        }
+    }
+
+    /// Apply the loaded settings to the board and to the menu.
+    fn apply_settings(&mut self) {
+        self.board.ruleset = self.settings.ruleset;
+        self.board.loop_free = self.settings.loop_free;
+        self.board.set_theme(Theme::named(&self.settings.theme));
+        self.board.region_markers = self.settings.region_markers;
+        self.board.reduced_motion = self.settings.reduced_motion;
+        self.mode = self.settings.mode;
+        self.update_texts();
+        self.ruleset_button
+            .sprite
+            .set_uv_rect(self.board.ruleset.icon());
+        self.loop_button
+            .sprite
+            .set_uv_rect(if self.board.loop_free {
+                atlas::NO_LOOPS
+            } else {
+                atlas::LOOPS
+            });
+        self.mode_button.sprite.set_uv_rect(self.mode.icon());
+        for (slider, &bus) in self.volume_sliders.iter_mut().zip(Bus::ALL.iter()) {
+            slider.set_value(self.settings.volumes[bus as usize]);
+        }
+        self.update_audio();
+        self.update_best();
+    }
+
+    /// Load the language of the settings, and update all text to it.
+    fn update_texts(&mut self) {
+        self.board.locale = Locale::new(&self.settings.language);
+        let locale = &self.board.locale;
+        self.start_button.set_text(locale.get("start"));
+        self.language_button.set_text(locale.get("language_name"));
+        // a theme added to the themes file but not to the catalogs shows its name
+        let key = format!("theme.{}", self.settings.theme);
+        let name = match locale.get(&key) {
+            x if x == key => self.settings.theme.replace('_', " ").to_uppercase(),
+            x => x.to_string(),
+        };
+        let theme = format!("{} {}", locale.get("theme"), name);
+        self.theme_button.set_text(&theme);
+        self.markers_button
+            .set_text(locale.get(if self.settings.region_markers {
+                "markers_on"
+            } else {
+                "markers_off"
+            }));
+        self.motion_button
+            .set_text(locale.get(if self.settings.reduced_motion {
+                "reduced_motion"
+            } else {
+                "full_motion"
+            }));
+        self.best_text.set_text(locale.get("best"));
+        self.board.update_texts();
+        self.update_best();
+    }
+
+    /// Update the audio buttons and the volumes of the mixer to the settings.
+    fn update_audio(&mut self) {
+        self.music_button
+            .sprite
+            .set_uv_rect(if self.settings.music {
+                atlas::MUSIC
+            } else {
+                atlas::MUSIC_OFF
+            });
+        self.audio_button
+            .sprite
+            .set_uv_rect(if self.settings.sound_effects {
+                atlas::SOUND
+            } else {
+                atlas::SOUND_OFF
+            });
+        self.settings.apply_volumes(&self.board.mixer);
+    }
+
+    /// The mode the board is played in, that is always the standard one in the classic rules.
+    fn selected_mode(&self) -> GameMode {
+        match self.board.ruleset {
+            Ruleset::Classic => GameMode::Standard,
+            _ => self.mode,
+        }
+    }
+
+    fn record_score(&mut self, score: u32) {
+        if self
+            .settings
+            .record_score(self.board.ruleset, self.board.mode, score)
+        {
+            self.settings.save();
+            self.update_best();
+        }
+    }
+
+    /// Show the best score of the selected rules and mode in the menu.
+    fn update_best(&mut self) {
+        let best = self
+            .settings
+            .best_score(self.board.ruleset, self.selected_mode());
+        // the text ends a bit left of the middle, whatever its length in the language
+        let y = self.best_text.get_y();
+        self.best_text
+            .set_position(-0.12 - self.best_text.get_width() / 2.0, y);
+        self.best_number = utils::number_to_sprites(
+            best,
+            -0.1,
+            y,
+            self.best_text.digit_height(),
+            [255, 0, 0, 255],
+            false,
+            self.board.texture,
+        );
+    }
+
+    /// The maze generator for the selected rules and mode. The one in the settings comes first,
+    /// then the one the balance file gives to the mode.
+    fn maze_config(&self) -> MazeConfig {
+        let algorithm = match self.board.ruleset {
+            // uniform spanning trees have many dead ends, and so many terminals
+            Ruleset::Power => Algorithm::Wilson,
+            // bushy trees make harder puzzles when there can be no loops
+            _ if self.board.loop_free => Algorithm::Kruskal,
+            _ => Algorithm::GrowingTree,
+        };
+        let algorithm = self
+            .settings
+            .maze_algorithm
+            .or_else(|| self.board.balance.maze_algorithm(self.board.mode))
+            .unwrap_or(algorithm);
+        self.board.balance.maze_config(algorithm)
+    }
+
+    /// Translate the input of this frame to actions, following the input map.
+    fn actions(&mut self, input: &Input) -> Vec<Action> {
+        let map = &self.settings.input;
+        let mut actions = Vec::new();
+        if input.mouse_left_state == 3 {
+            actions.push(map.mouse_left);
+        }
+        if input.mouse_rigth_state == 3 {
+            actions.push(map.mouse_right);
+        }
+        if input.mouse_middle_state == 3 {
+            actions.push(map.mouse_middle);
+        }
+        self.wheel += input.mouse_wheel;
+        while self.wheel >= 1.0 {
+            self.wheel -= 1.0;
+            actions.push(map.wheel_up);
+        }
+        while self.wheel <= -1.0 {
+            self.wheel += 1.0;
+            actions.push(map.wheel_down);
+        }
+        for key in input.keys.iter() {
+            actions.push(map.key_action(*key));
+        }
+        actions
+    }
+
     pub fn render(&mut self, window_id: WindowId) {
-        let sprites = self.get_sprites();
+        #[allow(unused_mut)]
+        let mut sprites = self.get_sprites();
+        #[cfg(all(debug_assertions, not(target_arch = "wasm32")))]
+        if let Some(atlas) = &self.atlas {
+            atlas.remap(&mut sprites);
+        }
         self.render
             .render(window_id)
-            .clear_screen(&[0.0f32, 0.25, 0.0, 1.0])
+            .clear_screen(&self.board.theme.clear_color())
             .draw_sprites(&mut self.camera, &sprites)
             .finish();
     }
@@ -1168,43 +2708,42 @@ impl<R: Rng, S: SpriteRender> Game<R, S> {
     }
 
     pub fn update_layout(&mut self) {
-        let prop = self.camera.width() as f32 / self.camera.height() as f32;
+        let width = self.camera.width();
+        let height = self.camera.height();
+        let prop = width / height;
+        self.board.resize(width, height);
+
+        // the board, with its panel, and the HUD beside it
+        let [ex, ey] = self.board.extents();
+        let (left, right) = (-ex - BOARD_MARGIN, ex + BOARD_MARGIN + HUD_SIZE);
+        let (top, bottom) = (-ey - BOARD_MARGIN - HUD_SIZE, ey + BOARD_MARGIN);
         if !self.in_menu {
             if prop > 1.0 {
                 // landscape
-                if prop > 1280.0 / 720.0 {
+                if width / 2.0 >= right {
                     self.camera.set_position(0.0, 0.0);
-                } else if prop > 1280.0 / 720.0 / 2.0 + 0.5 {
-                    self.camera.set_position(
-                        -self.camera.width() as f32 / 2.0 + 1.1 * 1280.0 / 720.0,
-                        0.0,
-                    );
+                } else if width >= right - left {
+                    self.camera.set_position(right - width / 2.0, 0.0);
                 } else {
-                    self.camera
-                        .set_position(self.camera.width() as f32 / 2.0 - 1.1, 0.0);
+                    self.camera.set_position(left + width / 2.0, 0.0);
                 }
             } else {
                 // portrait
-                if prop < 720.0 / 1280.0 {
+                if height / 2.0 >= -top {
                     self.camera.set_position(0.0, 0.0);
-                } else if prop < 1.0 / (1280.0 / 720.0 / 2.0 + 0.5) {
-                    self.camera.set_position(
-                        0.0,
-                        self.camera.height() as f32 / 2.0 - 1.1 * 1280.0 / 720.0,
-                    );
+                } else if height >= bottom - top {
+                    self.camera.set_position(0.0, top + height / 2.0);
                 } else {
-                    self.camera
-                        .set_position(0.0, -self.camera.height() as f32 / 2.0 + 1.1);
+                    self.camera.set_position(0.0, bottom - height / 2.0);
                 }
             }
+            self.background_painel
+                .set_size(2.0 * (ex + BOARD_MARGIN), 2.0 * (ey + BOARD_MARGIN));
         } else {
             self.camera.set_position(0.0, 0.0);
+            self.background_painel.set_size(2.2, 2.2);
         }
 
-        let width = self.camera.width();
-        let height = self.camera.height();
-        self.board.resize(width, height);
-
         if self.in_menu {
             self.music_button.sprite.set_position(0.95, 0.95);
             self.audio_button.sprite.set_position(0.75, 0.95);
@@ -1228,34 +2767,58 @@ impl<R: Rng, S: SpriteRender> Game<R, S> {
             }
         }
 
-        if prop > 1.0 {
-            let x;
-            if prop > 1280.0 / 720.0 {
-                x = -1.1 - (1280.0 / 720.0 - 1.0) * 1.1 / 2.0;
-            } else if prop > 1280.0 / 720.0 / 2.0 + 0.65 {
-                let l = -width + 1.1 * 1280.0 / 720.0;
-                x = (l - 1.1) / 2.0;
-            } else if prop > 1280.0 / 720.0 / 2.0 + 0.5 {
-                x = 1.1 * 1280.0 / 720.0 - 0.2;
+        if self.in_menu {
+            // in the settings screen, under the sliders
+            self.back_button.sprite.set_position(0.0, 0.82);
+        } else if prop > 1.0 {
+            let left_side = -width / 2.0 + self.camera.get_position().0;
+            let right_side = width / 2.0 + self.camera.get_position().0;
+            // at the left of the board if there is space, otherwise under the HUD
+            let x = if left - left_side > 0.33 {
+                (left_side + left) / 2.0
             } else {
-                x = width - 1.1 - 0.2;
-            }
+                right_side - 0.2
+            };
             self.back_button.sprite.set_position(x, -0.9);
         } else {
-            self.back_button.sprite.set_position(-0.9, -1.6);
+            self.back_button.sprite.set_position(-ex + 0.1, -ey - 0.6);
         }
     }
 
     pub fn get_sprites(&mut self) -> Vec<SpriteInstance> {
-        if self.in_menu {
-            vec![
+        if self.in_settings {
+            let mut vec = vec![
                 self.background_painel.clone(),
                 self.music_button.sprite.clone(),
                 self.audio_button.sprite.clone(),
-                self.start_button.sprite.clone(),
+                self.back_button.sprite.clone(),
+            ];
+            vec.extend(self.language_button.sprites());
+            vec.extend(self.theme_button.sprites());
+            vec.extend(self.markers_button.sprites());
+            vec.extend(self.motion_button.sprites());
+            for slider in self.volume_sliders.iter() {
+                vec.extend(slider.sprites());
+            }
+            vec
+        } else if self.in_menu {
+            let mut vec = vec![
+                self.background_painel.clone(),
+                self.music_button.sprite.clone(),
+                self.audio_button.sprite.clone(),
+                self.settings_button.sprite.clone(),
+                self.ruleset_button.sprite.clone(),
+                self.loop_button.sprite.clone(),
                 #[cfg(not(target_arch = "wasm32"))]
                 self.close_button.sprite.clone(),
-            ]
+            ];
+            vec.extend(self.start_button.sprites());
+            vec.extend(self.best_text.sprites());
+            if self.board.ruleset != Ruleset::Classic {
+                vec.push(self.mode_button.sprite.clone());
+            }
+            vec.extend(self.best_number.iter().cloned());
+            vec
         } else {
             let mut vec = vec![
                 self.back_button.sprite.clone(),
@@ -0,0 +1,354 @@
+//! Load the art, the sound effects, the music and the themes from `res/` while the game runs, in
+//! development builds, so they can be edited without rebuilding. Release and web builds only use
+//! the embedded ones.
+//!
+//! The atlas is packed again from the files, so a sprite can change its size, and new sprites
+//! can be added. The code still refers to each sprite by the place `build.rs` gave it, so the
+//! sprites are moved to their new places before being drawn, by `Atlas::remap`. A new sprite
+//! only has a constant to refer to it after a rebuild.
+
+use image::{GenericImage, GenericImageView, RgbaImage};
+use sprite_render::SpriteInstance;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use super::atlas;
+
+/// The folders and files that are watched, and what each one holds.
+const WATCHED: [(&str, Kind); 6] = [
+    ("res/textures", Kind::Texture),
+    ("res/font", Kind::Texture),
+    ("res/sound", Kind::Sound),
+    ("res/music", Kind::Music),
+    ("res/music.cfg", Kind::Music),
+    ("res/themes.cfg", Kind::Theme),
+];
+
+/// The width and height of the atlas, the same as the one `build.rs` makes.
+const SIZE: u32 = 2048;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Kind {
+    Texture,
+    Sound,
+    Music,
+    Theme,
+}
+
+/// The path of 'name' in the crate, so the files are found from any working directory.
+pub fn path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join(name)
+}
+
+/// What has changed since the last check.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Changes {
+    pub textures: bool,
+    pub sounds: bool,
+    pub music: bool,
+    pub themes: bool,
+}
+impl Changes {
+    fn set(&mut self, kind: Kind) {
+        match kind {
+            Kind::Texture => self.textures = true,
+            Kind::Sound => self.sounds = true,
+            Kind::Music => self.music = true,
+            Kind::Theme => self.themes = true,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Watcher {
+    /// The modification time of each file, in the last check.
+    modified: Vec<(PathBuf, std::time::SystemTime, Kind)>,
+    /// The time until the files are checked again.
+    timer: f32,
+    /// If the files were checked before.
+    started: bool,
+}
+impl Watcher {
+    /// Check the files every half second. Unlike the balance file, the first check finds the
+    /// art and the sounds as changed, so the game starts with the ones on the disk. The music
+    /// is already playing by then, and the themes are read from the disk, so they are only
+    /// loaded again when they change.
+    pub fn poll(&mut self, dt: f32) -> Changes {
+        let mut changes = Changes::default();
+        self.timer -= dt;
+        if self.timer > 0.0 {
+            return changes;
+        }
+        self.timer = 0.5;
+        let mut modified = Vec::new();
+        for &(name, kind) in WATCHED.iter() {
+            let watched = path(name);
+            let paths = match std::fs::read_dir(&watched) {
+                Ok(entries) => entries.filter_map(|x| x.ok()).map(|x| x.path()).collect(),
+                Err(_) => vec![watched],
+            };
+            for path in paths {
+                let time = match std::fs::metadata(&path).and_then(|x| x.modified()) {
+                    Ok(x) => x,
+                    Err(_) => continue,
+                };
+                let known = self.modified.iter().any(|x| x.0 == path && x.1 == time);
+                let on_disk = matches!(kind, Kind::Music | Kind::Theme);
+                if !known && (self.started || !on_disk) {
+                    changes.set(kind);
+                }
+                modified.push((path, time, kind));
+            }
+        }
+        // a deleted file is also a change
+        for (path, _, kind) in self.modified.iter() {
+            if !modified.iter().any(|x| x.0 == *path) {
+                changes.set(*kind);
+            }
+        }
+        self.modified = modified;
+        self.started = true;
+        changes
+    }
+}
+
+/// Each image, with the name of each of its sprites and their rects in it.
+type Sprites = Vec<(PathBuf, Vec<(String, [u32; 4])>)>;
+
+/// The sprites of each image in `res/textures` and `res/font`, by the name of their constant.
+/// Follows the same rules as `build.rs`.
+fn sprites() -> Result<Sprites, String> {
+    let read = |path: &Path| {
+        std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))
+    };
+    let malformed = |path: &Path| format!("{} is malformed", path.display());
+
+    let mut sprites = Vec::new();
+    let font = path("res/font/font.txt");
+    let mut glyphs = Vec::new();
+    for line in read(&font)?.lines() {
+        let tokens = line.split_ascii_whitespace().collect::<Vec<&str>>();
+        if let ["glyph", code, x, y, w, h, ..] = tokens[..] {
+            let rect = [x, y, w, h]
+                .iter()
+                .map(|x| x.parse().ok())
+                .collect::<Option<Vec<u32>>>()
+                .ok_or_else(|| malformed(&font))?;
+            if rect[2] > 0 && rect[3] > 0 {
+                glyphs.push((
+                    format!("glyph {}", code),
+                    [rect[0], rect[1], rect[2], rect[3]],
+                ));
+            }
+        }
+    }
+    sprites.push((path("res/font/font.png"), glyphs));
+
+    let textures = path("res/textures");
+    let mut entries = std::fs::read_dir(&textures)
+        .map_err(|err| format!("{}: {}", textures.display(), err))?
+        .filter_map(|x| x.ok().map(|x| x.path()))
+        .collect::<Vec<PathBuf>>();
+    // the descriptors first, so the images they describe are not taken as a whole
+    entries.sort_by_key(|x| x.extension().and_then(|x| x.to_str()) != Some("txt"));
+    for entry in entries {
+        match entry.extension().and_then(|x| x.to_str()) {
+            Some("txt") => {
+                let tokens = read(&entry)?
+                    .split_ascii_whitespace()
+                    .map(str::to_string)
+                    .collect::<Vec<String>>();
+                let mut rects = Vec::new();
+                for sprite in tokens.chunks(5) {
+                    let rect = sprite[1..]
+                        .iter()
+                        .map(|x| x.parse().ok())
+                        .collect::<Option<Vec<u32>>>()
+                        .filter(|x| x.len() == 4)
+                        .ok_or_else(|| malformed(&entry))?;
+                    rects.push((sprite[0].clone(), [rect[0], rect[1], rect[2], rect[3]]));
+                }
+                sprites.push((entry.with_extension("png"), rects));
+            }
+            Some("png") => {
+                if sprites.iter().any(|(path, _)| *path == entry) {
+                    continue;
+                }
+                let (width, height) = image::image_dimensions(&entry)
+                    .map_err(|err| format!("{}: {}", entry.display(), err))?;
+                let name = entry.file_stem().unwrap().to_string_lossy().into_owned();
+                sprites.push((entry, vec![(name, [0, 0, width, height])]));
+            }
+            _ => {}
+        }
+    }
+    Ok(sprites)
+}
+
+/// Place rects of 'sizes' in the atlas, in rows from the tallest, with a pixel around each one
+/// for its border. Return the top left corner of each, or None if they do not fit.
+fn pack(sizes: &[(u32, u32)]) -> Option<Vec<(u32, u32)>> {
+    let mut order = (0..sizes.len()).collect::<Vec<usize>>();
+    order.sort_by_key(|&i| Reverse(sizes[i].1));
+    let mut places = vec![(0, 0); sizes.len()];
+    let (mut x, mut y, mut row_height) = (1, 1, 0);
+    for i in order {
+        let (width, height) = sizes[i];
+        if x + width + 1 > SIZE {
+            x = 1;
+            y += row_height + 2;
+            row_height = 0;
+        }
+        if x + width + 1 > SIZE || y + height + 1 > SIZE {
+            return None;
+        }
+        places[i] = (x, y);
+        x += width + 2;
+        row_height = row_height.max(height);
+    }
+    Some(places)
+}
+
+/// The uv rect of the pixels 'rect' of the atlas, in the same way `build.rs` computes it.
+fn uv_rect([x, y, width, height]: [u32; 4]) -> [f32; 4] {
+    let size = SIZE as f32;
+    [
+        x as f32 / size,
+        y as f32 / size,
+        width as f32 / size,
+        height as f32 / size,
+    ]
+}
+
+/// An atlas packed from the art in `res/`.
+pub struct Atlas {
+    pub image: RgbaImage,
+    /// The uv rect of each sprite in the embedded atlas, by its bits, and its uv rect in this
+    /// one.
+    uv_rects: HashMap<[u32; 4], [f32; 4]>,
+}
+impl Atlas {
+    /// Pack the art in `res/`. The sprites of the embedded atlas that are no longer in the files
+    /// are kept, so the code that uses them still draws something.
+    pub fn build(embedded: &RgbaImage) -> Result<Self, String> {
+        let mut images = Vec::new();
+        // the name of each sprite, its image, and its rect in the image
+        let mut rects = Vec::new();
+        for (path, sprites) in sprites()? {
+            let image = image::open(&path)
+                .map_err(|err| format!("{}: {}", path.display(), err))?
+                .to_rgba8();
+            for (name, [x, y, width, height]) in sprites {
+                if x + width > image.width() || y + height > image.height() {
+                    return Err(format!("'{}' is outside of {}", name, path.display()));
+                }
+                if rects.iter().any(|(x, _, _)| *x == name) {
+                    return Err(format!("'{}' is repeated", name));
+                }
+                rects.push((name, images.len(), [x, y, width, height]));
+            }
+            images.push(image);
+        }
+        for &(name, place) in atlas::PLACES.iter() {
+            if !rects.iter().any(|(x, _, _)| x == name) {
+                rects.push((name.to_string(), images.len(), place));
+            }
+        }
+        images.push(embedded.clone());
+
+        let sizes = rects
+            .iter()
+            .map(|&(_, _, rect)| (rect[2], rect[3]))
+            .collect::<Vec<_>>();
+        let places = pack(&sizes).ok_or("the sprites do not fit in the atlas")?;
+
+        let mut atlas = RgbaImage::new(SIZE, SIZE);
+        let mut uv_rects = HashMap::new();
+        for ((name, image, [x, y, width, height]), (left, top)) in rects.iter().zip(places) {
+            atlas
+                .copy_from(&images[*image].view(*x, *y, *width, *height), left, top)
+                .map_err(|err| err.to_string())?;
+            // repeat the border, like `build.rs` does, so the sprite does not bleed
+            let (right, bottom) = (left + width - 1, top + height - 1);
+            for x in left - 1..=right + 1 {
+                let x_in = x.clamp(left, right);
+                atlas.put_pixel(x, top - 1, *atlas.get_pixel(x_in, top));
+                atlas.put_pixel(x, bottom + 1, *atlas.get_pixel(x_in, bottom));
+            }
+            for y in top..=bottom {
+                atlas.put_pixel(left - 1, y, *atlas.get_pixel(left, y));
+                atlas.put_pixel(right + 1, y, *atlas.get_pixel(right, y));
+            }
+
+            let uv = uv_rect([left, top, *width, *height]);
+            match atlas::PLACES.binary_search_by(|x| x.0.cmp(name.as_str())) {
+                Ok(i) => {
+                    let embedded = uv_rect(atlas::PLACES[i].1).map(f32::to_bits);
+                    uv_rects.insert(embedded, uv);
+                }
+                Err(_) => eprintln!("'{}' is new, rebuild to use it in the code", name),
+            }
+        }
+        Ok(Self {
+            image: atlas,
+            uv_rects,
+        })
+    }
+
+    /// Move 'sprites' from their places in the embedded atlas to their places in this one.
+    pub fn remap(&self, sprites: &mut [SpriteInstance]) {
+        for sprite in sprites.iter_mut() {
+            if let Some(&uv) = self.uv_rects.get(&sprite.uv_rect.map(f32::to_bits)) {
+                sprite.uv_rect = uv;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packed_rects_do_not_overlap() {
+        let sizes = (1..200)
+            .map(|i| (i * 37 % 150 + 1, i * 53 % 90 + 1))
+            .collect::<Vec<(u32, u32)>>();
+        let places = pack(&sizes).unwrap();
+        // each rect with its border
+        let rects = sizes
+            .iter()
+            .zip(places.iter())
+            .map(|(&(w, h), &(x, y))| [x - 1, y - 1, x + w + 1, y + h + 1])
+            .collect::<Vec<_>>();
+        for (i, a) in rects.iter().enumerate() {
+            assert!(a[2] <= SIZE && a[3] <= SIZE);
+            for b in rects[i + 1..].iter() {
+                let apart = a[2] <= b[0] || b[2] <= a[0] || a[3] <= b[1] || b[3] <= a[1];
+                assert!(apart, "{:?} overlaps {:?}", a, b);
+            }
+        }
+        assert!(pack(&[(SIZE, 1)]).is_none());
+    }
+
+    #[test]
+    fn unchanged_files_rebuild_the_same_sprites() {
+        let embedded = image::load_from_memory(crate::game::ATLAS)
+            .unwrap()
+            .to_rgba8();
+        let atlas = Atlas::build(&embedded).unwrap();
+        assert_eq!(atlas.uv_rects.len(), atlas::PLACES.len());
+        // each sprite is moved to a place with the same pixels, and the same border
+        for &(name, [x, y, width, height]) in atlas::PLACES.iter() {
+            let mut sprite =
+                SpriteInstance::new(0.0, 0.0, 1.0, 1.0, 0, uv_rect([x, y, width, height]));
+            atlas.remap(std::slice::from_mut(&mut sprite));
+            let [u, v, ..] = sprite.uv_rect;
+            let (left, top) = ((u * SIZE as f32) as u32, (v * SIZE as f32) as u32);
+            let a = embedded.view(x - 1, y - 1, width + 2, height + 2);
+            let b = atlas.image.view(left - 1, top - 1, width + 2, height + 2);
+            assert!(a.pixels().eq(b.pixels()), "'{}' differs", name);
+        }
+    }
+}
@@ -0,0 +1,400 @@
+//! The numbers that define the difficulty of the game, loaded from `res/balance.cfg`.
+
+use super::maze::{Algorithm, MazeConfig};
+use super::mode::GameMode;
+use super::scramble::ScrambleConfig;
+
+/// The balance file that is built into the game, used when there is no file to read.
+static EMBEDDED: &str = include_str!("../../res/balance.cfg");
+
+/// The most tiles in the shorter side of a board. The board stops growing there, and the longest
+/// side, stretched by up to `MAX_BOARD_RATIO`, still fits in a `u8`.
+pub const MAX_SIZE: u8 = 100;
+const MAX_BOARD_RATIO: f32 = 2.5;
+
+#[cfg(not(target_arch = "wasm32"))]
+const PATH: &str = "res/balance.cfg";
+
+/// The balance file on disk. Development builds find it in the crate, like the other assets, so
+/// it can be edited while the game runs from any directory.
+#[cfg(not(target_arch = "wasm32"))]
+fn path() -> std::path::PathBuf {
+    #[cfg(debug_assertions)]
+    let path = super::assets::path(PATH);
+    #[cfg(not(debug_assertions))]
+    let path = std::path::PathBuf::from(PATH);
+    path
+}
+
+/// The maze algorithm of a game mode: one of the names of `Algorithm`, or `auto` to let the rules
+/// pick it.
+#[derive(Clone, Copy, Debug)]
+pub struct MazeChoice(Option<Algorithm>);
+impl std::str::FromStr for MazeChoice {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "auto" => Ok(MazeChoice(None)),
+            _ => Algorithm::from_name(s)
+                .map(|x| MazeChoice(Some(x)))
+                .ok_or(()),
+        }
+    }
+}
+
+macro_rules! balance {
+    ($($(#[doc = $doc:expr])* $name:ident: $ty:ty,)*) => {
+        #[derive(Clone, Debug)]
+        pub struct Balance {
+            $($(#[doc = $doc])* pub $name: $ty,)*
+        }
+        impl Balance {
+            /// Parse a balance file, where each line is in the form `<name> <value>`. Every
+            /// name must be present exactly once.
+            fn parse(text: &str) -> Result<Self, String> {
+                $(let mut $name: Option<$ty> = None;)*
+                for (n, line) in text.lines().enumerate() {
+                    let line = line.split('#').next().unwrap_or("");
+                    let mut tokens = line.split_ascii_whitespace();
+                    let (key, value) = match (tokens.next(), tokens.next(), tokens.next()) {
+                        (None, _, _) => continue,
+                        (Some(key), Some(value), None) => (key, value),
+                        _ => return Err(format!("line {}: expected '<name> <value>'", n + 1)),
+                    };
+                    match key {
+                        $(stringify!($name) => {
+                            if $name.is_some() {
+                                return Err(format!("line {}: '{}' is repeated", n + 1, key));
+                            }
+                            $name = Some(value.parse().map_err(|_| {
+                                format!("line {}: invalid value '{}' for '{}'", n + 1, value, key)
+                            })?);
+                        })*
+                        _ => return Err(format!("line {}: unknown name '{}'", n + 1, key)),
+                    }
+                }
+                let balance = Self {
+                    $($name: $name.ok_or_else(|| format!("'{}' is missing", stringify!($name)))?,)*
+                };
+                balance.validate()?;
+                Ok(balance)
+            }
+        }
+    };
+}
+
+balance! {
+    /// The life at the start of a run, before the first level adds its own.
+    start_life: u32,
+    /// The number of tiles in the shorter side of the first level.
+    first_size: u8,
+    /// The longest a board can be, in relation to its shorter side. The board is stretched to
+    /// fill the screen, up to this ratio.
+    max_board_ratio: f32,
+    /// How many tiles the board grows in each dimension after a level is solved.
+    board_growth: u8,
+    /// The number of tiles in the shorter side of the board in the classic mode.
+    classic_size: u8,
+    /// The time before the water starts flowing in the classic mode, in seconds.
+    classic_countdown: f32,
+    /// The time the water takes to pass through a tile in the classic mode, in seconds.
+    classic_fill_time: f32,
+    time_base: f32,
+    time_per_tile: f32,
+    click_base: f32,
+    click_per_tile: f32,
+    /// The time before the first point of life drains, in seconds.
+    drain_delay: f32,
+    /// The time between each point of life drained, in seconds.
+    drain_interval: f32,
+    hint_cost: u32,
+    scramble_fraction: f32,
+    scramble_max_region: usize,
+    /// The total time of a run in the time attack mode, in seconds.
+    time_attack_time: u32,
+    /// The clicks given in the move limit mode, beyond the par of the level.
+    move_margin: u32,
+    move_hint_cost: u32,
+    /// The number of tiles in the shorter side of the board in the endless mode.
+    endless_size: u8,
+    endless_drain_factor: f32,
+    endless_min_drain: f32,
+    /// The life under which the music starts to speed up.
+    music_rush_life: u32,
+    /// The clicks left under which the music starts to speed up, in the move limit mode.
+    move_rush: u32,
+    /// The speed of the music when the life is almost over.
+    music_rush_speed: f32,
+    /// The life under which the player is warned that it is running out.
+    warning_life: u32,
+    /// The clicks left under which the player is warned, in the move limit mode.
+    move_warning: u32,
+    /// The chance of the growing tree generator branching from a random tile, instead of the
+    /// newest one.
+    maze_branchiness: f32,
+    /// The chance of each missing connection being added after the maze is built, making loops.
+    maze_loop_density: f32,
+    /// Connect each dead end to some neighbor after the maze is built, when loops are allowed.
+    maze_remove_dead_ends: bool,
+    /// The share of each kind of pipe the generator aims for. All zeros means no preference.
+    maze_weight_end: f32,
+    maze_weight_corner: f32,
+    maze_weight_straight: f32,
+    maze_weight_tee: f32,
+    maze_weight_cross: f32,
+    /// The maze algorithm of each game mode.
+    maze_standard: MazeChoice,
+    maze_zen: MazeChoice,
+    maze_time_attack: MazeChoice,
+    maze_move_limit: MazeChoice,
+    maze_endless: MazeChoice,
+}
+
+impl Default for Balance {
+    fn default() -> Self {
+        Self::parse(EMBEDDED).expect("the embedded balance file is invalid")
+    }
+}
+
+impl Balance {
+    /// Load the balance file, or the embedded one if it is missing or invalid.
+    pub fn load() -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Ok(text) = std::fs::read_to_string(path()) {
+            match Self::parse(&text) {
+                Ok(balance) => return balance,
+                Err(err) => eprintln!("invalid balance file '{}': {}", path().display(), err),
+            }
+        }
+        Self::default()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let checks = [
+            (
+                (2..=MAX_SIZE).contains(&self.first_size),
+                "first_size must be between 2 and 100",
+            ),
+            (
+                (1.0..=MAX_BOARD_RATIO).contains(&self.max_board_ratio),
+                "max_board_ratio must be between 1 and 2.5",
+            ),
+            (
+                self.board_growth <= MAX_SIZE,
+                "board_growth must be at most 100",
+            ),
+            (
+                (3..=MAX_SIZE).contains(&self.classic_size),
+                "classic_size must be between 3 and 100",
+            ),
+            (
+                self.classic_countdown >= 0.0,
+                "classic_countdown must not be negative",
+            ),
+            (
+                self.classic_fill_time > 0.0,
+                "classic_fill_time must be positive",
+            ),
+            (
+                self.time_base >= 0.0 && self.time_per_tile >= 0.0,
+                "the time must not be negative",
+            ),
+            (
+                self.click_base >= 0.0 && self.click_per_tile >= 0.0,
+                "the clicks must not be negative",
+            ),
+            (self.drain_delay >= 0.0, "drain_delay must not be negative"),
+            (self.drain_interval > 0.0, "drain_interval must be positive"),
+            (
+                (0.0..=1.0).contains(&self.scramble_fraction),
+                "scramble_fraction must be between 0 and 1",
+            ),
+            (
+                self.scramble_max_region >= 1,
+                "scramble_max_region must be at least 1",
+            ),
+            (
+                (2..=MAX_SIZE).contains(&self.endless_size),
+                "endless_size must be between 2 and 100",
+            ),
+            (
+                self.endless_drain_factor > 0.0 && self.endless_drain_factor <= 1.0,
+                "endless_drain_factor must be between 0 and 1",
+            ),
+            (
+                self.endless_min_drain > 0.0,
+                "endless_min_drain must be positive",
+            ),
+            (
+                self.music_rush_speed > 0.0,
+                "music_rush_speed must be positive",
+            ),
+            (
+                (0.0..=1.0).contains(&self.maze_branchiness),
+                "maze_branchiness must be between 0 and 1",
+            ),
+            (
+                (0.0..=1.0).contains(&self.maze_loop_density),
+                "maze_loop_density must be between 0 and 1",
+            ),
+            (
+                self.maze_kind_weights().iter().all(|&x| x >= 0.0),
+                "the maze weights must not be negative",
+            ),
+        ];
+        match checks.iter().find(|(ok, _)| !ok) {
+            Some((_, msg)) => Err(msg.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    /// The time a board with 'area' tiles is expected to take, in seconds.
+    pub fn par_time(&self, area: u32) -> f32 {
+        self.time_base + self.time_per_tile * area as f32
+    }
+
+    /// The life given at the start of a level with 'area' tiles: enough to drain for the
+    /// expected time, and to make the expected clicks.
+    pub fn level_life(&self, area: u32) -> i32 {
+        let expect_click = self.click_base + self.click_per_tile * area as f32;
+        (self.par_time(area) / self.drain_interval + expect_click) as i32
+    }
+
+    /// The time between each point of life drained in the 'level' of the endless mode.
+    pub fn endless_drain(&self, level: u32) -> f32 {
+        let factor = self
+            .endless_drain_factor
+            .powi(level.saturating_sub(1) as i32);
+        (self.drain_interval * factor).max(self.endless_min_drain)
+    }
+
+    fn maze_kind_weights(&self) -> [f32; 5] {
+        [
+            self.maze_weight_end,
+            self.maze_weight_corner,
+            self.maze_weight_straight,
+            self.maze_weight_tee,
+            self.maze_weight_cross,
+        ]
+    }
+
+    /// The maze algorithm chosen for 'mode', if it does not leave the choice to the rules.
+    pub fn maze_algorithm(&self, mode: GameMode) -> Option<Algorithm> {
+        let choice = match mode {
+            GameMode::Standard => self.maze_standard,
+            GameMode::Zen => self.maze_zen,
+            GameMode::TimeAttack => self.maze_time_attack,
+            GameMode::MoveLimit => self.maze_move_limit,
+            GameMode::Endless => self.maze_endless,
+        };
+        choice.0
+    }
+
+    /// The maze generator 'algorithm', shaped by the balance file.
+    pub fn maze_config(&self, algorithm: Algorithm) -> MazeConfig {
+        MazeConfig {
+            algorithm,
+            branchiness: self.maze_branchiness,
+            kind_weights: self.maze_kind_weights(),
+            remove_dead_ends: self.maze_remove_dead_ends,
+            loop_density: self.maze_loop_density,
+        }
+    }
+
+    pub fn scramble_config(&self) -> ScrambleConfig {
+        ScrambleConfig {
+            fraction: self.scramble_fraction,
+            max_region: self.scramble_max_region,
+        }
+    }
+}
+
+/// Reload the balance file when it changes on disk, so it can be tuned while the game runs.
+/// Only does something in debug builds, on native.
+#[derive(Default)]
+#[cfg_attr(
+    not(all(debug_assertions, not(target_arch = "wasm32"))),
+    allow(dead_code)
+)]
+pub struct Watcher {
+    modified: Option<std::time::SystemTime>,
+    /// The time until the file is checked again.
+    timer: f32,
+}
+impl Watcher {
+    #[allow(unused_variables)]
+    pub fn poll(&mut self, dt: f32, balance: &mut Balance) {
+        #[cfg(all(debug_assertions, not(target_arch = "wasm32")))]
+        {
+            self.timer -= dt;
+            if self.timer > 0.0 {
+                return;
+            }
+            self.timer = 0.5;
+            let path = path();
+            let modified = match std::fs::metadata(&path).and_then(|x| x.modified()) {
+                Ok(x) => x,
+                Err(_) => return,
+            };
+            match self.modified.replace(modified) {
+                Some(x) if x != modified => {}
+                // the first check only records the time
+                _ => return,
+            }
+            let text = match std::fs::read_to_string(&path) {
+                Ok(x) => x,
+                Err(_) => return,
+            };
+            match Balance::parse(&text) {
+                Ok(x) => {
+                    *balance = x;
+                    eprintln!("reloaded '{}'", path.display());
+                }
+                Err(err) => eprintln!("invalid balance file '{}': {}", path.display(), err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_are_bounded() {
+        let with = |name: &str, value: &str| {
+            let text = EMBEDDED
+                .lines()
+                .map(|line| match line.split_ascii_whitespace().next() {
+                    Some(x) if x == name => format!("{} {}", name, value),
+                    _ => line.to_string(),
+                })
+                .collect::<Vec<String>>()
+                .join("\n");
+            Balance::parse(&text)
+        };
+        assert!(with("board_growth", "100").is_ok());
+        assert!(with("board_growth", "101").is_err());
+        assert!(with("first_size", "101").is_err());
+        assert!(with("classic_size", "255").is_err());
+        assert!(with("endless_size", "200").is_err());
+        assert!(with("max_board_ratio", "3.0").is_err());
+        // the longest side of the biggest board still fits
+        assert!(MAX_SIZE as f32 * MAX_BOARD_RATIO <= u8::MAX as f32);
+    }
+
+    #[test]
+    fn each_mode_picks_its_maze() {
+        let text = EMBEDDED
+            .replace("maze_zen auto", "maze_zen backtracker")
+            .replace("maze_remove_dead_ends true", "maze_remove_dead_ends false");
+        let balance = Balance::parse(&text).unwrap();
+        assert_eq!(
+            balance.maze_algorithm(GameMode::Zen),
+            Some(Algorithm::Backtracker)
+        );
+        assert_eq!(balance.maze_algorithm(GameMode::Standard), None);
+        assert!(!balance.maze_config(Algorithm::Kruskal).remove_dead_ends);
+        assert!(Balance::parse(&EMBEDDED.replace("maze_zen auto", "maze_zen prim")).is_err());
+    }
+}
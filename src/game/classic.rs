@@ -0,0 +1,256 @@
+//! The classic Pipe Mania rules: the board starts empty, the player places pieces from a queue,
+//! and after a countdown the water starts to flow from a source, until it leaks.
+
+use super::{lerp, pipe_mask, Pipe};
+use rand::Rng;
+use std::collections::VecDeque;
+
+/// The pieces that can come in the queue, as (kind, dir): the two straights, the four corners
+/// and the cross.
+const PIECES: [(u8, u8); 7] = [(2, 0), (2, 1), (1, 0), (1, 1), (1, 2), (1, 3), (4, 0)];
+
+/// How many of the next pieces are visible to the player.
+pub const QUEUE_LEN: usize = 5;
+
+pub const DRY_COLOR: [u8; 4] = [200, 200, 200, 255];
+pub const WATER_COLOR: [u8; 4] = [0, 140, 255, 255];
+pub const SOURCE_COLOR: [u8; 4] = [0, 60, 255, 255];
+
+#[derive(Default)]
+pub struct Classic {
+    /// The next pieces, as (kind, dir). The front is the next one to be placed.
+    pub queue: VecDeque<(u8, u8)>,
+    pub source: usize,
+    /// The direction of the source outlet.
+    pub source_dir: u8,
+    /// The time until the water starts flowing.
+    pub countdown: f32,
+    /// The tile where the water is flowing now.
+    current: usize,
+    /// The connections of the current tile the water is passing through.
+    path: u8,
+    /// The direction in which the water leaves the current tile.
+    exit: u8,
+    /// How much of the current tile is already filled, from 0 to 1.
+    fill: f32,
+    /// The time the water takes to pass through a tile, in seconds.
+    fill_time: f32,
+    /// For each tile, the connections the water already passed through.
+    wet: Vec<u8>,
+    pub leaked: bool,
+}
+impl Classic {
+    /// Start a new board, with the source in a random position. The water starts flowing after
+    /// 'countdown' seconds, and takes 'fill_time' seconds to pass through each tile.
+    pub fn new<R: Rng>(width: u8, height: u8, countdown: f32, fill_time: f32, rng: &mut R) -> Self {
+        let mut queue = VecDeque::with_capacity(QUEUE_LEN);
+        for _ in 0..QUEUE_LEN {
+            queue.push_back(PIECES[rng.gen_range(0, PIECES.len())]);
+        }
+
+        // keep the source away from the borders, so it doesn't leak right away
+        let x = rng.gen_range(1, width as usize - 1);
+        let y = rng.gen_range(1, height as usize - 1);
+        let source = y * width as usize + x;
+        let exit = rng.gen_range(0, 4);
+
+        Self {
+            queue,
+            source,
+            source_dir: exit,
+            countdown,
+            current: source,
+            path: 1 << exit,
+            exit,
+            fill: 0.0,
+            fill_time,
+            wet: vec![0; width as usize * height as usize],
+            leaked: false,
+        }
+    }
+
+    /// Place the next piece of the queue in the tile 'i'. Return false if the tile cannot be
+    /// replaced, because it is the source or the water already passed through it.
+    pub fn place<R: Rng>(&mut self, i: usize, pipes: &mut [Pipe], size: f32, rng: &mut R) -> bool {
+        if i == self.source || i == self.current || self.wet[i] != 0 {
+            return false;
+        }
+        let (kind, dir) = self.queue.pop_front().unwrap();
+        self.queue.push_back(PIECES[rng.gen_range(0, PIECES.len())]);
+        pipes[i].set_kind(kind, dir, size);
+        pipes[i].set_rgba(DRY_COLOR);
+        true
+    }
+
+    /// Advance the water. Return the number of tiles that were completely filled, not counting
+    /// the source.
+    /// When the water has nowhere to go, `leaked` becomes true.
+    pub fn update(&mut self, dt: f32, pipes: &mut [Pipe], width: u8) -> u32 {
+        if self.leaked {
+            return 0;
+        }
+        if self.countdown > 0.0 {
+            self.countdown = (self.countdown - dt).max(0.0);
+            return 0;
+        }
+
+        self.fill = (self.fill + dt / self.fill_time).min(1.0);
+        if self.current != self.source && self.wet[self.current] == 0 {
+            let t = self.fill;
+            pipes[self.current].set_rgba([
+                lerp(t, DRY_COLOR[0] as f32, WATER_COLOR[0] as f32) as u8,
+                lerp(t, DRY_COLOR[1] as f32, WATER_COLOR[1] as f32) as u8,
+                lerp(t, DRY_COLOR[2] as f32, WATER_COLOR[2] as f32) as u8,
+                255,
+            ]);
+        }
+        if self.fill < 1.0 {
+            return 0;
+        }
+
+        self.wet[self.current] |= self.path;
+        let filled = (self.current != self.source) as u32;
+
+        let curr = self.current as i32;
+        let next = curr + [1, width as i32, -1, -(width as i32)][self.exit as usize];
+        let entry = (self.exit + 2) % 4;
+        let connected = (curr % width as i32 - next % width as i32).abs() <= 1
+            && next >= 0
+            && (next as usize) < pipes.len()
+            && pipe_mask(pipes[next as usize].kind, pipes[next as usize].dir) & (1 << entry) != 0;
+        if !connected {
+            self.leaked = true;
+            return filled;
+        }
+
+        let next = next as usize;
+        let mask = pipe_mask(pipes[next].kind, pipes[next].dir);
+        // go straight when possible, like in the cross, otherwise take the only other way
+        let exit = if mask & (1 << self.exit) != 0 {
+            Some(self.exit)
+        } else {
+            (0..4).find(|&d| d != entry && mask & (1 << d) != 0)
+        };
+        let exit = match exit {
+            Some(x) if next != self.source => x,
+            _ => {
+                self.leaked = true;
+                return filled;
+            }
+        };
+        let path = (1 << entry) | (1 << exit);
+        if self.wet[next] & path != 0 {
+            // the water can't pass twice through the same pipe
+            self.leaked = true;
+            return filled;
+        }
+
+        self.current = next;
+        self.path = path;
+        self.exit = exit;
+        self.fill = 0.0;
+        filled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::EMPTY;
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    const WIDTH: u8 = 5;
+
+    /// An empty board of 5x3 tiles, with the source in the tile 'source', pointing to 'exit'.
+    fn board(source: usize, exit: u8, rng: &mut SmallRng) -> (Classic, Vec<Pipe>) {
+        let mut classic = Classic::new(WIDTH, 3, 1.0, 0.5, rng);
+        classic.source = source;
+        classic.source_dir = exit;
+        classic.current = source;
+        classic.path = 1 << exit;
+        classic.exit = exit;
+        let mut pipes = (0..15)
+            .map(|_| Pipe::new(0.0, 0.0, 0.1, 0, EMPTY, 0))
+            .collect::<Vec<Pipe>>();
+        pipes[source].set_kind(0, exit, 0.1);
+        (classic, pipes)
+    }
+
+    /// Run the water for 'steps' updates of 0.5 seconds, returning the tiles filled.
+    fn run(classic: &mut Classic, pipes: &mut [Pipe], steps: usize) -> u32 {
+        (0..steps).map(|_| classic.update(0.5, pipes, WIDTH)).sum()
+    }
+
+    #[test]
+    fn water_flows_through_the_placed_pipes_and_leaks_at_the_end() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let (mut classic, mut pipes) = board(6, 0, &mut rng);
+        // two straights to the right of the source, and then nothing
+        classic.queue = vec![(2, 0); QUEUE_LEN].into();
+        assert!(classic.place(7, &mut pipes, 0.1, &mut rng));
+        assert!(classic.place(8, &mut pipes, 0.1, &mut rng));
+        assert_eq!(classic.queue.len(), QUEUE_LEN);
+        assert_eq!(pipes[8].kind, 2);
+
+        // nothing flows during the countdown
+        assert_eq!(run(&mut classic, &mut pipes, 2), 0);
+        assert_eq!(classic.countdown, 0.0);
+        assert!(!classic.leaked);
+
+        // the source fills, but does not count
+        assert_eq!(run(&mut classic, &mut pipes, 1), 0);
+        assert_eq!(run(&mut classic, &mut pipes, 1), 1);
+        assert_eq!(pipes[7].color, WATER_COLOR);
+        assert!(!classic.leaked);
+        // the last straight fills, and the water leaks into the empty tile
+        assert_eq!(run(&mut classic, &mut pipes, 1), 1);
+        assert!(classic.leaked);
+        assert_eq!(run(&mut classic, &mut pipes, 4), 0);
+    }
+
+    #[test]
+    fn wet_tiles_and_the_source_cannot_be_replaced() {
+        let mut rng = SmallRng::seed_from_u64(2);
+        let (mut classic, mut pipes) = board(6, 0, &mut rng);
+        classic.queue = vec![(2, 0); QUEUE_LEN].into();
+        assert!(!classic.place(6, &mut pipes, 0.1, &mut rng));
+        assert!(classic.place(7, &mut pipes, 0.1, &mut rng));
+        // the water is passing through the tile
+        run(&mut classic, &mut pipes, 3);
+        assert!(!classic.place(7, &mut pipes, 0.1, &mut rng));
+        // and then it has passed
+        run(&mut classic, &mut pipes, 1);
+        assert!(!classic.place(7, &mut pipes, 0.1, &mut rng));
+        assert!(classic.place(8, &mut pipes, 0.1, &mut rng));
+    }
+
+    #[test]
+    fn water_does_not_wrap_around_the_board() {
+        let mut rng = SmallRng::seed_from_u64(3);
+        // the source on the right edge, pointing out of the board
+        let (mut classic, mut pipes) = board(9, 0, &mut rng);
+        classic.queue = vec![(2, 0); QUEUE_LEN].into();
+        // a straight at the start of the next row would connect if the rows wrapped
+        assert!(classic.place(10, &mut pipes, 0.1, &mut rng));
+        assert_eq!(run(&mut classic, &mut pipes, 3), 0);
+        assert!(classic.leaked);
+    }
+
+    #[test]
+    fn water_cannot_flow_back_into_the_source() {
+        let mut rng = SmallRng::seed_from_u64(4);
+        let (mut classic, mut pipes) = board(6, 0, &mut rng);
+        // a loop of corners that turns back to the source from below
+        classic.queue = vec![(1, 1), (1, 2), (1, 3)].into();
+        assert!(classic.place(7, &mut pipes, 0.1, &mut rng));
+        assert!(classic.place(12, &mut pipes, 0.1, &mut rng));
+        assert!(classic.place(11, &mut pipes, 0.1, &mut rng));
+        assert_eq!(run(&mut classic, &mut pipes, 5), 2);
+        assert!(!classic.leaked);
+        // the last corner fills, and points up into the source
+        assert_eq!(classic.current, 11);
+        assert_eq!(run(&mut classic, &mut pipes, 1), 1);
+        assert!(classic.leaked);
+    }
+}
@@ -0,0 +1,339 @@
+//! Choose the colors of the regions of connected pipes, so that regions that touch each other
+//! get colors that are far apart, and a region keeps its color when it grows, shrinks or splits.
+
+/// The perceptual distance at which two colors are told apart easily. Any distance above it
+/// is as good as any other.
+const FAR: f32 = 0.2;
+
+/// A color in the Oklab space, where the euclidean distance follows how different two colors
+/// look.
+fn oklab(color: [u8; 4]) -> [f32; 3] {
+    let linear = |x: u8| {
+        let x = x as f32 / 255.0;
+        if x <= 0.04045 {
+            x / 12.92
+        } else {
+            ((x + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    let (r, g, b) = (linear(color[0]), linear(color[1]), linear(color[2]));
+    let l = (0.4122215 * r + 0.5363325 * g + 0.05144599 * b).cbrt();
+    let m = (0.2119035 * r + 0.6806995 * g + 0.107397 * b).cbrt();
+    let s = (0.08830246 * r + 0.2817188 * g + 0.6299787 * b).cbrt();
+    [
+        0.2104543 * l + 0.7936178 * m - 0.004072047 * s,
+        1.977998 * l - 2.428592 * m + 0.4505937 * s,
+        0.02590404 * l + 0.7827718 * m - 0.8086758 * s,
+    ]
+}
+
+fn lab_distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(a, b)| (a - b) * (a - b))
+        .sum::<f32>()
+        .sqrt()
+}
+
+/// Choose a color of 'palette' for each region in 'changed'.
+///
+/// 'regions' is the region of each tile of a board 'width' tiles wide, and 'colors' is the
+/// color each tile had. The regions that are not in 'changed' keep their colors. A changed
+/// region keeps the color most of its tiles had, unless it was taken by a larger changed
+/// region, or it is closer than another color to the colors of the regions it touches.
+///
+/// Return the region and its color, for each region of 'changed' that is on the board.
+pub fn assign(
+    regions: &[u16],
+    width: usize,
+    colors: &[u16],
+    changed: &[u16],
+    palette: &[[u8; 4]],
+) -> Vec<(u16, u16)> {
+    let lab = palette.iter().map(|&x| oklab(x)).collect::<Vec<_>>();
+    assign_by(regions, width, colors, changed, palette.len(), |a, b| {
+        lab_distance(lab[a as usize], lab[b as usize])
+    })
+}
+
+/// Choose one of 'count' markers for each region in 'changed', like `assign` does with the
+/// colors, so that regions that touch each other get different markers.
+pub fn assign_markers(
+    regions: &[u16],
+    width: usize,
+    markers: &[u16],
+    changed: &[u16],
+    count: usize,
+) -> Vec<(u16, u16)> {
+    assign_by(regions, width, markers, changed, count, |a, b| {
+        if a == b {
+            0.0
+        } else {
+            FAR
+        }
+    })
+}
+
+/// Choose one of 'count' colors for each region in 'changed', as told in `assign`, where
+/// 'distance' is how far apart two colors look.
+fn assign_by(
+    regions: &[u16],
+    width: usize,
+    colors: &[u16],
+    changed: &[u16],
+    count: usize,
+    distance: impl Fn(u16, u16) -> f32,
+) -> Vec<(u16, u16)> {
+    let len = regions.iter().copied().max().unwrap_or(0) as usize + 1;
+    let is_changed = |region: u16| changed.contains(&region);
+
+    let mut color: Vec<Option<u16>> = vec![None; len];
+    let mut size = vec![0usize; len];
+    // the number of tiles of each changed region that had each color
+    let mut votes = vec![Vec::new(); len];
+    for (i, &region) in regions.iter().enumerate() {
+        let c = colors[i] % count as u16;
+        if is_changed(region) {
+            size[region as usize] += 1;
+            let votes = &mut votes[region as usize];
+            match votes.iter_mut().find(|(x, _)| *x == c) {
+                Some((_, count)) => *count += 1,
+                None => votes.push((c, 1)),
+            }
+        } else {
+            color[region as usize] = Some(c);
+        }
+    }
+
+    let mut neighbors = vec![Vec::new(); len];
+    let mut touch = |a: u16, b: u16| {
+        if a != b && !neighbors[a as usize].contains(&b) {
+            neighbors[a as usize].push(b);
+            neighbors[b as usize].push(a);
+        }
+    };
+    for i in 0..regions.len() {
+        if (i + 1) % width != 0 && i + 1 < regions.len() {
+            touch(regions[i], regions[i + 1]);
+        }
+        if i + width < regions.len() {
+            touch(regions[i], regions[i + width]);
+        }
+    }
+
+    // the number of regions with each color, to spread the colors over the board
+    let mut usage = vec![0usize; count];
+    for &c in color.iter().flatten() {
+        usage[c as usize] += 1;
+    }
+
+    let mut order = changed
+        .iter()
+        .copied()
+        .filter(|&x| (x as usize) < len && size[x as usize] > 0)
+        .collect::<Vec<u16>>();
+    order.sort_unstable();
+    order.dedup();
+    // the larger regions keep their colors first
+    order.sort_by_key(|&x| std::cmp::Reverse(size[x as usize]));
+
+    let mut taken = Vec::new();
+    let mut result = Vec::with_capacity(order.len());
+    for region in order {
+        let near = neighbors[region as usize]
+            .iter()
+            .filter_map(|&x| color[x as usize])
+            .collect::<Vec<u16>>();
+        let spacing = |c: u16| near.iter().map(|&x| distance(c, x)).fold(FAR, f32::min);
+        let best = (0..count as u16)
+            .max_by(|&a, &b| {
+                spacing(a)
+                    .partial_cmp(&spacing(b))
+                    .unwrap()
+                    .then(usage[b as usize].cmp(&usage[a as usize]))
+                    .then(b.cmp(&a))
+            })
+            .unwrap();
+        let previous = votes[region as usize]
+            .iter()
+            .filter(|(c, _)| !taken.contains(c))
+            .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+            .map(|x| x.0);
+        let c = match previous {
+            Some(c) if spacing(c) >= spacing(best) => c,
+            _ => best,
+        };
+        taken.push(c);
+        usage[c as usize] += 1;
+        color[region as usize] = Some(c);
+        result.push((region, c));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// How different the colors 'a' and 'b' look, from 0, for the same color, to about 1, for
+    /// black and white.
+    fn distance(a: [u8; 4], b: [u8; 4]) -> f32 {
+        lab_distance(oklab(a), oklab(b))
+    }
+
+    const PALETTE: &[[u8; 4]] = &[
+        [255, 0, 0, 255],
+        [255, 40, 0, 255],
+        [0, 200, 0, 255],
+        [0, 220, 40, 255],
+        [0, 0, 255, 255],
+        [30, 0, 255, 255],
+        [255, 255, 0, 255],
+        [255, 255, 255, 255],
+    ];
+
+    /// The color of each tile, after applying the result of `assign` over 'colors'.
+    fn apply(regions: &[u16], colors: &[u16], assigned: &[(u16, u16)]) -> Vec<u16> {
+        regions
+            .iter()
+            .zip(colors.iter())
+            .map(|(r, &c)| assigned.iter().find(|x| x.0 == *r).map_or(c, |x| x.1))
+            .collect()
+    }
+
+    /// Check that every two tiles side by side, of different regions, have colors far apart.
+    fn assert_touching_apart(regions: &[u16], width: usize, colors: &[u16]) {
+        for i in 0..regions.len() {
+            let mut next = vec![i + width];
+            if (i + 1) % width != 0 {
+                next.push(i + 1);
+            }
+            for j in next.into_iter().filter(|&j| j < regions.len()) {
+                if regions[i] != regions[j] {
+                    let d = distance(PALETTE[colors[i] as usize], PALETTE[colors[j] as usize]);
+                    assert!(d >= FAR, "tiles {} and {} are too close: {}", i, j, d);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn distance_follows_perception() {
+        assert!(distance([0, 0, 0, 255], [0, 0, 0, 255]) < 1e-6);
+        assert!((distance([0, 0, 0, 255], [255, 255, 255, 255]) - 1.0).abs() < 0.01);
+        let red = distance(PALETTE[0], PALETTE[1]);
+        assert!(red < FAR);
+        assert!(distance(PALETTE[0], PALETTE[4]) > red);
+    }
+
+    #[test]
+    fn new_board_has_touching_regions_apart() {
+        // each tile its own region, in a grid with no pipes connected
+        let (width, height) = (7, 5);
+        let regions = (1..=(width * height) as u16).collect::<Vec<u16>>();
+        let colors = vec![0; regions.len()];
+        let assigned = assign(&regions, width, &colors, &regions, PALETTE);
+        assert_eq!(assigned.len(), regions.len());
+        let colors = apply(&regions, &colors, &assigned);
+        assert_touching_apart(&regions, width, &colors);
+        // and all colors are used
+        for c in 0..PALETTE.len() as u16 {
+            assert!(colors.contains(&c));
+        }
+    }
+
+    #[test]
+    fn merged_region_keeps_the_color_of_the_larger() {
+        #[rustfmt::skip]
+        let before = [
+            1, 1, 1, 2,
+            3, 3, 3, 2,
+            4, 4, 4, 4,
+        ];
+        let colors = [0, 0, 0, 4, 2, 2, 2, 4, 7, 7, 7, 7];
+        let (regions, colors) = (before, colors.to_vec());
+        assert_touching_apart(&regions, 4, &colors);
+
+        // the region 2 joins the region 1
+        #[rustfmt::skip]
+        let after = [
+            1, 1, 1, 1,
+            3, 3, 3, 1,
+            4, 4, 4, 4,
+        ];
+        let assigned = assign(&after, 4, &colors, &[1, 2], PALETTE);
+        assert_eq!(assigned, vec![(1, 0)]);
+        let colors = apply(&after, &colors, &assigned);
+        assert_touching_apart(&after, 4, &colors);
+    }
+
+    #[test]
+    fn split_region_keeps_the_color_in_the_larger_part() {
+        let colors = vec![4, 4, 4, 4, 4, 7, 7, 7, 7, 7];
+        // the region 1, in the first 5 tiles, splits in the left 3 tiles and the right 2
+        let after = [1, 1, 1, 5, 5, 2, 2, 2, 2, 2];
+        let assigned = assign(&after, 10, &colors, &[1, 5], PALETTE);
+        assert_eq!(assigned[0], (1, 4));
+        assert_eq!(assigned[1].0, 5);
+        assert_ne!(assigned[1].1, 4);
+        let colors = apply(&after, &colors, &assigned);
+        assert_touching_apart(&after, 10, &colors);
+    }
+
+    #[test]
+    fn split_parts_apart_do_not_share_a_color() {
+        // the two parts of the split region do not touch, but still get different colors
+        let after = [1, 1, 2, 5, 5];
+        let colors = vec![6, 6, 2, 6, 6];
+        let assigned = assign(&after, 5, &colors, &[1, 5], PALETTE);
+        assert_eq!(assigned.len(), 2);
+        assert_ne!(assigned[0].1, assigned[1].1);
+    }
+
+    #[test]
+    fn region_moves_away_from_a_close_neighbor() {
+        // the region 2 kept the color 1, but now it touches the region 1, of a similar red
+        #[rustfmt::skip]
+        let regions = [
+            1, 1, 2,
+            1, 3, 2,
+            3, 3, 2,
+        ];
+        let colors = vec![0, 0, 1, 0, 2, 1, 2, 2, 1];
+        let assigned = assign(&regions, 3, &colors, &[2], PALETTE);
+        assert_eq!(assigned.len(), 1);
+        assert_ne!(assigned[0].1, 1);
+        let colors = apply(&regions, &colors, &assigned);
+        assert_touching_apart(&regions, 3, &colors);
+    }
+
+    #[test]
+    fn touching_regions_get_different_markers() {
+        // more regions than markers, with the markers taken from the color before
+        let (width, height) = (6, 4);
+        let regions = (1..=(width * height) as u16).collect::<Vec<u16>>();
+        let markers = regions.iter().map(|&x| x % 8).collect::<Vec<u16>>();
+        let assigned = assign_markers(&regions, width, &markers, &regions, 8);
+        let markers = apply(&regions, &markers, &assigned);
+        for i in 0..regions.len() {
+            if (i + 1) % width != 0 {
+                assert_ne!(markers[i], markers[i + 1]);
+            }
+            if i + width < regions.len() {
+                assert_ne!(markers[i], markers[i + width]);
+            }
+        }
+    }
+
+    #[test]
+    fn unchanged_regions_keep_their_colors() {
+        let regions = [1, 1, 2, 2, 3, 3];
+        let colors = vec![0, 0, 0, 0, 4, 4];
+        let assigned = assign(&regions, 2, &colors, &[2], PALETTE);
+        // the region 2 must leave the color of the region 1, that is fixed
+        assert_eq!(assigned.len(), 1);
+        assert_eq!(assigned[0].0, 2);
+        assert!(distance(PALETTE[assigned[0].1 as usize], PALETTE[0]) >= FAR);
+        assert!(distance(PALETTE[assigned[0].1 as usize], PALETTE[4]) >= FAR);
+    }
+}
@@ -0,0 +1,140 @@
+use winit::event::VirtualKeyCode;
+
+/// Something the player can do to the tile under the cursor.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    RotateClockwise,
+    RotateCounterClockwise,
+    Lock,
+    Undo,
+    Hint,
+    None,
+}
+impl Action {
+    fn name(self) -> &'static str {
+        match self {
+            Action::RotateClockwise => "rotate_cw",
+            Action::RotateCounterClockwise => "rotate_ccw",
+            Action::Lock => "lock",
+            Action::Undo => "undo",
+            Action::Hint => "hint",
+            Action::None => "none",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "rotate_cw" => Action::RotateClockwise,
+            "rotate_ccw" => Action::RotateCounterClockwise,
+            "lock" => Action::Lock,
+            "undo" => Action::Undo,
+            "hint" => Action::Hint,
+            "none" => Action::None,
+            _ => return None,
+        })
+    }
+}
+
+/// Maps mouse buttons, the mouse wheel and keyboard keys to actions.
+#[derive(Clone, PartialEq, Debug)]
+pub struct InputMap {
+    pub mouse_left: Action,
+    pub mouse_right: Action,
+    pub mouse_middle: Action,
+    pub wheel_up: Action,
+    pub wheel_down: Action,
+    pub keys: Vec<(VirtualKeyCode, Action)>,
+}
+impl Default for InputMap {
+    fn default() -> Self {
+        Self {
+            mouse_left: Action::RotateCounterClockwise,
+            mouse_right: Action::RotateClockwise,
+            mouse_middle: Action::Lock,
+            wheel_up: Action::RotateClockwise,
+            wheel_down: Action::RotateCounterClockwise,
+            keys: vec![
+                (VirtualKeyCode::E, Action::RotateClockwise),
+                (VirtualKeyCode::Q, Action::RotateCounterClockwise),
+                (VirtualKeyCode::Space, Action::Lock),
+                (VirtualKeyCode::Z, Action::Undo),
+                (VirtualKeyCode::Back, Action::Undo),
+                (VirtualKeyCode::H, Action::Hint),
+            ],
+        }
+    }
+}
+impl InputMap {
+    pub fn key_action(&self, key: VirtualKeyCode) -> Action {
+        self.keys
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map_or(Action::None, |(_, a)| *a)
+    }
+
+    /// Parse a single 'input' line of the settings file, in the form
+    /// `<button or key> <action>`, like `mouse_left rotate_ccw` or `key_E rotate_cw`.
+    /// Return false if the line is malformed.
+    pub fn parse_line(&mut self, input: &str, action: &str) -> bool {
+        let action = match Action::from_name(action) {
+            Some(x) => x,
+            None => return false,
+        };
+        match input {
+            "mouse_left" => self.mouse_left = action,
+            "mouse_right" => self.mouse_right = action,
+            "mouse_middle" => self.mouse_middle = action,
+            "wheel_up" => self.wheel_up = action,
+            "wheel_down" => self.wheel_down = action,
+            _ => {
+                let key = match input.strip_prefix("key_").and_then(key_from_name) {
+                    Some(x) => x,
+                    None => return false,
+                };
+                self.keys.retain(|(k, _)| *k != key);
+                if action != Action::None {
+                    self.keys.push((key, action));
+                }
+            }
+        }
+        true
+    }
+
+    /// Write the map in the format read by `parse_line`, one binding per line,
+    /// each one prefixed by `prefix`.
+    pub fn write(&self, prefix: &str, out: &mut String) {
+        let buttons = [
+            ("mouse_left", self.mouse_left),
+            ("mouse_right", self.mouse_right),
+            ("mouse_middle", self.mouse_middle),
+            ("wheel_up", self.wheel_up),
+            ("wheel_down", self.wheel_down),
+        ];
+        for (name, action) in buttons.iter() {
+            out.push_str(&format!("{} {} {}\n", prefix, name, action.name()));
+        }
+        for (key, action) in self.keys.iter() {
+            out.push_str(&format!("{} key_{:?} {}\n", prefix, key, action.name()));
+        }
+    }
+}
+
+macro_rules! key_names {
+    ($($key:ident),* $(,)?) => {
+        fn key_from_name(name: &str) -> Option<VirtualKeyCode> {
+            match name {
+                $(stringify!($key) => Some(VirtualKeyCode::$key),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+// Only the keys that make sense to bind are listed here.
+key_names!(
+    A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z, Key0, Key1, Key2,
+    Key3, Key4, Key5, Key6, Key7, Key8, Key9, Numpad0, Numpad1, Numpad2, Numpad3, Numpad4, Numpad5,
+    Numpad6, Numpad7, Numpad8, Numpad9, Space, Return, Back, Tab, Delete, Insert, Home, End,
+    PageUp, PageDown, Left, Right, Up, Down, LShift, RShift, LControl, RControl, LAlt, RAlt, Comma,
+    Period, Slash, Semicolon, Minus, Equals, F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+);
@@ -0,0 +1,127 @@
+//! The text shown to the player in each language, from the catalogs in `res/lang`.
+
+/// The catalog of each language, by its code. The messages missing in a catalog are taken from
+/// the first one.
+static CATALOGS: &[(&str, &str)] = &[
+    ("en", include_str!("../../res/lang/en.txt")),
+    ("pt", include_str!("../../res/lang/pt.txt")),
+    ("es", include_str!("../../res/lang/es.txt")),
+];
+
+/// Parse a catalog, where each line is in the form `<key> <text>`.
+fn parse(text: &'static str) -> Vec<(&'static str, String)> {
+    let mut messages = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, message) = line.split_once(' ').unwrap_or((line, ""));
+        messages.push((key, message.trim().replace("\\n", "\n")));
+    }
+    messages
+}
+
+/// The codes of all languages, starting with English.
+pub fn codes() -> impl Iterator<Item = &'static str> {
+    CATALOGS.iter().map(|x| x.0)
+}
+
+/// The language of the system, if there is a catalog for it.
+pub fn system_language() -> Option<&'static str> {
+    #[cfg(not(target_arch = "wasm32"))]
+    let tag = ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .find_map(|x| std::env::var(x).ok().filter(|x| !x.is_empty()));
+    #[cfg(target_arch = "wasm32")]
+    let tag = web_sys::window().and_then(|x| x.navigator().language());
+    // a tag like 'pt-BR' or 'pt_BR.UTF-8'
+    let code = tag?.get(..2)?.to_ascii_lowercase();
+    codes().find(|&x| x == code)
+}
+
+pub struct Locale {
+    code: &'static str,
+    messages: Vec<(&'static str, String)>,
+    fallback: Vec<(&'static str, String)>,
+}
+impl Locale {
+    /// The messages of the language 'code', or of English if there is no catalog for it.
+    pub fn new(code: &str) -> Self {
+        let (code, text) = CATALOGS
+            .iter()
+            .find(|x| x.0 == code)
+            .unwrap_or(&CATALOGS[0]);
+        Self {
+            code,
+            messages: parse(text),
+            fallback: parse(CATALOGS[0].1),
+        }
+    }
+
+    /// The code of the language after this one, to switch between them.
+    pub fn next_code(&self) -> &'static str {
+        let i = CATALOGS.iter().position(|x| x.0 == self.code).unwrap_or(0);
+        CATALOGS[(i + 1) % CATALOGS.len()].0
+    }
+
+    /// The message 'key' in this language, in English if it is missing, or the key itself if it
+    /// is missing in English too.
+    pub fn get<'a>(&'a self, key: &'a str) -> &'a str {
+        self.messages
+            .iter()
+            .chain(self.fallback.iter())
+            .find(|x| x.0 == key)
+            .map_or(key, |x| x.1.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catalogs_have_the_same_keys() {
+        let keys = |text| parse(text).into_iter().map(|x| x.0).collect::<Vec<_>>();
+        let english = keys(CATALOGS[0].1);
+        for (code, text) in CATALOGS.iter() {
+            let mut keys = keys(text);
+            keys.sort_unstable();
+            let len = keys.len();
+            keys.dedup();
+            assert_eq!(keys.len(), len, "'{}' has a repeated key", code);
+            for key in english.iter() {
+                assert!(keys.contains(key), "'{}' has no '{}'", code, key);
+            }
+            for key in keys.iter() {
+                assert!(
+                    english.contains(key),
+                    "'{}' has the unknown '{}'",
+                    code,
+                    key
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn missing_messages_fall_back() {
+        let locale = Locale::new("pt");
+        assert_eq!(locale.get("play_again"), "JOGAR DE NOVO");
+        assert_eq!(locale.get("you_lose"), "VOCÊ\nPERDEU");
+        assert_eq!(locale.get("no_such_key"), "no_such_key");
+        let locale = Locale::new("xx");
+        assert_eq!(locale.code, "en");
+        assert_eq!(locale.get("start"), "START");
+        assert_eq!(Locale::new("es").next_code(), "en");
+    }
+
+    #[test]
+    fn themes_have_names() {
+        let locale = Locale::new("en");
+        for name in super::super::theme::Theme::names() {
+            let key = format!("theme.{}", name);
+            assert_ne!(locale.get(&key), key, "the theme '{}' has no name", name);
+        }
+    }
+}
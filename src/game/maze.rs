@@ -0,0 +1,488 @@
+//! Generation of the puzzles. A maze is a spanning tree of the grid, optionally with some extra
+//! connections. Each cell is a bitmask of its connections to the right, down, left and up.
+
+use rand::seq::{index::sample, SliceRandom};
+use rand::Rng;
+
+/// The algorithm used to build the spanning tree.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Algorithm {
+    /// Grow the tree from random cells of the tree (Prim-style), or from the newest one, as
+    /// controlled by `MazeConfig::branchiness`.
+    GrowingTree,
+    /// Loop-erased random walks, giving a uniform spanning tree. Many short branches.
+    Wilson,
+    /// Join the cells through random walls, when they are not already connected. Very bushy.
+    Kruskal,
+    /// Depth-first search. Long winding corridors with few branches.
+    Backtracker,
+}
+impl Algorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::GrowingTree => "growing_tree",
+            Algorithm::Wilson => "wilson",
+            Algorithm::Kruskal => "kruskal",
+            Algorithm::Backtracker => "backtracker",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "growing_tree" => Algorithm::GrowingTree,
+            "wilson" => Algorithm::Wilson,
+            "kruskal" => Algorithm::Kruskal,
+            "backtracker" => Algorithm::Backtracker,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MazeConfig {
+    pub algorithm: Algorithm,
+    /// For the growing tree, the chance of growing from a random cell instead of the newest
+    /// one. 1.0 grows like Prim's algorithm, 0.0 like the backtracker.
+    pub branchiness: f32,
+    /// The target share of each tile kind, in the order of `atlas::PIPES`. The generator prefers
+    /// connections that bring the board closer to it. All zeros means no preference. Wilson's
+    /// algorithm ignores it when building the tree, to keep it uniform.
+    pub kind_weights: [f32; 5],
+    /// Connect each dead end to some neighbor, after the tree is built.
+    pub remove_dead_ends: bool,
+    /// The chance of each missing connection being added after the tree is built, creating
+    /// loops.
+    pub loop_density: f32,
+}
+impl Default for MazeConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::GrowingTree,
+            branchiness: 1.0,
+            kind_weights: [0.0; 5],
+            remove_dead_ends: true,
+            loop_density: 0.0,
+        }
+    }
+}
+impl MazeConfig {
+    /// The configuration for puzzles whose solution must be a tree.
+    pub fn tree(&self) -> Self {
+        Self {
+            remove_dead_ends: false,
+            loop_density: 0.0,
+            ..self.clone()
+        }
+    }
+}
+
+/// The kind of a cell, in the order of `atlas::PIPES`, from its connections.
+pub fn kind_of(cell: i8) -> usize {
+    match cell.count_ones() {
+        1 => 0,
+        2 if cell == 0b0101 || cell == 0b1010 => 2,
+        2 => 1,
+        3 => 3,
+        4 => 4,
+        _ => 5,
+    }
+}
+
+/// Keeps the count of each kind, to steer the generation to the target share of kinds.
+struct KindBalance {
+    target: [f32; 5],
+    count: [u32; 6],
+    total: u32,
+}
+impl KindBalance {
+    fn new(config: &MazeConfig, len: usize) -> Self {
+        let sum: f32 = config.kind_weights.iter().sum();
+        let mut target = [0.0; 5];
+        if sum > 0.0 {
+            for (t, w) in target.iter_mut().zip(config.kind_weights.iter()) {
+                *t = w / sum;
+            }
+        }
+        let mut count = [0; 6];
+        count[5] = len as u32;
+        Self {
+            target,
+            count,
+            total: len as u32,
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.target.iter().any(|&x| x > 0.0)
+    }
+
+    /// How much a cell would be wanted after changing from `old` to `new`. The result is in
+    /// [0, 2], with 1 meaning neutral.
+    fn desire(&self, old: i8, new: i8) -> f32 {
+        if !self.is_active() {
+            return 1.0;
+        }
+        let kind = kind_of(new);
+        if kind >= 5 {
+            return 1.0;
+        }
+        let share = self.count[kind] as f32 / self.total as f32;
+        let freed = if kind_of(old) < 5 {
+            self.target[kind_of(old)] - self.count[kind_of(old)] as f32 / self.total as f32
+        } else {
+            0.0
+        };
+        (1.0 + 4.0 * (self.target[kind] - share) - 2.0 * freed).clamp(0.0, 2.0)
+    }
+
+    fn change(&mut self, old: i8, new: i8) {
+        self.count[kind_of(old)] -= 1;
+        self.count[kind_of(new)] += 1;
+    }
+}
+
+struct Grid<'a, R: Rng> {
+    width: usize,
+    cells: Box<[i8]>,
+    balance: KindBalance,
+    rng: &'a mut R,
+}
+impl<'a, R: Rng> Grid<'a, R> {
+    fn neighbor(&self, i: usize, dir: usize) -> Option<usize> {
+        let (x, y) = (i % self.width, i / self.width);
+        let height = self.cells.len() / self.width;
+        match dir {
+            0 if x + 1 < self.width => Some(i + 1),
+            1 if y + 1 < height => Some(i + self.width),
+            2 if x > 0 => Some(i - 1),
+            3 if y > 0 => Some(i - self.width),
+            _ => None,
+        }
+    }
+
+    fn connect(&mut self, curr: usize, dir: usize, next: usize) {
+        let (a, b) = (self.cells[curr], self.cells[next]);
+        self.cells[curr] |= 1 << dir; // set dir bitmask
+        self.cells[next] |= 1 << ((dir + 2) % 4); // set dir bitmask
+        self.balance.change(a, self.cells[curr]);
+        self.balance.change(b, self.cells[next]);
+    }
+
+    /// Chance of accepting a new connection, given the target share of kinds.
+    fn accept(&mut self, curr: usize, dir: usize) -> bool {
+        if !self.balance.is_active() {
+            return true;
+        }
+        let cell = self.cells[curr];
+        // never reject a connection completely, or the generation could get stuck
+        let p = (self.balance.desire(cell, cell | 1 << dir) / 2.0).max(0.1);
+        self.rng.gen::<f32>() < p
+    }
+
+    /// Grow the tree from the cells in the frontier. Is Prim-style when branchiness is 1.0,
+    /// and a depth-first search when it is 0.0.
+    fn growing_tree(&mut self, branchiness: f32) {
+        let len = self.cells.len();
+        let start = self.rng.gen_range(0, len);
+        let mut path: Vec<usize> = vec![start];
+        let mut in_tree = vec![false; len];
+        in_tree[start] = true;
+
+        'path: while !path.is_empty() {
+            let r = if self.rng.gen::<f32>() < branchiness {
+                self.rng.gen_range(0, path.len())
+            } else {
+                path.len() - 1
+            };
+            let curr = path[r];
+            let mut has_unexplored = false;
+            for i in sample(self.rng, 4, 4).iter() {
+                if let Some(next) = self.neighbor(curr, i) {
+                    if !in_tree[next] {
+                        // if it is inexpored
+                        has_unexplored = true;
+                        if !self.accept(curr, i) {
+                            continue;
+                        }
+                        self.connect(curr, i, next);
+                        in_tree[next] = true;
+                        path.push(next); // add to the backtrack path
+                        continue 'path;
+                    }
+                }
+            }
+            // If there is no neighbor inexpored, backtrack
+            if !has_unexplored {
+                path.remove(r);
+            }
+        }
+    }
+
+    /// Wilson's algorithm: random walks from each cell until reaching the tree, erasing the
+    /// loops of the walk.
+    fn wilson(&mut self) {
+        let len = self.cells.len();
+        let mut in_tree = vec![false; len];
+        in_tree[self.rng.gen_range(0, len)] = true;
+        // the last direction taken when leaving each cell in the current walk
+        let mut walk_dir = vec![0usize; len];
+        let mut order = (0..len).collect::<Vec<usize>>();
+        order.shuffle(self.rng);
+        for start in order {
+            let mut curr = start;
+            while !in_tree[curr] {
+                let dir = self.rng.gen_range(0, 4);
+                if let Some(next) = self.neighbor(curr, dir) {
+                    // overwriting the direction erases any loop in the walk
+                    walk_dir[curr] = dir;
+                    curr = next;
+                }
+            }
+            let mut curr = start;
+            while !in_tree[curr] {
+                let dir = walk_dir[curr];
+                let next = self.neighbor(curr, dir).unwrap();
+                self.connect(curr, dir, next);
+                in_tree[curr] = true;
+                curr = next;
+            }
+        }
+    }
+
+    /// Kruskal's algorithm: go through the walls in random order, and remove the ones that
+    /// separate cells that are not connected yet.
+    fn kruskal(&mut self) {
+        let len = self.cells.len();
+        let mut edges = Vec::with_capacity(len * 2);
+        for i in 0..len {
+            for dir in 0..2 {
+                if let Some(next) = self.neighbor(i, dir) {
+                    edges.push((i, dir, next));
+                }
+            }
+        }
+        edges.shuffle(self.rng);
+
+        fn root(parent: &mut [usize], mut i: usize) -> usize {
+            while parent[i] != i {
+                parent[i] = parent[parent[i]];
+                i = parent[i];
+            }
+            i
+        }
+        let mut parent = (0..len).collect::<Vec<usize>>();
+        // the edges rejected by the kind balance get a second chance, to complete the tree
+        let mut rejected = Vec::new();
+        for (curr, dir, next) in edges {
+            let (a, b) = (root(&mut parent, curr), root(&mut parent, next));
+            if a != b {
+                if !self.accept(curr, dir) {
+                    rejected.push((curr, dir, next));
+                    continue;
+                }
+                parent[a] = b;
+                self.connect(curr, dir, next);
+            }
+        }
+        for (curr, dir, next) in rejected {
+            let (a, b) = (root(&mut parent, curr), root(&mut parent, next));
+            if a != b {
+                parent[a] = b;
+                self.connect(curr, dir, next);
+            }
+        }
+    }
+
+    fn remove_dead_ends(&mut self) {
+        for curr in 0..self.cells.len() {
+            if self.cells[curr].count_ones() == 1 {
+                for i in sample(self.rng, 4, 4).iter() {
+                    if let Some(next) = self.neighbor(curr, i) {
+                        if self.cells[curr] & (1 << i) == 0 {
+                            self.connect(curr, i, next);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Add random connections, preferring the ones that move the kinds to the target share.
+    fn add_loops(&mut self, density: f32) {
+        for curr in 0..self.cells.len() {
+            for dir in 0..2 {
+                let next = match self.neighbor(curr, dir) {
+                    Some(x) if self.cells[curr] & (1 << dir) == 0 => x,
+                    _ => continue,
+                };
+                let (a, b) = (self.cells[curr], self.cells[next]);
+                let desire = (self.balance.desire(a, a | 1 << dir)
+                    + self.balance.desire(b, b | 1 << ((dir + 2) % 4)))
+                    / 2.0;
+                if self.rng.gen::<f32>() < density * desire {
+                    self.connect(curr, dir, next);
+                }
+            }
+        }
+    }
+}
+
+pub fn gen_maze<R: Rng>(width: u8, height: u8, config: &MazeConfig, rng: &mut R) -> Box<[i8]> {
+    let len = width as usize * height as usize;
+    let mut grid = Grid {
+        width: width as usize,
+        cells: vec![0i8; len].into_boxed_slice(),
+        balance: KindBalance::new(config, len),
+        rng,
+    };
+
+    match config.algorithm {
+        Algorithm::GrowingTree => grid.growing_tree(config.branchiness),
+        Algorithm::Wilson => grid.wilson(),
+        Algorithm::Kruskal => grid.kruskal(),
+        Algorithm::Backtracker => grid.growing_tree(0.0),
+    }
+    if config.remove_dead_ends {
+        grid.remove_dead_ends();
+    }
+    if config.loop_density > 0.0 {
+        grid.add_loops(config.loop_density);
+    }
+
+    grid.cells
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    const ALGORITHMS: [Algorithm; 4] = [
+        Algorithm::GrowingTree,
+        Algorithm::Wilson,
+        Algorithm::Kruskal,
+        Algorithm::Backtracker,
+    ];
+
+    /// The number of connections between cells, checking that both sides of each one agree.
+    fn count_edges(cells: &[i8], width: usize) -> usize {
+        let height = cells.len() / width;
+        let mut edges = 0;
+        for (i, &cell) in cells.iter().enumerate() {
+            let (x, y) = (i % width, i / width);
+            if cell & 0b0001 != 0 {
+                assert!(x + 1 < width, "cell {} connects out of the board", i);
+                assert!(
+                    cells[i + 1] & 0b0100 != 0,
+                    "cell {} is connected one way",
+                    i
+                );
+                edges += 1;
+            }
+            if cell & 0b0010 != 0 {
+                assert!(y + 1 < height, "cell {} connects out of the board", i);
+                assert!(
+                    cells[i + width] & 0b1000 != 0,
+                    "cell {} is connected one way",
+                    i
+                );
+                edges += 1;
+            }
+            assert!(x > 0 || cell & 0b0100 == 0);
+            assert!(y > 0 || cell & 0b1000 == 0);
+        }
+        edges
+    }
+
+    /// The number of cells reached from the first one.
+    fn count_reached(cells: &[i8], width: usize) -> usize {
+        let mut reached = vec![false; cells.len()];
+        let mut stack = vec![0];
+        reached[0] = true;
+        while let Some(i) = stack.pop() {
+            let next = [i + 1, i + width, i.wrapping_sub(1), i.wrapping_sub(width)];
+            for (dir, &j) in next.iter().enumerate() {
+                if cells[i] & (1 << dir) != 0 && !reached[j] {
+                    reached[j] = true;
+                    stack.push(j);
+                }
+            }
+        }
+        reached.iter().filter(|&&x| x).count()
+    }
+
+    #[test]
+    fn algorithms_build_spanning_trees() {
+        let (width, height) = (9, 6);
+        for &algorithm in ALGORITHMS.iter() {
+            let config = MazeConfig {
+                algorithm,
+                remove_dead_ends: false,
+                ..MazeConfig::default()
+            };
+            for seed in 0..10 {
+                let mut rng = SmallRng::seed_from_u64(seed);
+                let cells = gen_maze(width as u8, height as u8, &config, &mut rng);
+                // connected, and with one edge less than cells, so without loops
+                assert_eq!(count_reached(&cells, width), cells.len(), "{:?}", algorithm);
+                assert_eq!(
+                    count_edges(&cells, width),
+                    cells.len() - 1,
+                    "{:?}",
+                    algorithm
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn loops_add_connections() {
+        let config = MazeConfig {
+            remove_dead_ends: false,
+            loop_density: 0.5,
+            ..MazeConfig::default()
+        };
+        let mut rng = SmallRng::seed_from_u64(1);
+        let cells = gen_maze(8, 8, &config, &mut rng);
+        assert_eq!(count_reached(&cells, 8), cells.len());
+        assert!(count_edges(&cells, 8) > cells.len() - 1);
+        // a tree has none, even with the density set
+        let cells = gen_maze(8, 8, &config.tree(), &mut rng);
+        assert_eq!(count_edges(&cells, 8), cells.len() - 1);
+    }
+
+    #[test]
+    fn weights_shift_the_kinds() {
+        // the share of each kind over many boards
+        let share = |config: &MazeConfig| {
+            let mut count = [0usize; 6];
+            let mut rng = SmallRng::seed_from_u64(7);
+            for _ in 0..20 {
+                for &cell in gen_maze(10, 10, config, &mut rng).iter() {
+                    count[kind_of(cell)] += 1;
+                }
+            }
+            count.map(|x| x as f32 / 2000.0)
+        };
+        for &algorithm in [Algorithm::GrowingTree, Algorithm::Kruskal].iter() {
+            let plain = MazeConfig {
+                algorithm,
+                remove_dead_ends: false,
+                ..MazeConfig::default()
+            };
+            let straight = MazeConfig {
+                kind_weights: [0.1, 0.1, 1.0, 0.1, 0.0],
+                ..plain.clone()
+            };
+            let tee = MazeConfig {
+                kind_weights: [0.2, 0.1, 0.1, 1.0, 0.1],
+                ..plain.clone()
+            };
+            let (plain, straight, tee) = (share(&plain), share(&straight), share(&tee));
+            assert!(straight[2] > plain[2] + 0.02, "{:?}", algorithm);
+            assert!(tee[3] > plain[3] + 0.02, "{:?}", algorithm);
+        }
+    }
+}
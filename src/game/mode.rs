@@ -0,0 +1,68 @@
+//! The game modes, which decide how the player can lose a run.
+
+use super::atlas;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GameMode {
+    /// Clicks and time both drain life, and each level gives more.
+    Standard,
+    /// No timer and no life.
+    Zen,
+    /// A fixed total time, to solve as many levels as possible.
+    TimeAttack,
+    /// Each level has a budget of clicks, a little bigger than its par.
+    MoveLimit,
+    /// The board does not grow, but the time drains faster each level.
+    Endless,
+}
+impl GameMode {
+    pub const ALL: [GameMode; 5] = [
+        GameMode::Standard,
+        GameMode::Zen,
+        GameMode::TimeAttack,
+        GameMode::MoveLimit,
+        GameMode::Endless,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            GameMode::Standard => "standard",
+            GameMode::Zen => "zen",
+            GameMode::TimeAttack => "time_attack",
+            GameMode::MoveLimit => "move_limit",
+            GameMode::Endless => "endless",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|x| x.name() == name)
+    }
+
+    pub fn next(self) -> Self {
+        let i = Self::ALL.iter().position(|&x| x == self).unwrap();
+        Self::ALL[(i + 1) % Self::ALL.len()]
+    }
+
+    pub fn icon(self) -> [f32; 4] {
+        match self {
+            GameMode::Standard => atlas::MODE_STANDARD,
+            GameMode::Zen => atlas::MODE_ZEN,
+            GameMode::TimeAttack => atlas::MODE_TIME_ATTACK,
+            GameMode::MoveLimit => atlas::MODE_MOVE_LIMIT,
+            GameMode::Endless => atlas::MODE_ENDLESS,
+        }
+    }
+
+    /// If each click takes a point of life.
+    pub fn clicks_cost_life(self) -> bool {
+        matches!(
+            self,
+            GameMode::Standard | GameMode::MoveLimit | GameMode::Endless
+        )
+    }
+
+    /// If the mode has life at all, and so can be lost.
+    pub fn has_life(self) -> bool {
+        self != GameMode::Zen
+    }
+}
@@ -0,0 +1,110 @@
+//! The rating of a solved level: how close the player got to the par in clicks and in time,
+//! given in stars, and the bonus score they are worth.
+
+/// The most stars each rating can give.
+pub const MAX_STARS: u32 = 3;
+
+/// Three stars for reaching the par, two for staying within 'slack' times the par, and one for
+/// solving the level at all.
+fn stars(value: f32, par: f32, slack: f32) -> u32 {
+    if value <= par {
+        3
+    } else if value <= par * slack {
+        2
+    } else {
+        1
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct LevelRating {
+    /// Stars for the number of clicks, compared with the minimum.
+    pub click_stars: u32,
+    /// Stars for the time, compared with the par time.
+    pub time_stars: u32,
+    /// The score added for the stars.
+    pub bonus: u32,
+}
+impl LevelRating {
+    pub fn new(clicks: u32, par_clicks: u32, time: f32, par_time: f32, area: u32) -> Self {
+        let click_stars = stars(clicks as f32, par_clicks as f32, 1.5);
+        let time_stars = stars(time, par_time, 2.0);
+        Self {
+            click_stars,
+            time_stars,
+            // each star is worth a quarter point per tile, so bigger levels are worth more
+            bonus: (click_stars + time_stars) * area / 4,
+        }
+    }
+
+    pub fn stars(&self) -> u32 {
+        self.click_stars + self.time_stars
+    }
+}
+
+/// The totals of all levels solved in a run.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct RunSummary {
+    pub levels: u32,
+    pub stars: u32,
+    pub bonus: u32,
+    pub clicks: u32,
+    pub par_clicks: u32,
+}
+impl RunSummary {
+    pub fn add(&mut self, rating: &LevelRating, clicks: u32, par_clicks: u32) {
+        self.levels += 1;
+        self.stars += rating.stars();
+        self.bonus += rating.bonus;
+        self.clicks += clicks;
+        self.par_clicks += par_clicks;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stars_follow_the_par() {
+        // exactly the par still gets all the stars
+        let rating = LevelRating::new(10, 10, 30.0, 30.0, 16);
+        assert_eq!(
+            (rating.click_stars, rating.time_stars),
+            (MAX_STARS, MAX_STARS)
+        );
+        // up to one and a half times the clicks, and twice the time, for two stars
+        let rating = LevelRating::new(11, 10, 30.5, 30.0, 16);
+        assert_eq!((rating.click_stars, rating.time_stars), (2, 2));
+        let rating = LevelRating::new(15, 10, 60.0, 30.0, 16);
+        assert_eq!((rating.click_stars, rating.time_stars), (2, 2));
+        // and one for solving it at all
+        let rating = LevelRating::new(16, 10, 60.5, 30.0, 16);
+        assert_eq!((rating.click_stars, rating.time_stars), (1, 1));
+        // fewer clicks than the par, if it was ever too high, are still three stars
+        let rating = LevelRating::new(0, 0, 0.0, 30.0, 16);
+        assert_eq!(rating.stars(), 2 * MAX_STARS);
+    }
+
+    #[test]
+    fn bonus_grows_with_the_stars_and_the_area() {
+        assert_eq!(LevelRating::new(10, 10, 30.0, 30.0, 16).bonus, 24);
+        assert_eq!(LevelRating::new(16, 10, 60.5, 30.0, 16).bonus, 8);
+        assert_eq!(LevelRating::new(10, 10, 30.0, 30.0, 64).bonus, 96);
+        // rounded down on small boards
+        assert_eq!(LevelRating::new(16, 10, 60.5, 30.0, 3).bonus, 1);
+    }
+
+    #[test]
+    fn summary_adds_each_level() {
+        let mut summary = RunSummary::default();
+        let first = LevelRating::new(10, 10, 30.0, 30.0, 16);
+        let second = LevelRating::new(16, 10, 60.5, 30.0, 25);
+        summary.add(&first, 10, 10);
+        summary.add(&second, 16, 10);
+        assert_eq!(summary.levels, 2);
+        assert_eq!(summary.stars, 8);
+        assert_eq!(summary.bonus, 24 + 12);
+        assert_eq!((summary.clicks, summary.par_clicks), (26, 20));
+    }
+}
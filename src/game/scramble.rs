@@ -0,0 +1,183 @@
+//! Scrambling of a solved board, taking into account the rotational symmetry of each pipe, so
+//! that the number of clicks needed to solve it is known exactly.
+
+use super::maze::kind_of;
+use rand::seq::index::sample;
+use rand::Rng;
+
+/// The number of distinct orientations of each kind, in the order of `atlas::PIPES`. A straight
+/// pipe is the same after half a turn, and a cross is the same in any orientation.
+const PERIOD: [u8; 6] = [4, 4, 2, 4, 1, 1];
+
+#[derive(Clone, Debug)]
+pub struct ScrambleConfig {
+    /// The fraction of the tiles that can be rotated out of the solution, that will be.
+    pub fraction: f32,
+    /// The biggest group of tiles that may start connected to each other.
+    pub max_region: usize,
+}
+impl Default for ScrambleConfig {
+    fn default() -> Self {
+        Self {
+            fraction: 0.85,
+            max_region: 3,
+        }
+    }
+}
+
+/// Rotate the connections of a tile by some quarter turns, clockwise.
+fn rotate(cell: i8, turns: u8) -> i8 {
+    let cell = cell as u8 & 0b1111;
+    let turns = turns % 4;
+    (((cell << turns) | (cell >> (4 - turns))) & 0b1111) as i8
+}
+
+/// The minimum number of clicks to bring a tile, rotated by 'offset' quarter turns, back to
+/// the solution. The player can rotate both ways.
+pub fn min_clicks(cell: i8, offset: u8) -> u32 {
+    let period = PERIOD[kind_of(cell)];
+    let d = offset % period;
+    d.min(period - d) as u32
+}
+
+/// Choose a rotation offset for each tile of the solution 'maze'. At least 'fraction' of the
+/// tiles that are not symmetric in all orientations get a offset that is not a solution, and
+/// the board is then disturbed until no group bigger than 'max_region' starts connected, or
+/// until it gives up. This may rotate more tiles.
+pub fn scramble<R: Rng>(maze: &[i8], width: u8, config: &ScrambleConfig, rng: &mut R) -> Box<[u8]> {
+    let len = maze.len();
+    let mut offsets = vec![0u8; len].into_boxed_slice();
+
+    // a random offset that does not solve the tile
+    fn wrong_offset<R: Rng>(cell: i8, rng: &mut R) -> u8 {
+        rng.gen_range(1, PERIOD[kind_of(cell)])
+    }
+
+    let rotatable = (0..len)
+        .filter(|&i| PERIOD[kind_of(maze[i])] > 1)
+        .collect::<Vec<usize>>();
+    let amount = ((rotatable.len() as f32 * config.fraction).ceil() as usize).min(rotatable.len());
+    for i in sample(rng, rotatable.len(), amount).iter() {
+        let i = rotatable[i];
+        offsets[i] = wrong_offset(maze[i], rng);
+    }
+
+    // break the groups that start connected, giving up after some tries
+    for _ in 0..len {
+        let region = match big_regions(maze, &offsets, width, config.max_region) {
+            Some(x) => x,
+            None => break,
+        };
+        // a straight pipe that is already rotated can't be rotated to another wrong orientation
+        let candidates = region
+            .into_iter()
+            .filter(|&i| {
+                let period = PERIOD[kind_of(maze[i])];
+                period > 2 || period == 2 && offsets[i] == 0
+            })
+            .collect::<Vec<usize>>();
+        if candidates.is_empty() {
+            break;
+        }
+        let i = candidates[rng.gen_range(0, candidates.len())];
+        let mut offset = wrong_offset(maze[i], rng);
+        if offset == offsets[i] && PERIOD[kind_of(maze[i])] > 2 {
+            offset = offset % (PERIOD[kind_of(maze[i])] - 1) + 1;
+        }
+        offsets[i] = offset;
+    }
+
+    offsets
+}
+
+/// Find a group of connected tiles bigger than 'max'.
+fn big_regions(maze: &[i8], offsets: &[u8], width: u8, max: usize) -> Option<Vec<usize>> {
+    let width = width as usize;
+    let height = maze.len() / width;
+    let cells = (0..maze.len())
+        .map(|i| rotate(maze[i], offsets[i]))
+        .collect::<Vec<i8>>();
+    let neighbor = |i: usize, dir: usize| -> Option<usize> {
+        let (x, y) = (i % width, i / width);
+        match dir {
+            0 if x + 1 < width => Some(i + 1),
+            1 if y + 1 < height => Some(i + width),
+            2 if x > 0 => Some(i - 1),
+            3 if y > 0 => Some(i - width),
+            _ => None,
+        }
+    };
+
+    let mut visited = vec![false; maze.len()];
+    for start in 0..maze.len() {
+        if visited[start] {
+            continue;
+        }
+        visited[start] = true;
+        let mut region = vec![start];
+        let mut explore = vec![start];
+        while let Some(curr) = explore.pop() {
+            for dir in 0..4 {
+                if let Some(next) = neighbor(curr, dir) {
+                    if !visited[next]
+                        && cells[curr] & (1 << dir) != 0
+                        && cells[next] & (1 << ((dir + 2) % 4)) != 0
+                    {
+                        visited[next] = true;
+                        region.push(next);
+                        explore.push(next);
+                    }
+                }
+            }
+        }
+        if region.len() > max {
+            return Some(region);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::maze::{gen_maze, MazeConfig};
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn min_clicks_solves_the_tile() {
+        let cells = [0b0001, 0b0011, 0b0101, 0b0111, 0b1111];
+        for &cell in cells.iter() {
+            for offset in 0..4 {
+                let shown = rotate(cell, offset);
+                // the fewest clicks either way that bring the tile back
+                let clicks = (0..4)
+                    .find(|&k| rotate(shown, k) == cell || rotate(shown, (4 - k) % 4) == cell)
+                    .unwrap();
+                assert_eq!(min_clicks(cell, offset), clicks as u32, "{:04b}", cell);
+            }
+        }
+    }
+
+    #[test]
+    fn scramble_follows_the_config() {
+        let config = ScrambleConfig::default();
+        for seed in 0..10 {
+            let mut rng = SmallRng::seed_from_u64(seed);
+            let maze = gen_maze(8, 6, &MazeConfig::default(), &mut rng);
+            let offsets = scramble(&maze, 8, &config, &mut rng);
+            let rotatable = maze.iter().filter(|&&x| PERIOD[kind_of(x)] > 1).count();
+            let mut wrong = 0;
+            for (&cell, &offset) in maze.iter().zip(offsets.iter()) {
+                let period = PERIOD[kind_of(cell)];
+                // the offsets never go around a symmetric pipe
+                assert!(offset < period, "offset {} of {:04b}", offset, cell);
+                if offset != 0 {
+                    wrong += 1;
+                }
+            }
+            let amount = (rotatable as f32 * config.fraction).ceil() as usize;
+            assert!(wrong >= amount, "{} of {} tiles are rotated", wrong, amount);
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use super::input::InputMap;
+use super::locale;
+use super::maze::Algorithm;
+use super::mode::GameMode;
+use super::theme::Theme;
+use super::Ruleset;
+use crate::mixer::{Bus, Mixer};
+
+/// The player preferences that are kept between launches.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// Mute the music, keeping its volume.
+    pub music: bool,
+    /// Mute the sound effects, keeping their volume.
+    pub sound_effects: bool,
+    /// The volume of each bus of the mixer, in the order of `Bus::ALL`.
+    pub volumes: [f32; 3],
+    pub input: InputMap,
+    /// The rules, mode and loop rule last chosen in the menu.
+    pub ruleset: Ruleset,
+    pub mode: GameMode,
+    pub loop_free: bool,
+    /// The name of the color theme.
+    pub theme: String,
+    /// Draw a glyph over the regions, to tell them apart without their colors.
+    pub region_markers: bool,
+    /// Avoid animations that move or spin things around.
+    pub reduced_motion: bool,
+    /// The code of the language of the text, like 'en'.
+    pub language: String,
+    /// The maze generator to use, instead of the default one of each mode.
+    pub maze_algorithm: Option<Algorithm>,
+    /// The best score of each combination of rules and game mode.
+    best_scores: Vec<(Ruleset, GameMode, u32)>,
+}
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            music: true,
+            sound_effects: true,
+            volumes: [1.0; 3],
+            input: InputMap::default(),
+            ruleset: Ruleset::NetWalk,
+            mode: GameMode::Standard,
+            loop_free: false,
+            theme: Theme::default().name,
+            region_markers: false,
+            reduced_motion: false,
+            language: locale::system_language().unwrap_or("en").to_string(),
+            maze_algorithm: None,
+            best_scores: Vec::new(),
+        }
+    }
+}
+impl Settings {
+    #[cfg(not(target_arch = "wasm32"))]
+    const PATH: &'static str = "settings.txt";
+
+    /// The key of the settings in the localStorage of the browser.
+    #[cfg(target_arch = "wasm32")]
+    const KEY: &'static str = "pipe-puzzle-settings";
+
+    /// Load the settings saved from the last run, or the default ones if there is none.
+    pub fn load() -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Ok(text) = std::fs::read_to_string(Self::PATH) {
+            return Self::parse(&text);
+        }
+        #[cfg(target_arch = "wasm32")]
+        if let Some(text) = local_storage().and_then(|x| x.get_item(Self::KEY).ok().flatten()) {
+            return Self::parse(&text);
+        }
+        // write the defaults, so that the player has a file to edit
+        let settings = Self::default();
+        settings.save();
+        settings
+    }
+
+    pub fn save(&self) {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            if let Err(err) = std::fs::write(Self::PATH, self.write()) {
+                eprintln!("could not save settings: {}", err);
+            }
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            if let Some(storage) = local_storage() {
+                let _ = storage.set_item(Self::KEY, &self.write());
+            }
+        }
+    }
+
+    /// Parse the settings file. Each line is a setting, in the form `<name> <values...>`.
+    /// Unknown or malformed lines are ignored, keeping the default value.
+    fn parse(text: &str) -> Self {
+        let mut settings = Self::default();
+        for line in text.lines() {
+            let mut tokens = line.split_ascii_whitespace();
+            let ok = match (tokens.next(), tokens.next(), tokens.next()) {
+                (None, _, _) => true,
+                (Some(x), _, _) if x.starts_with('#') => true,
+                (Some("music"), Some(value), None) => parse_bool(value, &mut settings.music),
+                (Some("sound_effects"), Some(value), None) => {
+                    parse_bool(value, &mut settings.sound_effects)
+                }
+                (Some("volume"), Some(bus), Some(value)) => {
+                    let bus = Bus::from_name(bus);
+                    let value = value.parse::<f32>().ok();
+                    match (bus, value, tokens.next()) {
+                        (Some(bus), Some(value), None) if (0.0..=1.0).contains(&value) => {
+                            settings.volumes[bus as usize] = value;
+                            true
+                        }
+                        _ => false,
+                    }
+                }
+                (Some("input"), Some(input), Some(action)) => {
+                    settings.input.parse_line(input, action)
+                }
+                (Some("ruleset"), Some(name), None) => {
+                    parse_name(Ruleset::from_name(name), &mut settings.ruleset)
+                }
+                (Some("mode"), Some(name), None) => {
+                    parse_name(GameMode::from_name(name), &mut settings.mode)
+                }
+                (Some("loop_free"), Some(value), None) => {
+                    parse_bool(value, &mut settings.loop_free)
+                }
+                (Some("theme"), Some(name), None) => {
+                    let name = Theme::names().into_iter().find(|x| x == name);
+                    parse_name(name, &mut settings.theme)
+                }
+                (Some("region_markers"), Some(value), None) => {
+                    parse_bool(value, &mut settings.region_markers)
+                }
+                (Some("reduced_motion"), Some(value), None) => {
+                    parse_bool(value, &mut settings.reduced_motion)
+                }
+                (Some("language"), Some(code), None) => {
+                    let code = locale::codes().find(|&x| x == code);
+                    parse_name(code.map(str::to_string), &mut settings.language)
+                }
+                (Some("maze_algorithm"), Some(name), None) => {
+                    settings.maze_algorithm = Algorithm::from_name(name);
+                    settings.maze_algorithm.is_some()
+                }
+                (Some("best"), Some(ruleset), Some(mode)) => {
+                    let ruleset = Ruleset::from_name(ruleset);
+                    let mode = GameMode::from_name(mode);
+                    let score = tokens.next().and_then(|x| x.parse().ok());
+                    match (ruleset, mode, score, tokens.next()) {
+                        (Some(ruleset), Some(mode), Some(score), None) => {
+                            settings.record_score(ruleset, mode, score);
+                            true
+                        }
+                        _ => false,
+                    }
+                }
+                _ => false,
+            };
+            if !ok {
+                eprintln!("ignoring malformed setting: '{}'", line);
+            }
+        }
+        settings
+    }
+
+    fn write(&self) -> String {
+        let on_off = |x: bool| if x { "on" } else { "off" };
+        let mut out = String::new();
+        out.push_str(&format!("music {}\n", on_off(self.music)));
+        out.push_str(&format!("sound_effects {}\n", on_off(self.sound_effects)));
+        for &bus in Bus::ALL.iter() {
+            out.push_str(&format!(
+                "volume {} {}\n",
+                bus.name(),
+                self.volumes[bus as usize]
+            ));
+        }
+        self.input.write("input", &mut out);
+        out.push_str(&format!("ruleset {}\n", self.ruleset.name()));
+        out.push_str(&format!("mode {}\n", self.mode.name()));
+        out.push_str(&format!("loop_free {}\n", on_off(self.loop_free)));
+        out.push_str(&format!("theme {}\n", self.theme));
+        out.push_str(&format!("region_markers {}\n", on_off(self.region_markers)));
+        out.push_str(&format!("reduced_motion {}\n", on_off(self.reduced_motion)));
+        out.push_str(&format!("language {}\n", self.language));
+        if let Some(algorithm) = self.maze_algorithm {
+            out.push_str(&format!("maze_algorithm {}\n", algorithm.name()));
+        }
+        for (ruleset, mode, score) in self.best_scores.iter() {
+            out.push_str(&format!(
+                "best {} {} {}\n",
+                ruleset.name(),
+                mode.name(),
+                score
+            ));
+        }
+        out
+    }
+
+    /// Set the volumes of the mixer, silencing the buses that are muted.
+    pub fn apply_volumes(&self, mixer: &Mixer) {
+        for &bus in Bus::ALL.iter() {
+            let on = match bus {
+                Bus::Master => true,
+                Bus::Music => self.music,
+                Bus::Effects => self.sound_effects,
+            };
+            let volume = if on { self.volumes[bus as usize] } else { 0.0 };
+            mixer.set_volume(bus, volume);
+        }
+    }
+
+    pub fn best_score(&self, ruleset: Ruleset, mode: GameMode) -> u32 {
+        self.best_scores
+            .iter()
+            .find(|(r, m, _)| *r == ruleset && *m == mode)
+            .map_or(0, |x| x.2)
+    }
+
+    /// Keep 'score' if it is the best one of its rules and mode. Return true if it is.
+    pub fn record_score(&mut self, ruleset: Ruleset, mode: GameMode, score: u32) -> bool {
+        match self
+            .best_scores
+            .iter_mut()
+            .find(|(r, m, _)| *r == ruleset && *m == mode)
+        {
+            Some((_, _, best)) if *best >= score => false,
+            Some((_, _, best)) => {
+                *best = score;
+                true
+            }
+            None => {
+                self.best_scores.push((ruleset, mode, score));
+                true
+            }
+        }
+    }
+}
+
+/// Parse a 'on' or 'off' value. Return false if it is neither.
+fn parse_bool(value: &str, out: &mut bool) -> bool {
+    match value {
+        "on" => *out = true,
+        "off" => *out = false,
+        _ => return false,
+    }
+    true
+}
+
+/// Keep a value parsed from its name. Return false if the name was unknown.
+fn parse_name<T>(value: Option<T>, out: &mut T) -> bool {
+    match value {
+        Some(x) => {
+            *out = x;
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok()?
+}
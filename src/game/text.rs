@@ -0,0 +1,318 @@
+//! Text drawn with the bitmap font, that `build.rs` packs in the atlas from `res/font`.
+
+use sprite_render::{SpriteInstance, TextureId};
+
+mod font {
+    include!(concat!(env!("OUT_DIR"), "/font.rs"));
+}
+
+/// The character drawn in place of the ones the font does not have.
+const REPLACEMENT: char = '?';
+
+/// Where each line of a text goes, in relation to the x position of the text.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Align {
+    Left,
+    Center,
+}
+
+/// The glyph of 'c': its rect in the atlas, its rect in relation to the pen at the top of the
+/// line, and its advance, all in lines.
+fn glyph(c: char) -> &'static (char, [f32; 4], [f32; 4], f32) {
+    let find = |c: char| font::GLYPHS.binary_search_by_key(&c, |x| x.0).ok();
+    let i = find(c)
+        .or_else(|| find(REPLACEMENT))
+        .expect("the font has no replacement character");
+    &font::GLYPHS[i]
+}
+
+fn kerning(a: char, b: char) -> f32 {
+    font::KERNING
+        .binary_search_by(|x| x.0.cmp(&(a, b)))
+        .map_or(0.0, |i| font::KERNING[i].1)
+}
+
+/// The width of a line of text, in lines.
+fn line_width(line: &str) -> f32 {
+    let mut width = 0.0;
+    let mut previous = None;
+    for c in line.chars() {
+        if let Some(previous) = previous {
+            width += kerning(previous, c);
+        }
+        width += glyph(c).3;
+        previous = Some(c);
+    }
+    width
+}
+
+/// The width and height of 'text', with lines of height 'size'.
+pub fn measure(text: &str, size: f32) -> [f32; 2] {
+    let lines = text.split('\n');
+    let count = lines.clone().count();
+    let width = lines.map(line_width).fold(0.0, f32::max);
+    [width * size, count as f32 * size]
+}
+
+/// The sprites of 'text', in lines of height 'size', separated by '\n'. Each line is placed at
+/// 'x' by 'align', and the whole text is centered at 'y'.
+pub fn text_to_sprites(
+    text: &str,
+    x: f32,
+    y: f32,
+    size: f32,
+    color: [u8; 4],
+    align: Align,
+    texture: TextureId,
+) -> Vec<SpriteInstance> {
+    let mut sprites = Vec::with_capacity(text.len());
+    let mut top = y - measure(text, size)[1] / 2.0;
+    for line in text.split('\n') {
+        let width = line_width(line) * size;
+        let mut pen = match align {
+            Align::Left => x,
+            Align::Center => x - width / 2.0,
+        };
+        let mut previous = None;
+        for c in line.chars() {
+            if let Some(previous) = previous {
+                pen += kerning(previous, c) * size;
+            }
+            let &(_, uv_rect, [left, up, w, h], advance) = glyph(c);
+            if w > 0.0 {
+                let sprite = SpriteInstance::new(
+                    pen + (left + w / 2.0) * size,
+                    top + (up + h / 2.0) * size,
+                    w * size,
+                    h * size,
+                    texture,
+                    uv_rect,
+                )
+                .with_color(color);
+                sprites.push(sprite);
+            }
+            pen += advance * size;
+            previous = Some(c);
+        }
+        top += size;
+    }
+    sprites
+}
+
+/// A text that can be moved, scaled and turned as a whole, centered at its position.
+#[derive(Clone)]
+pub struct Label {
+    text: String,
+    pos: [f32; 2],
+    size: f32,
+    angle: f32,
+    color: [u8; 4],
+    texture: TextureId,
+    sprites: Vec<SpriteInstance>,
+    /// The position of each sprite in relation to the position of the label, before turning.
+    offsets: Vec<[f32; 2]>,
+}
+impl Label {
+    pub fn new(text: &str, x: f32, y: f32, size: f32, texture: TextureId) -> Self {
+        let mut label = Self {
+            text: text.to_string(),
+            pos: [x, y],
+            size,
+            angle: 0.0,
+            color: [255; 4],
+            texture,
+            sprites: Vec::new(),
+            offsets: Vec::new(),
+        };
+        label.layout();
+        label
+    }
+
+    pub fn with_color(mut self, color: [u8; 4]) -> Self {
+        self.set_color(color);
+        self
+    }
+
+    /// Lay out the glyphs of the text again. Only needed when the text changes, the other
+    /// changes move the sprites that are already there.
+    fn layout(&mut self) {
+        self.sprites = text_to_sprites(
+            &self.text,
+            0.0,
+            0.0,
+            self.size,
+            self.color,
+            Align::Center,
+            self.texture,
+        );
+        self.offsets = self.sprites.iter().map(|x| x.pos).collect();
+        self.place();
+    }
+
+    /// Put each sprite at its offset, turned by the angle around the position of the label.
+    fn place(&mut self) {
+        let (sin, cos) = self.angle.sin_cos();
+        for (sprite, &[x, y]) in self.sprites.iter_mut().zip(self.offsets.iter()) {
+            sprite.set_position(
+                self.pos[0] + x * cos - y * sin,
+                self.pos[1] + x * sin + y * cos,
+            );
+            sprite.set_angle(self.angle);
+        }
+    }
+
+    pub fn set_text(&mut self, text: &str) {
+        if self.text != text {
+            self.text = text.to_string();
+            self.layout();
+        }
+    }
+
+    pub fn set_position(&mut self, x: f32, y: f32) {
+        if self.pos != [x, y] {
+            self.pos = [x, y];
+            self.place();
+        }
+    }
+
+    /// Set the height of each line of the text.
+    pub fn set_size(&mut self, size: f32) {
+        if size == self.size {
+            return;
+        }
+        if self.size == 0.0 {
+            self.size = size;
+            self.layout();
+            return;
+        }
+        // the layout is proportional to the size
+        let scale = size / self.size;
+        self.size = size;
+        for (sprite, offset) in self.sprites.iter_mut().zip(self.offsets.iter_mut()) {
+            *offset = [offset[0] * scale, offset[1] * scale];
+            sprite.set_size(sprite.get_width() * scale, sprite.get_height() * scale);
+        }
+        self.place();
+    }
+
+    pub fn set_angle(&mut self, angle: f32) {
+        if angle != self.angle {
+            self.angle = angle;
+            self.place();
+        }
+    }
+
+    pub fn set_color(&mut self, color: [u8; 4]) {
+        self.color = color;
+        for sprite in self.sprites.iter_mut() {
+            sprite.set_color(color);
+        }
+    }
+
+    pub fn get_x(&self) -> f32 {
+        self.pos[0]
+    }
+
+    pub fn get_y(&self) -> f32 {
+        self.pos[1]
+    }
+
+    pub fn get_size(&self) -> f32 {
+        self.size
+    }
+
+    /// The height of the digits, to draw numbers beside the text at the same height.
+    pub fn digit_height(&self) -> f32 {
+        glyph('0').2[3] * self.size
+    }
+
+    pub fn get_width(&self) -> f32 {
+        measure(&self.text, self.size)[0]
+    }
+
+    pub fn get_height(&self) -> f32 {
+        measure(&self.text, self.size)[1]
+    }
+
+    pub fn sprites(&self) -> impl Iterator<Item = SpriteInstance> + '_ {
+        self.sprites.iter().cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measure_follows_the_advances() {
+        let [width, height] = measure("A", 2.0);
+        assert_eq!(width, glyph('A').3 * 2.0);
+        assert_eq!(height, 2.0);
+        let [width, height] = measure("AV\nA", 1.0);
+        assert_eq!(width, glyph('A').3 + glyph('V').3 + kerning('A', 'V'));
+        assert_eq!(height, 2.0);
+        // the font pulls this pair together
+        assert!(kerning('A', 'V') < 0.0);
+    }
+
+    #[test]
+    fn unknown_characters_are_replaced() {
+        assert_eq!(glyph('\u{4e2d}').0, REPLACEMENT);
+        assert_eq!(glyph('ç').0, 'ç');
+        assert_eq!(measure("\u{4e2d}", 1.0), measure("?", 1.0));
+    }
+
+    #[test]
+    fn label_keeps_its_layout_when_moved() {
+        let mut label = Label::new("AV\nA", 0.0, 0.0, 1.0, 0);
+        label.set_size(0.5);
+        label.set_angle(0.3);
+        label.set_position(1.0, 2.0);
+        let mut expected = Label::new("AV\nA", 1.0, 2.0, 0.5, 0);
+        expected.set_angle(0.3);
+        for (a, b) in label.sprites().zip(expected.sprites()) {
+            assert!((a.get_x() - b.get_x()).abs() < 1e-5);
+            assert!((a.get_y() - b.get_y()).abs() < 1e-5);
+            assert!((a.get_width() - b.get_width()).abs() < 1e-5);
+            assert!((a.get_height() - b.get_height()).abs() < 1e-5);
+        }
+        assert_eq!(label.sprites().count(), 3);
+    }
+
+    #[test]
+    fn lines_are_aligned() {
+        let texture = 0;
+        let left = text_to_sprites("ab\nc", 1.0, 0.0, 0.5, [255; 4], Align::Left, texture);
+        let center = text_to_sprites("ab\nc", 1.0, 0.0, 0.5, [255; 4], Align::Center, texture);
+        assert_eq!(left.len(), 3);
+        let left_edge =
+            |s: &SpriteInstance, c: char| s.get_x() - s.get_width() / 2.0 - glyph(c).2[0] * 0.5;
+        // the lines start at x on the left, and are centered at x in the center
+        assert!((left_edge(&left[0], 'a') - 1.0).abs() < 1e-5);
+        assert!((left_edge(&left[2], 'c') - 1.0).abs() < 1e-5);
+        let start = left_edge(&center[2], 'c');
+        assert!((start + glyph('c').3 * 0.25 - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn text_is_centered_vertically() {
+        let sprites = text_to_sprites("a\na", 0.0, 1.0, 0.5, [255; 4], Align::Left, 0);
+        let [_, up, _, h] = glyph('a').2;
+        assert!((sprites[0].get_y() - (0.5 + (up + h / 2.0) * 0.5)).abs() < 1e-5);
+        assert!((sprites[1].get_y() - sprites[0].get_y() - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn label_turns_around_its_position() {
+        let mut label = Label::new("ab", 1.0, 2.0, 0.5, 0);
+        let before = label.sprites().collect::<Vec<_>>();
+        label.set_angle(std::f32::consts::PI);
+        let after = label.sprites().collect::<Vec<_>>();
+        // half a turn mirrors each glyph around the center of the label
+        for (a, b) in before.iter().zip(after.iter()) {
+            assert!((a.get_x() + b.get_x() - 2.0).abs() < 1e-5);
+            assert!((a.get_y() + b.get_y() - 4.0).abs() < 1e-5);
+        }
+        assert_eq!(label.get_width(), measure("ab", 0.5)[0]);
+    }
+}
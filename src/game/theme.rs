@@ -0,0 +1,158 @@
+//! The color themes: the colors given to the regions of connected pipes, and the color behind the
+//! board, loaded from `res/themes.cfg`.
+
+/// The theme file that is built into the game.
+static EMBEDDED: &str = include_str!("../../res/themes.cfg");
+
+#[derive(Clone, Debug)]
+pub struct Theme {
+    pub name: String,
+    /// The color the screen is cleared with.
+    pub background: [u8; 4],
+    /// The colors of the regions. A region takes the color of its index, wrapping around.
+    colors: Vec<[u8; 4]>,
+}
+impl Theme {
+    /// Parse a theme file, where each theme starts with a line `theme <name>`, followed by a
+    /// line `background <r> <g> <b>` and the lines `color <r> <g> <b>`.
+    fn parse(text: &str) -> Result<Vec<Self>, String> {
+        let mut themes: Vec<Theme> = Vec::new();
+        let mut background = false;
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let tokens = line.split_ascii_whitespace().collect::<Vec<&str>>();
+            let rgb = |r: &str, g: &str, b: &str| match (r.parse(), g.parse(), b.parse()) {
+                (Ok(r), Ok(g), Ok(b)) => Ok([r, g, b, 255]),
+                _ => Err(format!("line {}: invalid color", n + 1)),
+            };
+            if let ["theme", name] = tokens[..] {
+                if let Some(last) = themes.last() {
+                    last.validate()?;
+                }
+                if themes.iter().any(|x| x.name == name) {
+                    return Err(format!("line {}: the theme '{}' is repeated", n + 1, name));
+                }
+                themes.push(Theme {
+                    name: name.to_string(),
+                    background: [0, 0, 0, 255],
+                    colors: Vec::new(),
+                });
+                background = false;
+                continue;
+            }
+            let theme = match themes.last_mut() {
+                Some(theme) => theme,
+                None if tokens.is_empty() => continue,
+                None => return Err(format!("line {}: expected a 'theme' before", n + 1)),
+            };
+            match tokens[..] {
+                [] => {}
+                ["background", r, g, b] if !background => {
+                    theme.background = rgb(r, g, b)?;
+                    background = true;
+                }
+                ["background", ..] => {
+                    return Err(format!("line {}: the background is repeated", n + 1))
+                }
+                ["color", r, g, b] => theme.colors.push(rgb(r, g, b)?),
+                _ => return Err(format!("line {}: unknown setting", n + 1)),
+            }
+        }
+        match themes.last() {
+            Some(last) => last.validate()?,
+            None => return Err("there is no theme".to_string()),
+        }
+        Ok(themes)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.colors.len() < 2 {
+            return Err(format!("the theme '{}' needs at least 2 colors", self.name));
+        }
+        Ok(())
+    }
+
+    /// The names of all themes, starting with the default one.
+    pub fn names() -> Vec<String> {
+        Self::all().into_iter().map(|x| x.name).collect()
+    }
+
+    /// The theme 'name', or the default one if there is none with this name.
+    pub fn named(name: &str) -> Self {
+        let mut themes = Self::all();
+        let i = themes.iter().position(|x| x.name == name).unwrap_or(0);
+        themes.swap_remove(i)
+    }
+
+    /// The themes of the file in `res/` in development builds, so they can be edited while the
+    /// game runs, or else the embedded ones.
+    fn all() -> Vec<Self> {
+        #[cfg(all(debug_assertions, not(target_arch = "wasm32")))]
+        {
+            let path = super::assets::path("res/themes.cfg");
+            let themes = std::fs::read_to_string(&path)
+                .map_err(|err| err.to_string())
+                .and_then(|text| Self::parse(&text));
+            match themes {
+                Ok(themes) => return themes,
+                Err(err) => eprintln!("invalid theme file '{}': {}", path.display(), err),
+            }
+        }
+        Self::parse(EMBEDDED).expect("the embedded theme file is invalid")
+    }
+
+    pub fn colors(&self) -> &[[u8; 4]] {
+        &self.colors
+    }
+
+    /// The background color, in the form taken by `clear_screen`.
+    pub fn clear_color(&self) -> [f32; 4] {
+        self.background.map(|x| x as f32 / 255.0)
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::all().swap_remove(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn themes_file_parses() {
+        let themes = Theme::parse(EMBEDDED).unwrap();
+        assert!(themes.len() >= 2);
+        assert_eq!(Theme::default().name, themes[0].name);
+        for theme in themes.iter() {
+            assert!(theme.colors.len() >= 2);
+            assert_eq!(Theme::named(&theme.name).colors, theme.colors);
+        }
+    }
+
+    #[test]
+    fn invalid_themes_are_rejected() {
+        let ok = "theme a\nbackground 1 2 3\ncolor 0 0 0 # black\ncolor 255 255 255\n";
+        let themes = Theme::parse(ok).unwrap();
+        assert_eq!(themes[0].background, [1, 2, 3, 255]);
+        assert_eq!(themes[0].colors.len(), 2);
+
+        let errors = [
+            "",
+            "# no theme",
+            "color 0 0 0\ntheme a\ncolor 0 0 0\ncolor 1 1 1",
+            "theme a\ncolor 0 0 0",
+            "theme a\ncolor 0 0 0\ncolor 1 1 1\ntheme b\ncolor 0 0 0",
+            "theme a\ncolor 0 0 0\ncolor 1 1 1\ntheme a\ncolor 0 0 0\ncolor 1 1 1",
+            "theme a\ncolor 0 0 256\ncolor 1 1 1",
+            "theme a\ncolor 0 0\ncolor 1 1 1",
+            "theme a\nbackground 0 0 0\nbackground 0 0 0\ncolor 0 0 0\ncolor 1 1 1",
+            "theme a\ncolor 0 0 0\ncolor 1 1 1\nforeground 0 0 0",
+        ];
+        for text in errors.iter() {
+            assert!(Theme::parse(text).is_err(), "'{}' was accepted", text);
+        }
+    }
+}
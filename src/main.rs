@@ -1,11 +1,9 @@
-use std::io::Cursor;
-
-use audio_engine::{AudioEngine, OggDecoder};
+use audio_engine::AudioEngine;
 use sprite_render::Camera;
 
 use winit::{
     dpi::LogicalSize,
-    event::{ElementState, Event, MouseButton, WindowEvent},
+    event::{ElementState, Event, KeyboardInput, MouseButton, MouseScrollDelta, WindowEvent},
     event_loop::EventLoop,
     window::WindowBuilder,
 };
@@ -14,19 +12,64 @@ mod time;
 use time::Instant;
 
 mod game;
-use game::Game;
+use game::{Game, Settings};
 
 mod audio_effect;
 
+mod mixer;
+use mixer::Mixer;
+
+mod sound_bank;
+
+mod music;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod offline;
+
 fn audio_engine() -> &'static AudioEngine {
     use std::sync::Once;
     static mut AUDIO_ENGINE: Option<AudioEngine> = None;
     static INIT: Once = Once::new();
     INIT.call_once(|| unsafe { AUDIO_ENGINE = Some(AudioEngine::new().unwrap()) });
-    unsafe { AUDIO_ENGINE.as_ref().unwrap() }
+    unsafe { (*std::ptr::addr_of!(AUDIO_ENGINE)).as_ref().unwrap() }
+}
+
+/// Write the music of a level to a WAV file, to hear it without a sound card. The arguments are
+/// `<file> <seconds> [<time of a loss>]`.
+#[cfg(not(target_arch = "wasm32"))]
+fn render_music(args: &[String]) -> Result<(), String> {
+    use audio_engine::SoundSource;
+    let usage = "usage: --render-music <file> <seconds> [<time of a loss>]";
+    let seconds = |x: Option<&String>| x.map(|x| x.parse::<f32>().map_err(|_| usage));
+    let path = args.first().ok_or(usage)?;
+    let duration = seconds(args.get(1)).ok_or(usage)??;
+    let lose_at = seconds(args.get(2)).transpose()?;
+
+    let speed = audio_effect::Control::new(1.0);
+    let mut music = music::scene_source(music::Scene::Playing, speed.clone())
+        .ok_or("there is no music when playing")?;
+    let mut render = offline::Render::new();
+    if let Some(time) = lose_at {
+        render = render.at(time, || speed.set(0.0));
+    }
+    let samples = render.run(&mut music, duration);
+    let wav = offline::encode_wav(&samples, music.channels(), music.sample_rate());
+    std::fs::write(path, wav).map_err(|err| format!("could not write '{}': {}", path, err))
 }
 
 fn main() {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let args = std::env::args().collect::<Vec<String>>();
+        if args.get(1).map(String::as_str) == Some("--render-music") {
+            if let Err(err) = render_music(&args[2..]) {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+            return;
+        }
+    }
+
     let event_loop = EventLoop::new();
     let wb = WindowBuilder::new()
         .with_title("Hello world!")
@@ -35,7 +78,6 @@ fn main() {
 
     #[cfg(target_arch = "wasm32")]
     let wb = {
-        use wasm_bindgen::prelude::*;
         use wasm_bindgen::JsCast;
         use winit::platform::web::WindowBuilderExtWebSys;
 
@@ -64,19 +106,12 @@ fn main() {
         }
     };
 
-    let music = OggDecoder::new(Cursor::new(&include_bytes!("../res/sound/pipe.ogg")[..])).unwrap();
-    let music = audio_effect::SlowDown::new(music);
-    let slow_down_ref = music.slow_down.clone();
-    let music = audio_effect::WithIntro::new(
-        OggDecoder::new(Cursor::new(
-            &include_bytes!("../res/sound/pipe-intro.ogg")[..],
-        ))
-        .unwrap(),
-        music,
-    );
+    let settings = Settings::load();
+    let mixer = Mixer::new();
+    // set the volumes before playing, so the music does not start and then mute
+    settings.apply_volumes(&mixer);
 
-    let mut music = audio_engine().new_sound(music).unwrap();
-    music.play();
+    let music = music::MusicDirector::new(&mixer);
 
     use rand::SeedableRng;
     let size = window.inner_size();
@@ -89,7 +124,8 @@ fn main() {
                 .as_millis() as u64,
         ),
         music,
-        slow_down_ref,
+        mixer,
+        settings,
         camera,
         render,
     );
@@ -107,6 +143,7 @@ fn main() {
                 WindowEvent::CloseRequested => *control_flow = winit::event_loop::ControlFlow::Exit,
                 WindowEvent::MouseInput { button, state, .. } => {
                     if button == MouseButton::Left {
+                        input.mouse_left_down = state == ElementState::Pressed;
                         input.mouse_left_state = match state {
                             ElementState::Pressed => 1,
                             ElementState::Released => 3,
@@ -116,8 +153,31 @@ fn main() {
                             ElementState::Pressed => 1,
                             ElementState::Released => 3,
                         };
+                    } else if button == MouseButton::Middle {
+                        input.mouse_middle_state = match state {
+                            ElementState::Pressed => 1,
+                            ElementState::Released => 3,
+                        };
                     }
                 }
+                WindowEvent::MouseWheel { delta, .. } => {
+                    input.mouse_wheel += match delta {
+                        MouseScrollDelta::LineDelta(_, y) => y,
+                        // assume a line is about 20 pixels
+                        MouseScrollDelta::PixelDelta(pos) => pos.y as f32 / 20.0,
+                    };
+                }
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            virtual_keycode: Some(key),
+                            state: ElementState::Pressed,
+                            ..
+                        },
+                    ..
+                } => {
+                    input.keys.push(key);
+                }
                 WindowEvent::CursorMoved { position, .. } => {
                     input.mouse_x = position.x as f32;
                     input.mouse_y = position.y as f32;
@@ -0,0 +1,96 @@
+//! A small mixer: every sound plays through a bus, and the volume of each bus can change while
+//! the sounds play, with a short ramp instead of a hard cut.
+
+use crate::audio_effect::{Gain, GainTarget};
+use audio_engine::SoundSource;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// The time a change of volume takes to complete, in seconds.
+const RAMP_TIME: f32 = 0.08;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Bus {
+    /// Affects all the other buses.
+    Master,
+    Music,
+    Effects,
+}
+impl Bus {
+    pub const ALL: [Bus; 3] = [Bus::Master, Bus::Music, Bus::Effects];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Bus::Master => "master",
+            Bus::Music => "music",
+            Bus::Effects => "effects",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|x| x.name() == name)
+    }
+}
+
+/// The volume of each bus, as the bits of a f32, so the audio thread can read it without locks.
+#[derive(Clone)]
+pub struct Mixer {
+    volumes: Arc<[AtomicU32; 3]>,
+}
+impl Mixer {
+    pub fn new() -> Self {
+        let one = || AtomicU32::new(1.0f32.to_bits());
+        Self {
+            volumes: Arc::new([one(), one(), one()]),
+        }
+    }
+
+    /// Set the volume of 'bus', from 0 to 1.
+    pub fn set_volume(&self, bus: Bus, volume: f32) {
+        self.volumes[bus as usize].store(volume.clamp(0.0, 1.0).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn volume(&self, bus: Bus) -> f32 {
+        f32::from_bits(self.volumes[bus as usize].load(Ordering::Relaxed))
+    }
+
+    /// The amplitude of the sounds in 'bus', after the master volume. The volumes are squared,
+    /// so the sliders feel linear to the ear.
+    fn gain(&self, bus: Bus) -> f32 {
+        let volume = self.volume(Bus::Master) * self.volume(bus);
+        volume * volume
+    }
+
+    /// Route 'source' through 'bus'.
+    pub fn source<T: SoundSource>(&self, bus: Bus, source: T) -> Gain<T, BusGain> {
+        let target = BusGain {
+            mixer: self.clone(),
+            bus,
+        };
+        Gain::new(source, target, RAMP_TIME)
+    }
+
+    /// Play 'source' once, through 'bus'. Nothing is played if the bus is silent, and then
+    /// false is returned.
+    pub fn play<T: SoundSource + Send + 'static>(&self, bus: Bus, source: T) -> bool {
+        if self.gain(bus) == 0.0 {
+            return false;
+        }
+        crate::audio_engine()
+            .new_sound(self.source(bus, source))
+            .unwrap()
+            .play();
+        true
+    }
+}
+
+/// The amplitude of a bus of a `Mixer`, that a sound in it ramps to.
+pub struct BusGain {
+    mixer: Mixer,
+    bus: Bus,
+}
+impl GainTarget for BusGain {
+    fn target(&self) -> f32 {
+        self.mixer.gain(self.bus)
+    }
+}
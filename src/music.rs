@@ -0,0 +1,339 @@
+//! Chooses the music of each scene of the game, following `res/music.cfg`, and crossfades from a
+//! track to the next.
+
+use audio_engine::{OggDecoder, Sound, SoundSource};
+use std::io::Cursor;
+
+use crate::audio_effect::{Control, Gain, LoopPoints, LoopRegion, Speed};
+use crate::mixer::{Bus, Mixer};
+
+/// The music file that is built into the game.
+static CONFIG: &str = include_str!("../res/music.cfg");
+
+mod files {
+    include!(concat!(env!("OUT_DIR"), "/music.rs"));
+}
+
+fn file(name: &str) -> Option<&'static [u8]> {
+    files::FILES.iter().find(|x| x.0 == name).map(|x| x.1)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Scene {
+    Menu,
+    Playing,
+    LevelComplete,
+    GameOver,
+}
+impl Scene {
+    pub const ALL: [Scene; 4] = [
+        Scene::Menu,
+        Scene::Playing,
+        Scene::LevelComplete,
+        Scene::GameOver,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Scene::Menu => "menu",
+            Scene::Playing => "playing",
+            Scene::LevelComplete => "level_complete",
+            Scene::GameOver => "game_over",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|x| x.name() == name)
+    }
+}
+
+struct Config {
+    /// The name and the file of each track.
+    tracks: Vec<(String, String)>,
+    /// The track of each scene, in the order of `Scene::ALL`.
+    scenes: [Option<usize>; 4],
+    crossfade: f32,
+}
+impl Config {
+    /// Parse a music file, where 'exists' tells if there is a music file with a name.
+    fn parse(text: &str, exists: impl Fn(&str) -> bool) -> Result<Self, String> {
+        let mut tracks: Vec<(String, String)> = Vec::new();
+        let mut scenes = [None; 4];
+        let mut crossfade = None;
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let tokens = line.split_ascii_whitespace().collect::<Vec<&str>>();
+            match tokens[..] {
+                [] => {}
+                ["track", name, path] => {
+                    if tracks.iter().any(|x| x.0 == name) {
+                        return Err(format!("line {}: the track '{}' is repeated", n + 1, name));
+                    }
+                    if !exists(path) {
+                        return Err(format!("line {}: there is no file '{}'", n + 1, path));
+                    }
+                    tracks.push((name.to_string(), path.to_string()));
+                }
+                ["scene", scene, track] => {
+                    let scene = Scene::from_name(scene)
+                        .ok_or_else(|| format!("line {}: unknown scene '{}'", n + 1, scene))?;
+                    scenes[scene as usize] = match track {
+                        "none" => None,
+                        _ => {
+                            Some(tracks.iter().position(|x| x.0 == track).ok_or_else(|| {
+                                format!("line {}: unknown track '{}'", n + 1, track)
+                            })?)
+                        }
+                    };
+                }
+                ["crossfade", seconds] => match seconds.parse::<f32>() {
+                    Ok(x) if x > 0.0 => crossfade = Some(x),
+                    _ => return Err(format!("line {}: invalid crossfade '{}'", n + 1, seconds)),
+                },
+                _ => return Err(format!("line {}: unknown setting", n + 1)),
+            }
+        }
+        Ok(Self {
+            tracks,
+            scenes,
+            crossfade: crossfade.ok_or("'crossfade' is missing")?,
+        })
+    }
+}
+
+/// The music file 'file_name' in loop, played at the speed of 'speed', with the contents of
+/// each file given by 'read'.
+fn track_source<B>(
+    file_name: &str,
+    read: &dyn Fn(&str) -> Option<B>,
+    speed: Control,
+) -> Result<impl SoundSource + Send, String>
+where
+    B: AsRef<[u8]> + Clone + Send + 'static,
+{
+    let bytes = read(file_name).ok_or_else(|| format!("there is no file '{}'", file_name))?;
+    let loop_file = format!(
+        "{}.loop",
+        file_name.rsplit_once('.').map_or(file_name, |x| x.0)
+    );
+    let region = match read(&loop_file) {
+        Some(text) => LoopRegion::parse(std::str::from_utf8(text.as_ref()).unwrap_or(""))
+            .map_err(|err| format!("invalid loop file '{}': {}", loop_file, err))?,
+        None => LoopRegion {
+            start: 0,
+            end: None,
+        },
+    };
+    let decoder = || {
+        OggDecoder::new(Cursor::new(bytes.clone()))
+            .map_err(|_| format!("invalid music file '{}'", file_name))
+    };
+    Ok(Speed::new(
+        LoopPoints::new(decoder()?, decoder()?, region),
+        speed,
+    ))
+}
+
+/// The music of 'scene', to render it offline.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn scene_source(scene: Scene, speed: Control) -> Option<impl SoundSource + Send> {
+    let config = Config::parse(CONFIG, |x| file(x).is_some()).expect("the music file is invalid");
+    let track = config.scenes[scene as usize]?;
+    Some(track_source(&config.tracks[track].1, &file, speed).unwrap())
+}
+
+struct Track {
+    sound: Sound,
+    /// The volume the track is fading to.
+    volume: Control,
+    /// The volume the track is at, written by its source as it fades.
+    level: Control,
+    speed: Control,
+    /// Play the track from its start, once it fades out.
+    restart: bool,
+    playing: bool,
+}
+
+pub struct MusicDirector {
+    tracks: Vec<Track>,
+    scenes: [Option<usize>; 4],
+    scene: Scene,
+}
+impl MusicDirector {
+    /// Create the tracks, and start the one of the menu.
+    pub fn new(mixer: &Mixer) -> Self {
+        let config =
+            Config::parse(CONFIG, |x| file(x).is_some()).expect("the music file is invalid");
+        Self::with_config(mixer, &config, file, Scene::Menu).expect("the music is invalid")
+    }
+
+    /// Load again the music from `res/`, and keep playing the track of the current scene.
+    #[cfg(all(debug_assertions, not(target_arch = "wasm32")))]
+    pub fn reload(&mut self, mixer: &Mixer) -> Result<(), String> {
+        use std::path::Path;
+        use std::sync::Arc;
+        let res = Path::new(env!("CARGO_MANIFEST_DIR")).join("res");
+        let text = std::fs::read_to_string(res.join("music.cfg")).map_err(|x| x.to_string())?;
+        let read = |name: &str| {
+            let bytes = std::fs::read(res.join("music").join(name)).ok()?;
+            Some(Arc::<[u8]>::from(bytes))
+        };
+        let config = Config::parse(&text, |x| res.join("music").join(x).is_file())?;
+        let director = Self::with_config(mixer, &config, read, self.scene)?;
+        for track in self.tracks.iter_mut() {
+            track.sound.pause();
+        }
+        *self = director;
+        Ok(())
+    }
+
+    /// Create the tracks of 'config', reading the files with 'read', and start the one of
+    /// 'scene'.
+    fn with_config<B>(
+        mixer: &Mixer,
+        config: &Config,
+        read: impl Fn(&str) -> Option<B>,
+        scene: Scene,
+    ) -> Result<Self, String>
+    where
+        B: AsRef<[u8]> + Clone + Send + 'static,
+    {
+        let current = config.scenes[scene as usize];
+        let tracks = config
+            .tracks
+            .iter()
+            .enumerate()
+            .map(|(i, (_, file))| {
+                // the current scene starts playing already, without a fade
+                let level = if Some(i) == current { 1.0 } else { 0.0 };
+                let volume = Control::new(level);
+                let level = Control::new(level);
+                let speed = Control::new(1.0);
+                let source = Gain::new(
+                    track_source(file, &read, speed.clone())?,
+                    volume.clone(),
+                    config.crossfade,
+                )
+                .with_level(level.clone());
+                let sound = crate::audio_engine()
+                    .new_sound(mixer.source(Bus::Music, source))
+                    .unwrap();
+                Ok(Track {
+                    sound,
+                    volume,
+                    level,
+                    speed,
+                    restart: false,
+                    playing: false,
+                })
+            })
+            .collect::<Result<Vec<Track>, String>>()?;
+        let mut director = Self {
+            tracks,
+            scenes: config.scenes,
+            scene,
+        };
+        director.update_volumes();
+        Ok(director)
+    }
+
+    pub fn set_scene(&mut self, scene: Scene) {
+        if scene != self.scene {
+            self.scene = scene;
+            self.update_volumes();
+        }
+    }
+
+    /// Fade out the track of 'scene', and play it again from its start.
+    pub fn restart(&mut self, scene: Scene) {
+        if let Some(i) = self.scenes[scene as usize] {
+            self.tracks[i].restart = true;
+            self.update_volumes();
+        }
+    }
+
+    /// Play the track of the playing scene at 'speed'. The other tracks keep theirs, so the
+    /// track of another scene is not stopped with it.
+    pub fn set_playing_speed(&self, speed: f32) {
+        if let Some(i) = self.scenes[Scene::Playing as usize] {
+            self.tracks[i].speed.set(speed);
+        }
+    }
+
+    /// Follow the fades, to restart or pause the tracks that are silent.
+    pub fn update(&mut self) {
+        let mut changed = false;
+        for track in self.tracks.iter_mut() {
+            if track.level.get() > 0.0 || track.volume.get() > 0.0 {
+                continue;
+            }
+            if track.restart {
+                track.sound.reset();
+                track.restart = false;
+                changed = true;
+            } else if track.playing {
+                // stop decoding the tracks that are not heard
+                track.sound.pause();
+                track.playing = false;
+            }
+        }
+        if changed {
+            self.update_volumes();
+        }
+    }
+
+    /// Fade in the track of the current scene, and fade out the others.
+    fn update_volumes(&mut self) {
+        let current = self.scenes[self.scene as usize];
+        for (i, track) in self.tracks.iter_mut().enumerate() {
+            let on = Some(i) == current && !track.restart;
+            track.volume.set(if on { 1.0 } else { 0.0 });
+            if on && !track.playing {
+                track.sound.play();
+                track.playing = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_parses() {
+        let exists = |x: &str| file(x).is_some();
+        let config = Config::parse(CONFIG, exists).unwrap();
+        assert!(config.crossfade > 0.0);
+        for track in config.scenes.iter().flatten() {
+            assert!(*track < config.tracks.len());
+        }
+
+        let track = config.tracks[0].1.as_str();
+        let text = format!(
+            "track a {}\nscene menu a\nscene game_over none # silence\ncrossfade 0.25\n",
+            track
+        );
+        let config = Config::parse(&text, exists).unwrap();
+        assert_eq!(config.tracks, vec![("a".to_string(), track.to_string())]);
+        assert_eq!(config.scenes, [Some(0), None, None, None]);
+        assert_eq!(config.crossfade, 0.25);
+
+        let errors = [
+            format!("track a {0}\ntrack a {0}\ncrossfade 1", track),
+            "track a no_such_file.ogg\ncrossfade 1".to_string(),
+            "scene menu a\ncrossfade 1".to_string(),
+            format!("track a {}\nscene credits a\ncrossfade 1", track),
+            "crossfade 0".to_string(),
+            "crossfade 1\nvolume 1".to_string(),
+            String::new(),
+        ];
+        for text in errors.iter() {
+            assert!(
+                Config::parse(text, exists).is_err(),
+                "'{}' was accepted",
+                text
+            );
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! Render sound sources to WAV files, without a sound card, to hear and test the audio effects.
+
+use audio_engine::SoundSource;
+
+/// The frames rendered at each call to the source, like the audio thread does.
+const BLOCK: usize = 256;
+
+/// Pulls the samples of a source, calling scripted actions at given times, like changing a
+/// control.
+#[derive(Default)]
+pub struct Render<'a> {
+    /// The actions, and the time to call each one, in seconds.
+    script: Vec<(f32, Box<dyn FnOnce() + 'a>)>,
+}
+impl<'a> Render<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Call 'action' when the render reaches 'time', in seconds.
+    pub fn at(mut self, time: f32, action: impl FnOnce() + 'a) -> Self {
+        self.script.push((time, Box::new(action)));
+        self
+    }
+
+    /// Render 'seconds' of 'source', or less if it ends before.
+    pub fn run(mut self, source: &mut impl SoundSource, seconds: f32) -> Vec<i16> {
+        let channels = source.channels() as usize;
+        let rate = source.sample_rate() as f32;
+        let frames = (seconds * rate) as usize;
+        let mut script = self
+            .script
+            .drain(..)
+            .map(|(time, action)| ((time * rate) as usize, action))
+            .collect::<Vec<_>>();
+        script.sort_by_key(|x| x.0);
+        let mut script = script.into_iter().peekable();
+
+        let mut out = Vec::with_capacity(frames * channels);
+        let mut buffer = vec![0; BLOCK * channels];
+        let mut frame = 0;
+        while frame < frames {
+            while let Some((_, action)) = script.next_if(|x| x.0 <= frame) {
+                action();
+            }
+            // stop at the next action, so it happens at the exact frame
+            let next = script.peek().map_or(frames, |x| x.0.min(frames));
+            let len = (next - frame).min(BLOCK) * channels;
+            let written = source.write_samples(&mut buffer[..len]);
+            out.extend_from_slice(&buffer[..written]);
+            frame += written / channels;
+            if written < len {
+                break;
+            }
+        }
+        out
+    }
+}
+
+/// Encode 'samples' as a 16-bit PCM WAV file.
+pub fn encode_wav(samples: &[i16], channels: u16, sample_rate: u32) -> Vec<u8> {
+    let data_len = samples.len() as u32 * 2;
+    let mut out = Vec::with_capacity(44 + data_len as usize);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(36 + data_len).to_le_bytes());
+    out.extend_from_slice(b"WAVE");
+    out.extend_from_slice(b"fmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    // PCM
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&channels.to_le_bytes());
+    out.extend_from_slice(&sample_rate.to_le_bytes());
+    out.extend_from_slice(&(sample_rate * channels as u32 * 2).to_le_bytes());
+    out.extend_from_slice(&(channels * 2).to_le_bytes());
+    out.extend_from_slice(&16u16.to_le_bytes());
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        out.extend_from_slice(&sample.to_le_bytes());
+    }
+    out
+}
+
+/// Decode a file written by `encode_wav`, returning its channels, sample rate and samples.
+#[cfg(test)]
+fn decode_wav(bytes: &[u8]) -> Option<(u16, u32, Vec<i16>)> {
+    let u16_at = |i: usize| Some(u16::from_le_bytes([*bytes.get(i)?, *bytes.get(i + 1)?]));
+    let u32_at = |i: usize| Some(u16_at(i)? as u32 | (u16_at(i + 2)? as u32) << 16);
+    if bytes.get(..4)? != b"RIFF" || bytes.get(8..16)? != b"WAVEfmt " || u16_at(20)? != 1 {
+        return None;
+    }
+    let channels = u16_at(22)?;
+    let sample_rate = u32_at(24)?;
+    let data = bytes.get(44..)?;
+    let samples = data
+        .chunks_exact(2)
+        .map(|x| i16::from_le_bytes([x[0], x[1]]))
+        .collect();
+    Some((channels, sample_rate, samples))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_effect::{Control, FadeIn, LoopPoints, LoopRegion, LowPass, Mix, Speed};
+
+    const RATE: u32 = 8000;
+
+    /// A sawtooth wave, made only of integer math, so the result is the same in every platform.
+    struct Saw {
+        /// The frames in a cycle.
+        period: usize,
+        frames: usize,
+        frame: usize,
+    }
+    impl Saw {
+        fn new(period: usize, seconds: f32) -> Self {
+            Self {
+                period,
+                frames: (seconds * RATE as f32) as usize,
+                frame: 0,
+            }
+        }
+    }
+    impl SoundSource for Saw {
+        fn channels(&self) -> u16 {
+            1
+        }
+        fn sample_rate(&self) -> u32 {
+            RATE
+        }
+        fn reset(&mut self) {
+            self.frame = 0;
+        }
+        fn write_samples(&mut self, buffer: &mut [i16]) -> usize {
+            let len = buffer.len().min(self.frames - self.frame);
+            for (i, x) in buffer[..len].iter_mut().enumerate() {
+                let phase = (self.frame + i) % self.period;
+                *x = (phase * 16000 / self.period) as i16 - 8000;
+            }
+            self.frame += len;
+            len
+        }
+    }
+
+    /// Compare 'samples' with the reference file 'name', in `tests/golden`. The reference is only
+    /// written when the environment variable BLESS is set, and a missing one fails the test. A
+    /// difference of 1 is accepted, for the rounding of the float functions of each platform.
+    fn check_golden(name: &str, samples: &[i16], channels: u16) {
+        let path = format!("{}/tests/golden/{}.wav", env!("CARGO_MANIFEST_DIR"), name);
+        if std::env::var_os("BLESS").is_some() {
+            std::fs::create_dir_all(std::path::Path::new(&path).parent().unwrap()).unwrap();
+            std::fs::write(&path, encode_wav(samples, channels, RATE)).unwrap();
+            eprintln!("wrote the reference '{}'", path);
+            return;
+        }
+        let (expected_channels, expected_rate, expected) = std::fs::read(&path)
+            .ok()
+            .and_then(|x| decode_wav(&x))
+            .unwrap_or_else(|| {
+                panic!(
+                    "the reference '{}' is missing or invalid; run with BLESS=1 to write it",
+                    path
+                )
+            });
+        assert_eq!((expected_channels, expected_rate), (channels, RATE));
+        assert_eq!(
+            expected.len(),
+            samples.len(),
+            "the length of '{}' changed",
+            name
+        );
+        let differs = |i: usize| (samples[i] as i32 - expected[i] as i32).abs() > 1;
+        if let Some(i) = (0..samples.len()).find(|&i| differs(i)) {
+            panic!(
+                "'{}' differs from the reference at {:.4} seconds; run with BLESS=1 if the change is \
+                 expected",
+                name,
+                (i / channels as usize) as f32 / RATE as f32,
+            );
+        }
+    }
+
+    #[test]
+    fn wav_round_trip() {
+        let samples = [0, 1, -1, i16::MAX, i16::MIN, 1234];
+        let wav = encode_wav(&samples, 2, 22050);
+        assert_eq!(wav.len(), 44 + 12);
+        assert_eq!(decode_wav(&wav), Some((2, 22050, samples.to_vec())));
+    }
+
+    #[test]
+    fn script_runs_at_the_exact_frame() {
+        let control = Control::new(1.0);
+        let mut source = Speed::new(Saw::new(100, 1.0), control.clone());
+        let samples = Render::new()
+            .at(0.5, || control.set(0.0))
+            .run(&mut source, 0.75);
+        assert_eq!(samples.len(), RATE as usize * 3 / 4);
+        let half = RATE as usize / 2;
+        // before the change it is the saw, and after it starts to slow down
+        let saw = Render::new().run(&mut Saw::new(100, 1.0), 1.0);
+        assert_eq!(samples[..half], saw[..half]);
+        assert_ne!(samples[half + 100..half + 200], saw[half + 100..half + 200]);
+    }
+
+    #[test]
+    fn golden_music_chain() {
+        // the chain of the game music: an intro, a loop, and the speed of the game over
+        let region = LoopRegion::parse("loop_start 2000\nloop_end 6000").unwrap();
+        let music = LoopPoints::new(Saw::new(80, 1.0), Saw::new(80, 1.0), region);
+        let speed = Control::new(1.0);
+        let mut source = Speed::new(music, speed.clone());
+        let samples = Render::new()
+            .at(1.0, || speed.set(1.3))
+            .at(1.25, || speed.set(0.0))
+            .at(2.0, || speed.set(1.0))
+            .run(&mut source, 2.5);
+        check_golden("music_chain", &samples, 1);
+    }
+
+    #[test]
+    fn golden_effects() {
+        let mut source = Mix::new(vec![
+            Box::new(FadeIn::new(Saw::new(40, 1.0), 0.5)),
+            Box::new(LowPass::new(Saw::new(13, 0.5), 800.0)),
+        ]);
+        let samples = Render::new().run(&mut source, 1.0);
+        check_golden("effects", &samples, 1);
+    }
+}
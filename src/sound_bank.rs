@@ -0,0 +1,191 @@
+//! Sound effects decoded once, and played from shared buffers, with a limit on how many copies
+//! of each one can play at the same time.
+
+use audio_engine::{SoundSource, WavDecoder};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+use crate::audio_effect::{Pan, Pitch};
+use crate::mixer::{Bus, Mixer};
+use crate::time::Instant;
+
+/// The time a stolen voice takes to fade out, in seconds, to not cut it with a pop.
+const STEAL_FADE: f32 = 0.005;
+
+// the states of a voice
+const PLAYING: u8 = 0;
+const STOPPING: u8 = 1;
+const DONE: u8 = 2;
+
+/// How many copies of a sound can play at once, and how often it can restart.
+#[derive(Clone, Copy, Debug)]
+pub struct Polyphony {
+    /// When a sound plays with this many voices already playing, the oldest one stops.
+    pub max_voices: usize,
+    /// A sound played again sooner than this, in seconds, is ignored.
+    pub min_interval: f32,
+}
+impl Polyphony {
+    pub fn new(max_voices: usize, min_interval: f32) -> Self {
+        Self {
+            max_voices,
+            min_interval,
+        }
+    }
+}
+
+struct Entry<K> {
+    key: K,
+    samples: Arc<[i16]>,
+    channels: u16,
+    sample_rate: u32,
+    polyphony: Polyphony,
+    /// The voices that may still be playing, from the oldest to the newest.
+    voices: Vec<Arc<AtomicU8>>,
+    last_play: Option<Instant>,
+}
+
+pub struct SoundBank<K: Copy + PartialEq> {
+    mixer: Mixer,
+    bus: Bus,
+    sounds: Vec<Entry<K>>,
+}
+impl<K: Copy + PartialEq> SoundBank<K> {
+    /// A empty bank, that plays its sounds through 'bus'.
+    pub fn new(mixer: Mixer, bus: Bus) -> Self {
+        Self {
+            mixer,
+            bus,
+            sounds: Vec::new(),
+        }
+    }
+
+    /// Decode the WAV file 'wav', to be played by 'key', replacing the sound it had. Return
+    /// false if 'wav' could not be decoded.
+    pub fn load<T>(&mut self, key: K, wav: T, polyphony: Polyphony) -> bool
+    where
+        T: AsRef<[u8]> + Send + 'static,
+    {
+        match WavDecoder::new(Cursor::new(wav)) {
+            Ok(decoder) => {
+                self.load_source(key, decoder, polyphony);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Render 'source', that must end, to be played by 'key', replacing the sound it had.
+    pub fn load_source<T: SoundSource>(&mut self, key: K, mut source: T, polyphony: Polyphony) {
+        let mut samples = Vec::new();
+        let mut buffer = [0i16; 4096];
+        loop {
+            let len = source.write_samples(&mut buffer);
+            samples.extend_from_slice(&buffer[..len]);
+            if len < buffer.len() {
+                break;
+            }
+        }
+        // the voices already playing keep the old samples
+        self.sounds.retain(|x| x.key != key);
+        self.sounds.push(Entry {
+            key,
+            samples: samples.into(),
+            channels: source.channels(),
+            sample_rate: source.sample_rate(),
+            polyphony,
+            voices: Vec::new(),
+            last_play: None,
+        });
+    }
+
+    /// Play the sound of 'key', unless it has just played, stealing its oldest voice if there
+    /// are too many.
+    pub fn play(&mut self, key: K) {
+        self.play_with(key, 0.0, 1.0);
+    }
+
+    /// Like `play`, but placed at 'pan', from -1 at the left to 1 at the right, and with its
+    /// pitch multiplied by 'pitch'.
+    pub fn play_with(&mut self, key: K, pan: f32, pitch: f32) {
+        let entry = match self.sounds.iter_mut().find(|x| x.key == key) {
+            Some(x) => x,
+            None => return,
+        };
+        if let Some(last) = entry.last_play {
+            if last.elapsed().as_secs_f32() < entry.polyphony.min_interval {
+                return;
+            }
+        }
+        entry
+            .voices
+            .retain(|x| x.load(Ordering::Relaxed) == PLAYING);
+        if entry.voices.len() >= entry.polyphony.max_voices {
+            let oldest = entry.voices.remove(0);
+            oldest.store(STOPPING, Ordering::Relaxed);
+        }
+        let state = Arc::new(AtomicU8::new(PLAYING));
+        let voice = Voice {
+            samples: entry.samples.clone(),
+            channels: entry.channels,
+            sample_rate: entry.sample_rate,
+            pos: 0,
+            fade: 1.0,
+            state: state.clone(),
+        };
+        if self
+            .mixer
+            .play(self.bus, Pan::new(Pitch::new(voice, pitch), pan))
+        {
+            entry.voices.push(state);
+            entry.last_play = Some(Instant::now());
+        }
+    }
+}
+
+/// A cheap source that plays a decoded sound, sharing its samples with the other voices.
+struct Voice {
+    samples: Arc<[i16]>,
+    channels: u16,
+    sample_rate: u32,
+    pos: usize,
+    /// The amplitude while fading out, after the voice was stolen.
+    fade: f32,
+    state: Arc<AtomicU8>,
+}
+impl SoundSource for Voice {
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+    fn reset(&mut self) {
+        self.pos = 0;
+    }
+    fn write_samples(&mut self, buffer: &mut [i16]) -> usize {
+        if self.state.load(Ordering::Relaxed) == DONE {
+            return 0;
+        }
+        let len = buffer.len().min(self.samples.len() - self.pos);
+        buffer[..len].copy_from_slice(&self.samples[self.pos..self.pos + len]);
+        self.pos += len;
+        if self.state.load(Ordering::Relaxed) == STOPPING {
+            let step = 1.0 / (STEAL_FADE * self.sample_rate as f32);
+            for frame in buffer[..len].chunks_mut(self.channels as usize) {
+                self.fade = (self.fade - step).max(0.0);
+                for sample in frame.iter_mut() {
+                    *sample = (*sample as f32 * self.fade) as i16;
+                }
+            }
+            if self.fade == 0.0 {
+                self.state.store(DONE, Ordering::Relaxed);
+            }
+        }
+        if self.pos == self.samples.len() {
+            self.state.store(DONE, Ordering::Relaxed);
+        }
+        len
+    }
+}